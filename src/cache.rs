@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use tokio::time::{Duration, Instant};
+
+pub type CacheKey = u64;
+
+pub const DEFAULT_MAX_ENTRIES: usize = 256;
+
+fn default_max_entries() -> usize {
+    DEFAULT_MAX_ENTRIES
+}
+
+/// e.g. `cache = { ttl_sec = 60 }`. The least recently used entry is evicted when the cache
+/// has `max_entries`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CacheConfig {
+    pub ttl_sec: u64,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+struct CacheEntry {
+    output: Vec<u8>,
+    cached_at: Instant,
+    used_at: Instant,
+}
+
+/// Output cache of a deterministic command, keyed by the hash of the request.
+pub struct ResponseCache {
+    config: CacheConfig,
+    entries: HashMap<CacheKey, CacheEntry>,
+    hits: u64,
+    misses: u64,
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            entries: HashMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn key(input: &str, output_size: Option<usize>) -> CacheKey {
+        let mut hasher = DefaultHasher::new();
+        input.hash(&mut hasher);
+        output_size.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn get(&mut self, key: CacheKey) -> Option<Vec<u8>> {
+        let ttl = Duration::from_secs(self.config.ttl_sec);
+        match self.entries.get_mut(&key) {
+            Some(entry) if entry.cached_at.elapsed() < ttl => {
+                entry.used_at = Instant::now();
+                self.hits += 1;
+                Some(entry.output.clone())
+            }
+            Some(_) => {
+                self.entries.remove(&key);
+                self.misses += 1;
+                None
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: CacheKey, output: Vec<u8>) {
+        if self.config.max_entries == 0 {
+            return;
+        }
+
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.config.max_entries {
+            let least_used = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used_at)
+                .map(|(key, _)| *key);
+            if let Some(least_used) = least_used {
+                self.entries.remove(&least_used);
+            }
+        }
+
+        let now = Instant::now();
+        self.entries.insert(
+            key,
+            CacheEntry {
+                output,
+                cached_at: now,
+                used_at: now,
+            },
+        );
+    }
+
+    pub fn flush(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn test_response_cache() {
+        time::pause();
+
+        let mut cache = ResponseCache::new(CacheConfig {
+            ttl_sec: 10,
+            max_entries: 2,
+        });
+        let key = ResponseCache::key("1+1", None);
+        assert_eq!(None, cache.get(key));
+
+        cache.insert(key, b"2".to_vec());
+        assert_eq!(Some(b"2".to_vec()), cache.get(key));
+        assert_ne!(key, ResponseCache::key("1+1", Some(1)));
+
+        // the least recently used entry is evicted when the cache is full
+        time::advance(Duration::from_secs(1)).await;
+        cache.insert(ResponseCache::key("2+2", None), b"4".to_vec());
+        cache.insert(ResponseCache::key("3+3", None), b"6".to_vec());
+        assert_eq!(None, cache.get(key));
+
+        time::advance(Duration::from_secs(10)).await;
+        assert_eq!(None, cache.get(ResponseCache::key("3+3", None)));
+
+        assert_eq!(
+            CacheStats {
+                hits: 1,
+                misses: 3,
+                entries: 1,
+            },
+            cache.stats()
+        );
+        cache.flush();
+        assert_eq!(0, cache.stats().entries);
+    }
+
+    #[tokio::test]
+    async fn test_evict_least_recently_used() {
+        time::pause();
+
+        let mut cache = ResponseCache::new(CacheConfig {
+            ttl_sec: 10,
+            max_entries: 2,
+        });
+        let (first, second) = (
+            ResponseCache::key("1+1", None),
+            ResponseCache::key("2+2", None),
+        );
+        cache.insert(first, b"2".to_vec());
+        time::advance(Duration::from_secs(1)).await;
+        cache.insert(second, b"4".to_vec());
+        time::advance(Duration::from_secs(1)).await;
+        assert_eq!(Some(b"2".to_vec()), cache.get(first));
+
+        time::advance(Duration::from_secs(1)).await;
+        cache.insert(ResponseCache::key("3+3", None), b"6".to_vec());
+        assert_eq!(Some(b"2".to_vec()), cache.get(first));
+        assert_eq!(None, cache.get(second));
+    }
+
+    #[test]
+    fn test_default_max_entries() {
+        let config: CacheConfig = toml::from_str("ttl_sec = 60").unwrap();
+        assert_eq!(DEFAULT_MAX_ENTRIES, config.max_entries);
+    }
+}
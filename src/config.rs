@@ -2,9 +2,19 @@ use dirs::home_dir;
 use serde::Deserialize;
 use std::io::Write;
 
-use crate::process_manager::{Cmd, CmdName, CmdTable};
+use crate::cache::CacheConfig;
+use crate::history;
+use crate::logging::{self, LogFormat};
+use crate::process_manager::{
+    Cmd, CmdName, CmdTable, ConcurrentRequestPolicy, InputEncoding, InputStep, OutputEncoding,
+    ProcessLimit, ProcessLimitPolicy, PromptOutput, Signal, DEFAULT_CMD_TIMEOUT_SEC, DEFAULT_SHELL,
+};
+use crate::server::{ServerConfig, DEFAULT_REQUEST_TIMEOUT_SEC};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -20,41 +30,277 @@ pub enum ConfigError {
 
     #[error("{0}")]
     InvalidPath(PathBuf),
+
+    #[error("config not found at {0}, run `dairi-server --init` to create the default config")]
+    NotFound(PathBuf),
+
+    #[error("config already exists at {0}")]
+    AlreadyExists(PathBuf),
+
+    #[error("environment variable ${0} is not set")]
+    UnresolvedEnvVar(String),
+
+    #[error("include cycle at {0}")]
+    IncludeCycle(PathBuf),
+
+    #[error("cmd [{name}] in {path} is already defined")]
+    DuplicatedIncludedCmd { name: CmdName, path: PathBuf },
+
+    #[error("{path}: {source}")]
+    IncludedTomlError {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+
+    #[error("cmd [{0}]: wait_for_exit requires persistent = false")]
+    WaitForExitRequiresOneShot(CmdName),
+
+    #[error(
+        "request_timeout_sec {request_timeout_sec} is shorter than the timeout {cmd_timeout_sec} sec of cmd [{name}]"
+    )]
+    RequestTimeoutTooShort {
+        request_timeout_sec: u64,
+        name: CmdName,
+        cmd_timeout_sec: u64,
+    },
 }
 
 type Result<T> = std::result::Result<T, ConfigError>;
 
 const DEFAULT_OUTPUT_SIZE: usize = 4 * 1024;
+const CONFIG_PATH_ENV: &str = "DAIRI_CONFIG";
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
+    /// The file the config is loaded from.
+    #[serde(skip)]
+    pub path: PathBuf,
+
+    /// Other files whose cmds are added to `cmds`, relative to the file including them. They
+    /// may only have `cmds` and `include`.
+    #[serde(default)]
+    pub include: Vec<PathBuf>,
     pub cmds: Vec<CmdConfig>,
+    pub allowed_uids: Option<Vec<u32>>,
+    pub line_protocol_socket: Option<PathBuf>,
+    /// The default of `use_shell` of the cmds.
+    pub use_shell: Option<bool>,
+    /// The default of `input_pipeline` of the cmds.
+    pub input_pipeline: Option<Vec<InputStep>>,
+    /// The max number of running processes. Unlimited if `None`.
+    pub max_processes: Option<usize>,
+    /// Defaults to `evict_lru`.
+    pub max_processes_policy: Option<ProcessLimitPolicy>,
+    /// e.g. `debug`. Overridden by `RUST_LOG`. Defaults to `info`.
+    pub log_level: Option<String>,
+    pub log_format: Option<LogFormat>,
+    /// The max bytes of a request body and an input. Unlimited if `None`.
+    pub max_input_size: Option<usize>,
+    /// The upper bound of `output_size` of the cmds and the requests. Unlimited if `None`.
+    pub max_output_size_limit: Option<usize>,
+    /// The permissions of the sockets in octal, e.g. `"0660"` to allow the group. Defaults to
+    /// `"0600"`.
+    #[serde(default, deserialize_with = "deserialize_octal_mode")]
+    pub socket_mode: Option<u32>,
+    /// The timeout of a whole request, which needs to be longer than `timeout_sec` and
+    /// `spawn_timeout_sec` of every cmd. Defaults to 180.
+    pub request_timeout_sec: Option<u64>,
+    /// Lets an identical request to a cmd wait for the one in flight and share its output,
+    /// instead of passing the same input twice. Defaults to false.
+    #[serde(default)]
+    pub coalesce_requests: bool,
+    /// How long a stopped process is given to exit by `quit_cmd` or `TERM` before it is
+    /// killed. Defaults to 1000.
+    pub kill_grace_milli_sec: Option<u64>,
+    /// Spawns the cmds with the env of the login shell `$SHELL -l`, for the server started by
+    /// an editor without the `PATH` of the terminal. Resolved once at the startup, not on a
+    /// reload. Defaults to false.
+    pub source_login_shell: Option<bool>,
+    /// Set false to fail the run with the status of a running process that can't be checked,
+    /// instead of killing it and spawning another, e.g. to debug the REPL. Defaults to true.
+    pub auto_kill_unhealthy: Option<bool>,
+    /// Set false to close each HTTP/1 connection after the response, instead of keeping it
+    /// for the next request of the client. Defaults to true.
+    pub http1_keep_alive: Option<bool>,
+    /// The max requests in flight on an HTTP/2 connection. Defaults to that of hyper.
+    pub http2_max_concurrent_streams: Option<u32>,
+    /// Compresses the responses with gzip or deflate for a client sending `Accept-Encoding`.
+    /// Defaults to false, since it only costs CPU time for the short replies on the socket.
+    pub compress_responses: Option<bool>,
+}
+
+/// A file included by `include`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IncludedConfig {
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    #[serde(default)]
+    cmds: Vec<CmdConfig>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CmdConfig {
     pub name: CmdName,
+    /// `$VAR` and `${VAR}` are replaced with the environment variables, `$$` with `$`.
     pub cmd: String,
+    /// The max bytes of the output, the rest is discarded. Defaults to 4KB.
     pub output_size: Option<usize>,
     pub auto_trailing_newline: Option<bool>,
     pub join_input_newline_with: Option<String>,
     pub truncate_line_regex: Option<String>,
+    #[serde(default)]
     pub remove_empty_line: bool,
+    #[serde(default)]
     pub no_empty_input: bool,
     pub timeout_sec: Option<u64>,
     pub wait_output_timeout_milli_sec: Option<u64>,
+    pub timeout_signal: Option<Signal>,
+    pub cache: Option<CacheConfig>,
+    pub quit_cmd: Option<String>,
+    pub prompt_regex: Option<String>,
+    pub prompt_output: Option<PromptOutput>,
+    pub skip_leading_output_lines: Option<usize>,
+    /// Runs `cmd` with `shell -c` so pipes and env vars can be used. `cmd` isn't split into
+    /// the program and the args then.
+    pub use_shell: Option<bool>,
+    pub shell: Option<String>,
+    /// Passed to the process once right after it is spawned, e.g. `using LinearAlgebra`. The
+    /// output is discarded.
+    pub init_input: Option<String>,
+    /// Whether stderr of the process is included in the output. Defaults to true.
+    pub capture_stderr: Option<bool>,
+    /// How the output is encoded in the response. Defaults to `utf8-lossy`.
+    pub output_encoding: Option<OutputEncoding>,
+    /// Retries of a failed spawn, waiting `spawn_retry_base_milli_sec` doubled on each retry.
+    pub spawn_retries: Option<u32>,
+    pub spawn_retry_base_milli_sec: Option<u64>,
+    /// Each input passed to the cmd is appended to this file, e.g. `~/.dairi/julia_history`.
+    pub history_file: Option<PathBuf>,
+    /// Matches in the output are replaced with `output_replace`, or removed if it isn't set,
+    /// e.g. `\u001b\[[0-9;]*m` to strip the colors.
+    pub output_filter_regex: Option<String>,
+    pub output_replace: Option<String>,
+    /// Removes the ANSI color and cursor escape sequences from the output.
+    pub strip_ansi: Option<bool>,
+    /// Spawns the process on a pseudo terminal instead of pipes, for the programs that buffer
+    /// the output or behave differently when stdout isn't a terminal. stderr goes to the same
+    /// terminal, so it can't be told apart from stdout.
+    pub use_pty: Option<bool>,
+    /// The timeout of the first interaction after the spawn, which is the `init_input` if set,
+    /// for the REPLs that take long to start. Defaults to `timeout_sec`.
+    pub spawn_timeout_sec: Option<u64>,
+    /// Set false for filters like `sort` or `jq`, which are spawned for each input and exit at
+    /// the end of stdin, instead of a REPL kept running. Defaults to true.
+    pub persistent: Option<bool>,
+    /// What to do with a request while another request is running the cmd. Defaults to
+    /// `queue`.
+    pub on_concurrent_request: Option<ConcurrentRequestPolicy>,
+    /// Removes the copy of the input that the REPL echoes back at the head of the output.
+    pub strip_input_echo: Option<bool>,
+    /// Passed by `POST /cmd/:cmd_name/reset` to clear the state of the REPL without restarting
+    /// it, e.g. `%reset -f` for IPython. The output is discarded.
+    pub reset_input: Option<String>,
+    /// The capacity of the buffers reading the output. `output_size` still caps the whole
+    /// output, which is read over as many fills as needed. Defaults to 8KB.
+    pub read_buffer_size: Option<usize>,
+    /// Passed to the program after the args in `cmd`. With `use_shell` they are the positional
+    /// parameters `$1`, `$2`.. of `cmd`.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Env vars of the process, overridden by the env of the request.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Reads the output until the process closes it and waits for the exit, instead of
+    /// waiting for the output to pause, then responds with the exit code. Requires
+    /// `persistent = false`.
+    pub wait_for_exit: Option<bool>,
+    /// Waits after the spawn until the output matches this, e.g. the first prompt of the REPL
+    /// after its banner, within `spawn_timeout_sec`. Passed before `init_input`.
+    pub ready_regex: Option<String>,
+    /// Restarts the process on the next run once it has been running for this long, e.g. to
+    /// bound the memory of a REPL that leaks. Unlimited if not set.
+    pub max_lifetime_sec: Option<u64>,
+    /// The number of the latest output lines kept for `GET /cmd/:cmd_name/recent`. Defaults
+    /// to 100, 0 disables it.
+    pub recent_output_lines: Option<usize>,
+    /// The max runs per second, beyond which the runs fail with 429. Bursts up to the same
+    /// number are allowed. Unlimited if not set.
+    pub rate_limit_per_sec: Option<u32>,
+    /// The order of the input arrangements, e.g. `["join_input_newline", "truncate_line"]`.
+    /// Defaults to the top level `input_pipeline`. A step not listed isn't applied.
+    pub input_pipeline: Option<Vec<InputStep>>,
+    /// The cmd run instead when the program can't be spawned, e.g. `python` for `python3`.
+    /// The fallback may have its own fallback.
+    pub fallback: Option<CmdName>,
+    /// Makes stderr of the process the same pipe as stdout, so the writes to both keep their
+    /// order in the output. `capture_stderr` is ignored then.
+    pub redirect_stderr_to_stdout: Option<bool>,
+    /// Keeps the first lines of the output, followed by `... N more lines`. The output is
+    /// cut by whichever of this and `output_size` is reached first.
+    pub max_output_lines: Option<usize>,
+    /// Ends each line of the input instead of `"\n"`, e.g. `"\r\n"` or `"\r"` for the REPLs
+    /// that expect them. The newlines of the input from editors are normalized first.
+    pub input_newline: Option<String>,
+    /// How the input is encoded on stdin. Defaults to `utf8`, `latin1` for the programs
+    /// reading ISO-8859-1.
+    pub input_encoding: Option<InputEncoding>,
+    /// Waits this long before running the input, and drops it if a newer input to the cmd
+    /// comes meanwhile, e.g. for the inputs sent on each keystroke. The dropped requests fail
+    /// with 409.
+    pub debounce_milli_sec: Option<u64>,
+    /// A marker that the program prints after each result, e.g. `__DAIRI_END__`. The output
+    /// ends at the marker without waiting for `wait_output_timeout_milli_sec`, and the marker
+    /// and what follows it in the same read are dropped. Falls back to the wait if the marker
+    /// isn't printed.
+    pub output_sentinel: Option<String>,
+    /// Flags the response with `is_error` when the output matches this, e.g. `^ERROR:` for
+    /// Julia. The output is returned as it is.
+    pub error_regex: Option<String>,
 }
 
 impl Config {
-    pub fn load_from_default_path_or_create() -> Result<Self> {
-        let config_path = Self::default_config_path()?;
-
+    /// Loads the config from `config_path`, `$DAIRI_CONFIG` or the default path in this order.
+    pub fn load_from_default_path(config_path: Option<&Path>) -> Result<Self> {
+        let config_path = Self::resolve_config_path(config_path)?;
         if !config_path.exists() {
-            Self::create_default_toml(&config_path)?;
+            return Err(ConfigError::NotFound(config_path));
+        }
+        Self::load_from_path(&config_path)
+    }
+
+    /// Writes the default config to the path resolved as `load_from_default_path`, never
+    /// overwrites an existing file. Returns the path written.
+    pub fn init_default_config(config_path: Option<&Path>) -> Result<PathBuf> {
+        let config_path = Self::resolve_config_path(config_path)?;
+        if config_path.exists() {
+            return Err(ConfigError::AlreadyExists(config_path));
         }
+        Self::create_default_toml(&config_path)?;
+        Ok(config_path)
+    }
 
+    pub fn load_from_path(config_path: &Path) -> Result<Self> {
         let config_file_contents = fs::read_to_string(config_path)?;
-        let config: Config = toml::from_str(config_file_contents.as_ref())?;
+        let mut config: Config = toml::from_str(config_file_contents.as_ref())?;
+        config.path = config_path.to_path_buf();
+
+        let mut names: HashSet<CmdName> = config.cmds.iter().map(|c| c.name.clone()).collect();
+        let mut including = vec![fs::canonicalize(config_path)?];
+        let mut included_cmds = Vec::new();
+        for include in config.include.iter() {
+            load_included(
+                &resolve_include(config_path, include),
+                &mut including,
+                &mut names,
+                &mut included_cmds,
+            )?;
+        }
+        config.cmds.extend(included_cmds);
+
+        config.check_request_timeout()?;
+        config.check_wait_for_exit()?;
 
         Ok(config)
     }
@@ -74,12 +320,171 @@ impl Config {
         Ok(())
     }
 
-    fn default_config_path() -> Result<PathBuf> {
+    fn resolve_config_path(config_path: Option<&Path>) -> Result<PathBuf> {
+        match Self::explicit_config_path(config_path) {
+            Some(config_path) => Ok(config_path),
+            None => Self::default_config_path(),
+        }
+    }
+
+    /// The config path given by `--config` or `$DAIRI_CONFIG`.
+    pub fn explicit_config_path(config_path: Option<&Path>) -> Option<PathBuf> {
+        config_path
+            .map(Path::to_path_buf)
+            .or_else(|| std::env::var_os(CONFIG_PATH_ENV).map(PathBuf::from))
+    }
+
+    pub fn default_config_path() -> Result<PathBuf> {
         let mut dir = home_dir().ok_or(ConfigError::FaildToGetHome)?;
         dir.push(".config/dairi/config.toml");
         Ok(dir)
     }
-    pub fn as_cmd_table(&self) -> CmdTable {
+    /// The request timeout layer would cut a cmd short of its own timeout and respond with a
+    /// bare 408 while the process is still running.
+    fn check_request_timeout(&self) -> Result<()> {
+        let request_timeout_sec = self
+            .request_timeout_sec
+            .unwrap_or(DEFAULT_REQUEST_TIMEOUT_SEC);
+        for cmd_config in self.cmds.iter() {
+            let timeout_sec = cmd_config.timeout_sec.unwrap_or(DEFAULT_CMD_TIMEOUT_SEC);
+            let cmd_timeout_sec = timeout_sec.max(cmd_config.spawn_timeout_sec.unwrap_or(0));
+            if request_timeout_sec < cmd_timeout_sec {
+                return Err(ConfigError::RequestTimeoutTooShort {
+                    request_timeout_sec,
+                    name: cmd_config.name.clone(),
+                    cmd_timeout_sec,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// A persistent process doesn't exit after the input.
+    fn check_wait_for_exit(&self) -> Result<()> {
+        match self.cmds.iter().find(|cmd_config| {
+            cmd_config.wait_for_exit.unwrap_or(false) && cmd_config.persistent.unwrap_or(true)
+        }) {
+            Some(cmd_config) => Err(ConfigError::WaitForExitRequiresOneShot(
+                cmd_config.name.clone(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the problems that would make the cmds fail at runtime.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut names = HashSet::new();
+
+        if let Some(log_level) = self.log_level.as_ref() {
+            if logging::parse_log_level(log_level).is_none() {
+                problems.push(format!("invalid log_level: {:?}", log_level));
+            }
+        }
+
+        for cmd_config in self.cmds.iter() {
+            let name = &cmd_config.name;
+            if !names.insert(name) {
+                problems.push(format!("cmd [{}]: duplicated cmd name", name));
+            }
+
+            for (field, regex) in [
+                (
+                    "truncate_line_regex",
+                    cmd_config.truncate_line_regex.as_ref(),
+                ),
+                ("prompt_regex", cmd_config.prompt_regex.as_ref()),
+                ("ready_regex", cmd_config.ready_regex.as_ref()),
+                (
+                    "output_filter_regex",
+                    cmd_config.output_filter_regex.as_ref(),
+                ),
+                ("error_regex", cmd_config.error_regex.as_ref()),
+            ] {
+                if let Some(Err(e)) = regex.map(|regex| Regex::new(regex)) {
+                    problems.push(format!("cmd [{}]: invalid {}: {}", name, field, e));
+                }
+            }
+
+            if cmd_config.init_input.is_some() && cmd_config.persistent == Some(false) {
+                problems.push(format!(
+                    "cmd [{}]: init_input requires a persistent cmd",
+                    name
+                ));
+            }
+
+            if let Some(fallback) = cmd_config.fallback.as_ref() {
+                if !self
+                    .cmds
+                    .iter()
+                    .any(|cmd_config| &cmd_config.name == fallback)
+                {
+                    problems.push(format!(
+                        "cmd [{}]: fallback {} is not defined",
+                        name, fallback
+                    ));
+                }
+            }
+
+            if cmd_config.rate_limit_per_sec == Some(0) {
+                problems.push(format!(
+                    "cmd [{}]: rate_limit_per_sec 0 rejects every run",
+                    name
+                ));
+            }
+
+            if cmd_config.prompt_output.is_some() && cmd_config.prompt_regex.is_none() {
+                problems.push(format!(
+                    "cmd [{}]: prompt_output requires prompt_regex",
+                    name
+                ));
+            }
+
+            let use_shell = cmd_config.use_shell.or(self.use_shell).unwrap_or(false);
+            let cmd = match expand_cmd(&cmd_config.cmd, use_shell) {
+                Ok(cmd) => cmd,
+                Err(e) => {
+                    problems.push(format!("cmd [{}]: {}", name, e));
+                    cmd_config.cmd.clone()
+                }
+            };
+            let program = if use_shell {
+                cmd_config.shell.as_deref().unwrap_or(DEFAULT_SHELL)
+            } else {
+                cmd.split_whitespace().next().unwrap_or("")
+            };
+            if find_program(program).is_none() {
+                problems.push(format!("cmd [{}]: program not found: {:?}", name, program));
+            }
+        }
+
+        problems
+    }
+
+    pub fn as_server_config(&self) -> ServerConfig {
+        ServerConfig {
+            socket_path: None,
+            config_path: self.path.clone(),
+            allowed_uids: self.allowed_uids.clone(),
+            line_protocol_socket: self.line_protocol_socket.clone(),
+            request_timeout_sec: self.request_timeout_sec,
+            socket_mode: self.socket_mode,
+            http1_keep_alive: self.http1_keep_alive,
+            http2_max_concurrent_streams: self.http2_max_concurrent_streams,
+            compress_responses: self.compress_responses,
+        }
+    }
+
+    pub fn as_process_limit(&self) -> Option<ProcessLimit> {
+        self.max_processes.map(|max_processes| ProcessLimit {
+            max_processes,
+            policy: self
+                .max_processes_policy
+                .unwrap_or(ProcessLimitPolicy::EvictLru),
+        })
+    }
+
+    pub fn as_cmd_table(&self) -> Result<CmdTable> {
         let mut cmd_table = CmdTable::new();
 
         for CmdConfig {
@@ -92,28 +497,254 @@ impl Config {
             remove_empty_line,
             no_empty_input,
             timeout_sec,
-            wait_output_timeout_milli_sec: wait_output_timeout_sec,
+            wait_output_timeout_milli_sec,
+            timeout_signal,
+            cache,
+            quit_cmd,
+            prompt_regex,
+            prompt_output,
+            skip_leading_output_lines,
+            use_shell,
+            shell,
+            init_input,
+            capture_stderr,
+            output_encoding,
+            spawn_retries,
+            spawn_retry_base_milli_sec,
+            history_file,
+            output_filter_regex,
+            output_replace,
+            strip_ansi,
+            use_pty,
+            spawn_timeout_sec,
+            persistent,
+            on_concurrent_request,
+            strip_input_echo,
+            reset_input,
+            read_buffer_size,
+            args,
+            env,
+            wait_for_exit,
+            ready_regex,
+            max_lifetime_sec,
+            recent_output_lines,
+            rate_limit_per_sec,
+            input_pipeline,
+            fallback,
+            redirect_stderr_to_stdout,
+            max_output_lines,
+            input_newline,
+            input_encoding,
+            debounce_milli_sec,
+            output_sentinel,
+            error_regex,
         } in self.cmds.iter()
         {
             cmd_table.insert(
                 name.clone(),
-                Cmd::new(
-                    name.clone(),
-                    cmd.clone(),
-                    output_size.unwrap_or(DEFAULT_OUTPUT_SIZE),
-                    auto_trailing_newline.unwrap_or(false),
-                    join_input_newline_with.clone(),
-                    truncate_line_regex.clone(),
-                    *remove_empty_line,
-                    *no_empty_input,
-                    *timeout_sec,
-                    *wait_output_timeout_sec,
-                ),
+                Cmd {
+                    name: name.clone(),
+                    cmd: expand_cmd(cmd, use_shell.or(self.use_shell).unwrap_or(false))?,
+                    output_size: output_size.unwrap_or(DEFAULT_OUTPUT_SIZE),
+                    auto_trailing_newline: auto_trailing_newline.unwrap_or(false),
+                    join_input_newline_with: join_input_newline_with.clone(),
+                    truncate_line_regex: truncate_line_regex.clone(),
+                    remove_empty_line: *remove_empty_line,
+                    no_empty_input: *no_empty_input,
+                    timeout_sec: *timeout_sec,
+                    wait_output_timeout_milli_sec: *wait_output_timeout_milli_sec,
+                    timeout_signal: *timeout_signal,
+                    cache: *cache,
+                    quit_cmd: quit_cmd.clone(),
+                    prompt_regex: prompt_regex.clone(),
+                    prompt_output: *prompt_output,
+                    skip_leading_output_lines: *skip_leading_output_lines,
+                    use_shell: use_shell.or(self.use_shell).unwrap_or(false),
+                    shell: shell.clone(),
+                    init_input: init_input.clone(),
+                    capture_stderr: *capture_stderr,
+                    output_encoding: *output_encoding,
+                    spawn_retries: *spawn_retries,
+                    spawn_retry_base_milli_sec: *spawn_retry_base_milli_sec,
+                    history_file: history_file.as_deref().map(history::expand_tilde),
+                    output_filter_regex: output_filter_regex.clone(),
+                    output_replace: output_replace.clone(),
+                    strip_ansi: *strip_ansi,
+                    use_pty: *use_pty,
+                    spawn_timeout_sec: *spawn_timeout_sec,
+                    persistent: *persistent,
+                    on_concurrent_request: *on_concurrent_request,
+                    strip_input_echo: *strip_input_echo,
+                    reset_input: reset_input.clone(),
+                    read_buffer_size: *read_buffer_size,
+                    args: args.clone(),
+                    env: env.clone(),
+                    wait_for_exit: *wait_for_exit,
+                    ready_regex: ready_regex.clone(),
+                    max_lifetime_sec: *max_lifetime_sec,
+                    recent_output_lines: *recent_output_lines,
+                    rate_limit_per_sec: *rate_limit_per_sec,
+                    input_pipeline: input_pipeline
+                        .clone()
+                        .or_else(|| self.input_pipeline.clone()),
+                    fallback: fallback.clone(),
+                    redirect_stderr_to_stdout: *redirect_stderr_to_stdout,
+                    max_output_lines: *max_output_lines,
+                    input_newline: input_newline.clone(),
+                    input_encoding: *input_encoding,
+                    debounce_milli_sec: *debounce_milli_sec,
+                    output_sentinel: output_sentinel.clone(),
+                    error_regex: error_regex.clone(),
+                },
             );
         }
 
-        cmd_table
+        Ok(cmd_table)
+    }
+}
+
+fn deserialize_octal_mode<'de, D>(deserializer: D) -> std::result::Result<Option<u32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let mode = match Option::<String>::deserialize(deserializer)? {
+        Some(mode) => mode,
+        None => return Ok(None),
+    };
+    let digits = mode.strip_prefix("0o").unwrap_or(&mode);
+    match u32::from_str_radix(digits, 8) {
+        Ok(parsed) if parsed <= 0o777 => Ok(Some(parsed)),
+        _ => Err(serde::de::Error::custom(format!(
+            "invalid octal mode: {:?}",
+            mode
+        ))),
+    }
+}
+
+/// Resolves the path in `include` of the file at `including_path`.
+fn resolve_include(including_path: &Path, include: &Path) -> PathBuf {
+    let include = history::expand_tilde(include);
+    match including_path.parent() {
+        Some(dir) if include.is_relative() => dir.join(include),
+        _ => include,
+    }
+}
+
+/// Appends the cmds of the file and the files it includes to `cmds`. `including` is the chain of
+/// the files including it, to detect a cycle.
+fn load_included(
+    path: &Path,
+    including: &mut Vec<PathBuf>,
+    names: &mut HashSet<CmdName>,
+    cmds: &mut Vec<CmdConfig>,
+) -> Result<()> {
+    let canonical_path = fs::canonicalize(path)?;
+    if including.contains(&canonical_path) {
+        return Err(ConfigError::IncludeCycle(path.to_path_buf()));
+    }
+
+    let contents = fs::read_to_string(path)?;
+    let included: IncludedConfig =
+        toml::from_str(&contents).map_err(|source| ConfigError::IncludedTomlError {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    tracing::debug!(
+        "including {} cmds from {}",
+        included.cmds.len(),
+        path.display()
+    );
+
+    for cmd_config in included.cmds {
+        if !names.insert(cmd_config.name.clone()) {
+            return Err(ConfigError::DuplicatedIncludedCmd {
+                name: cmd_config.name,
+                path: path.to_path_buf(),
+            });
+        }
+        cmds.push(cmd_config);
+    }
+
+    including.push(canonical_path);
+    for include in included.include.iter() {
+        load_included(&resolve_include(path, include), including, names, cmds)?;
+    }
+    including.pop();
+    Ok(())
+}
+
+/// Replaces `$VAR` and `${VAR}` with the environment variables. A `$` not followed by a
+/// variable name is kept as is and `$$` is a literal `$`.
+/// The cmd of `use_shell` is passed to the shell as is, which expands the variables itself,
+/// e.g. `${FOO:-default}`.
+fn expand_cmd(cmd: &str, use_shell: bool) -> Result<String> {
+    if use_shell {
+        Ok(cmd.to_string())
+    } else {
+        expand_env_vars(cmd)
+    }
+}
+
+pub fn expand_env_vars(s: &str) -> Result<String> {
+    fn is_name_char(c: char) -> bool {
+        c.is_ascii_alphanumeric() || c == '_'
+    }
+
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find('$') {
+        expanded.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+
+        let (name, remaining) = if let Some(inner) = after.strip_prefix('{') {
+            match inner.find('}') {
+                Some(end) if end > 0 => (&inner[..end], &inner[end + 1..]),
+                _ => ("", after),
+            }
+        } else if let Some(remaining) = after.strip_prefix('$') {
+            expanded.push('$');
+            rest = remaining;
+            continue;
+        } else if after.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            let end = after.find(|c| !is_name_char(c)).unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        } else {
+            ("", after)
+        };
+
+        if name.is_empty() {
+            expanded.push('$');
+        } else {
+            let value =
+                std::env::var(name).map_err(|_| ConfigError::UnresolvedEnvVar(name.to_string()))?;
+            expanded.push_str(&value);
+        }
+        rest = remaining;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Resolves the program like `which`, searching `PATH` unless the program contains a `/`.
+pub fn find_program(program: &str) -> Option<PathBuf> {
+    fn is_executable(path: &Path) -> bool {
+        fs::metadata(path)
+            .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    }
+
+    if program.is_empty() {
+        return None;
+    }
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return is_executable(&path).then_some(path);
     }
+
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths)
+        .map(|dir| dir.join(program))
+        .find(|path| is_executable(path))
 }
 
 const DEFAULT_CONFIG: &str = r##"
@@ -128,6 +759,8 @@ remove_empty_line = true
 no_empty_input = true
 timeout_sec = 120
 wait_output_timeout_milli_sec = 500
+args = ["--banner=no"]
+env = { JULIA_NUM_THREADS = "1" }
 
 "##;
 
@@ -141,5 +774,326 @@ mod test {
         assert_eq!(config.cmds.len(), 1);
         assert_eq!(config.cmds[0].cmd, "julia");
         assert_eq!(config.cmds[0].name, "julia");
+        assert_eq!(config.cmds[0].args, vec!["--banner=no".to_string()]);
+        assert_eq!(
+            config.cmds[0].env,
+            HashMap::from([("JULIA_NUM_THREADS".to_string(), "1".to_string())])
+        );
+    }
+
+    #[test]
+    fn test_parse_minimal_config() {
+        let config: Config = toml::from_str(
+            r##"
+[[cmds]]
+name = "sh"
+cmd = "sh"
+"##,
+        )
+        .unwrap();
+        let cmd_config = &config.cmds[0];
+        assert!(!cmd_config.remove_empty_line);
+        assert!(!cmd_config.no_empty_input);
+        assert!(cmd_config.args.is_empty());
+        assert!(cmd_config.env.is_empty());
+
+        let config: Config = toml::from_str(
+            r##"
+[[cmds]]
+name = "sh"
+cmd = "sh"
+no_empty_input = true
+"##,
+        )
+        .unwrap();
+        assert!(!config.cmds[0].remove_empty_line);
+        assert!(config.cmds[0].no_empty_input);
+
+        let cmd_table = config.as_cmd_table().unwrap();
+        assert_eq!(
+            Cmd {
+                name: "sh".to_string(),
+                cmd: "sh".to_string(),
+                output_size: DEFAULT_OUTPUT_SIZE,
+                no_empty_input: true,
+                ..Default::default()
+            },
+            cmd_table["sh"]
+        );
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let config: Config = toml::from_str(
+            r##"
+[[cmds]]
+name = "sh"
+cmd = "sh"
+
+[[cmds]]
+name = "sh"
+cmd = "no_such_program_dairi"
+truncate_line_regex = "(unclosed"
+error_regex = "[unclosed"
+prompt_output = "joined"
+"##,
+        )
+        .unwrap();
+
+        let problems = config.validate();
+        assert_eq!(5, problems.len(), "{:?}", problems);
+        assert!(problems[0].contains("duplicated"));
+        assert!(problems[1].contains("truncate_line_regex"));
+        assert!(problems[2].contains("error_regex"));
+        assert!(problems[3].contains("prompt_output"));
+        assert!(problems[4].contains("no_such_program_dairi"));
+    }
+
+    #[test]
+    fn test_socket_mode() {
+        let socket_mode = |toml: &str| {
+            toml::from_str::<Config>(&format!("{}\ncmds = []", toml)).map(|c| c.socket_mode)
+        };
+        assert_eq!(None, socket_mode("").unwrap());
+        assert_eq!(Some(0o600), socket_mode(r#"socket_mode = "0600""#).unwrap());
+        assert_eq!(
+            Some(0o660),
+            socket_mode(r#"socket_mode = "0o660""#).unwrap()
+        );
+        assert!(socket_mode(r#"socket_mode = "0800""#).is_err());
+        assert!(socket_mode(r#"socket_mode = "1777""#).is_err());
+    }
+
+    #[test]
+    fn test_check_wait_for_exit() {
+        let config = |persistent: &str| -> Config {
+            toml::from_str(&format!(
+                "[[cmds]]\nname = \"sort\"\ncmd = \"sort\"\nwait_for_exit = true\n{}",
+                persistent
+            ))
+            .unwrap()
+        };
+        assert!(config("persistent = false").check_wait_for_exit().is_ok());
+        for persistent in ["", "persistent = true"] {
+            assert!(matches!(
+                config(persistent).check_wait_for_exit(),
+                Err(ConfigError::WaitForExitRequiresOneShot(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_check_request_timeout() {
+        let config = |toml: &str| -> Config { toml::from_str(toml).unwrap() };
+        let cmds = r##"
+[[cmds]]
+name = "julia"
+cmd = "julia"
+timeout_sec = 60
+spawn_timeout_sec = 120
+"##;
+
+        assert!(config(cmds).check_request_timeout().is_ok());
+        assert!(config(&format!("request_timeout_sec = 120\n{}", cmds))
+            .check_request_timeout()
+            .is_ok());
+        match config(&format!("request_timeout_sec = 90\n{}", cmds)).check_request_timeout() {
+            Err(ConfigError::RequestTimeoutTooShort {
+                request_timeout_sec,
+                name,
+                cmd_timeout_sec,
+            }) => {
+                assert_eq!(90, request_timeout_sec);
+                assert_eq!("julia", name);
+                assert_eq!(120, cmd_timeout_sec);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        // timeout_sec defaults to 30
+        let default_timeout_cmds = r##"
+request_timeout_sec = 10
+[[cmds]]
+name = "sh"
+cmd = "sh"
+"##;
+        assert!(config(default_timeout_cmds)
+            .check_request_timeout()
+            .is_err());
+    }
+
+    #[test]
+    fn test_load_from_explicit_path() {
+        let dir = std::env::temp_dir().join(format!("dairi-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("work.toml");
+        fs::write(
+            &config_path,
+            DEFAULT_CONFIG.replace("name = \"julia\"", "name = \"work_julia\""),
+        )
+        .unwrap();
+
+        let config = Config::load_from_default_path(Some(&config_path)).unwrap();
+        assert_eq!(config_path, config.path);
+        assert_eq!("work_julia", config.cmds[0].name);
+
+        let none_path = dir.join("none.toml");
+        assert!(matches!(
+            Config::load_from_default_path(Some(&none_path)),
+            Err(ConfigError::NotFound(_))
+        ));
+        assert!(!none_path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_init_default_config() {
+        let dir = std::env::temp_dir().join(format!("dairi-init-{}", std::process::id()));
+        let config_path = dir.join("nested").join("config.toml");
+
+        assert_eq!(
+            config_path,
+            Config::init_default_config(Some(&config_path)).unwrap()
+        );
+        assert_eq!(DEFAULT_CONFIG, fs::read_to_string(&config_path).unwrap());
+        assert!(matches!(
+            Config::init_default_config(Some(&config_path)),
+            Err(ConfigError::AlreadyExists(_))
+        ));
+
+        let config = Config::load_from_default_path(Some(&config_path)).unwrap();
+        assert_eq!("julia", config.cmds[0].name);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_input_pipeline() {
+        let config: Config = toml::from_str(
+            r#"
+input_pipeline = ["truncate_line", "auto_trailing_newline"]
+[[cmds]]
+name = "julia"
+cmd = "julia"
+input_pipeline = ["join_input_newline", "truncate_line"]
+[[cmds]]
+name = "python"
+cmd = "python"
+"#,
+        )
+        .unwrap();
+        let cmd_table = config.as_cmd_table().unwrap();
+        assert_eq!(
+            Some(vec![InputStep::JoinInputNewline, InputStep::TruncateLine]),
+            cmd_table["julia"].input_pipeline
+        );
+        assert_eq!(
+            Some(vec![
+                InputStep::TruncateLine,
+                InputStep::AutoTrailingNewline
+            ]),
+            cmd_table["python"].input_pipeline
+        );
+
+        let error = toml::from_str::<Config>(
+            "[[cmds]]\nname = \"julia\"\ncmd = \"julia\"\ninput_pipeline = [\"trim\"]\n",
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("trim"), "{}", error);
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("dairi-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("repls")).unwrap();
+        let write = |path: &str, contents: &str| fs::write(dir.join(path), contents).unwrap();
+        let cmd = |name: &str| format!("[[cmds]]\nname = \"{0}\"\ncmd = \"{0}\"\n", name);
+
+        write(
+            "config.toml",
+            &format!("include = [\"repls/extra.toml\"]\n{}", cmd("julia")),
+        );
+        write(
+            "repls/extra.toml",
+            &format!("include = [\"nested.toml\"]\n{}", cmd("python")),
+        );
+        write("repls/nested.toml", &cmd("node"));
+        let config = Config::load_from_path(&dir.join("config.toml")).unwrap();
+        let names: Vec<&str> = config.cmds.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(vec!["julia", "python", "node"], names);
+
+        write("repls/nested.toml", &cmd("julia"));
+        assert!(matches!(
+            Config::load_from_path(&dir.join("config.toml")),
+            Err(ConfigError::DuplicatedIncludedCmd { name, .. }) if name == "julia"
+        ));
+
+        write("repls/nested.toml", "include = [\"extra.toml\"]\n");
+        assert!(matches!(
+            Config::load_from_path(&dir.join("config.toml")),
+            Err(ConfigError::IncludeCycle(_))
+        ));
+
+        write("repls/nested.toml", "log_level = \"debug\"\n");
+        assert!(matches!(
+            Config::load_from_path(&dir.join("config.toml")),
+            Err(ConfigError::IncludedTomlError { .. })
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expand_env_vars() {
+        std::env::set_var("DAIRI_TEST_JULIA_BIN", "/opt/julia/bin/julia");
+        assert_eq!(
+            "/opt/julia/bin/julia --quiet",
+            expand_env_vars("$DAIRI_TEST_JULIA_BIN --quiet").unwrap()
+        );
+        assert_eq!(
+            "/opt/julia/bin/julia_1",
+            expand_env_vars("${DAIRI_TEST_JULIA_BIN}_1").unwrap()
+        );
+        assert_eq!(
+            "echo $ $1 ${ $HOME",
+            expand_env_vars("echo $ $1 ${ $$HOME").unwrap()
+        );
+
+        match expand_env_vars("${DAIRI_TEST_UNSET_VAR}/python") {
+            Err(ConfigError::UnresolvedEnvVar(name)) => assert_eq!("DAIRI_TEST_UNSET_VAR", name),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_shell_cmd_not_expanded() {
+        let config: Config = toml::from_str(
+            r#"
+            [[cmds]]
+            name = "shell"
+            cmd = "echo $HOME $$ ${DAIRI_TEST_UNSET_VAR:-default}"
+            use_shell = true
+
+            [[cmds]]
+            name = "program"
+            cmd = "echo $HOME $$"
+            "#,
+        )
+        .unwrap();
+        let cmd_table = config.as_cmd_table().unwrap();
+        assert_eq!(
+            "echo $HOME $$ ${DAIRI_TEST_UNSET_VAR:-default}",
+            cmd_table["shell"].cmd
+        );
+        assert_eq!(
+            format!("echo {} $", std::env::var("HOME").unwrap()),
+            cmd_table["program"].cmd
+        );
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_find_program() {
+        assert!(find_program("sh").is_some());
+        assert_eq!(Some(PathBuf::from("/bin/sh")), find_program("/bin/sh"));
+        assert_eq!(None, find_program("no_such_program_dairi"));
+        assert_eq!(None, find_program(""));
     }
 }
@@ -0,0 +1,127 @@
+//! Running the server in the background with `--daemonize`, and the pidfile of `--pidfile`
+//! for the scripts managing it outside systemd.
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Forks twice so that the server is detached from the terminal and can't acquire it again,
+/// and points stdin, stdout and stderr to `/dev/null`. The working directory is kept so that
+/// the relative paths of the config still resolve. Must be called before the runtime starts
+/// any thread.
+pub fn daemonize() -> io::Result<()> {
+    // SAFETY: no other thread is running, the parents exit right after the fork without
+    // running any destructor.
+    unsafe {
+        if check(libc::fork())? > 0 {
+            libc::_exit(0);
+        }
+        check(libc::setsid())?;
+        if check(libc::fork())? > 0 {
+            libc::_exit(0);
+        }
+
+        let dev_null = check(libc::open(c"/dev/null".as_ptr(), libc::O_RDWR))?;
+        for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            check(libc::dup2(dev_null, fd))?;
+        }
+        if dev_null > libc::STDERR_FILENO {
+            libc::close(dev_null);
+        }
+    }
+    Ok(())
+}
+
+fn is_running(pid: libc::pid_t) -> bool {
+    // SAFETY: signal 0 only checks the existence of the process.
+    pid > 0
+        && (unsafe { libc::kill(pid, 0) } == 0
+            // the process of another user
+            || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+}
+
+/// Fails if the pid in the existing pidfile is still running. Checked before `daemonize` so
+/// that the error is printed on the terminal. A file left by a server that didn't stop
+/// cleanly is overwritten by `write_pidfile`.
+pub fn check_pidfile(path: &Path) -> io::Result<()> {
+    let pid = match fs::read_to_string(path) {
+        Ok(contents) => contents.trim().parse::<libc::pid_t>().ok(),
+        Err(_) => None,
+    };
+    match pid {
+        Some(pid) if pid != std::process::id() as libc::pid_t && is_running(pid) => {
+            Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!(
+                    "dairi is already running with pid {} of {}",
+                    pid,
+                    path.display()
+                ),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Writes the pid of this process.
+pub fn write_pidfile(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, format!("{}\n", std::process::id()))
+}
+
+/// Removes the pidfile unless another process has written its pid since.
+pub fn remove_pidfile(path: &Path) {
+    let owned = fs::read_to_string(path)
+        .map(|contents| contents.trim() == std::process::id().to_string())
+        .unwrap_or(false);
+    if owned {
+        if let Err(e) = fs::remove_file(path) {
+            tracing::warn!("failed to remove the pidfile {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pidfile() {
+        let dir = std::env::temp_dir().join(format!("dairi-pidfile-test-{}", std::process::id()));
+        let path = dir.join("dairi.pid");
+
+        check_pidfile(&path).unwrap();
+        write_pidfile(&path).unwrap();
+        assert_eq!(
+            format!("{}\n", std::process::id()),
+            fs::read_to_string(&path).unwrap()
+        );
+        check_pidfile(&path).unwrap();
+        remove_pidfile(&path);
+        assert!(!path.exists());
+
+        // pid 1 is always running
+        fs::write(&path, "1\n").unwrap();
+        let e = check_pidfile(&path).unwrap_err();
+        assert_eq!(ErrorKind::AlreadyExists, e.kind());
+        // not removed since it isn't ours
+        remove_pidfile(&path);
+        assert!(path.exists());
+
+        // left by a stopped server
+        fs::write(&path, "0\n").unwrap();
+        check_pidfile(&path).unwrap();
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
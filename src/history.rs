@@ -0,0 +1,104 @@
+//! Appends the inputs passed to the cmds into their `history_file`.
+//!
+//! The files are written by a background thread fed by a channel so that recording an input
+//! doesn't add latency to running the cmd.
+
+use dirs::home_dir;
+use once_cell::sync::Lazy;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread;
+
+struct HistoryEntry {
+    path: PathBuf,
+    input: String,
+}
+
+static HISTORY_SENDER: Lazy<Mutex<Sender<HistoryEntry>>> = Lazy::new(|| {
+    let (sender, receiver) = mpsc::channel::<HistoryEntry>();
+    thread::Builder::new()
+        .name("dairi-history".to_string())
+        .spawn(move || {
+            for entry in receiver {
+                if let Err(e) = append_history(&entry.path, &entry.input) {
+                    tracing::warn!("failed to write history to {}: {}", entry.path.display(), e);
+                }
+            }
+        })
+        .expect("failed to spawn the history writer thread");
+    Mutex::new(sender)
+});
+
+/// Replaces the leading `~` of the path with $HOME.
+pub fn expand_tilde(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Queues the input to be appended to the history file, never blocks.
+pub fn record(path: &Path, input: &str) {
+    let entry = HistoryEntry {
+        path: path.to_path_buf(),
+        input: input.to_string(),
+    };
+    if HISTORY_SENDER.lock().unwrap().send(entry).is_err() {
+        tracing::warn!("history writer has stopped");
+    }
+}
+
+fn append_history(path: &Path, input: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(input.as_bytes())?;
+    if !input.ends_with('\n') {
+        file.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_record_history() {
+        let dir = std::env::temp_dir().join(format!("dairi-history-{}", std::process::id()));
+        let path = dir.join("nested").join("julia_history");
+
+        record(&path, "1 + 1\n");
+        record(&path, "println(\"hi\")");
+
+        let mut contents = String::new();
+        for _ in 0..50 {
+            contents = fs::read_to_string(&path).unwrap_or_default();
+            if contents.lines().count() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!("1 + 1\nprintln(\"hi\")\n", contents);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_expand_tilde() {
+        let home = home_dir().unwrap();
+        assert_eq!(
+            home.join(".julia_history"),
+            expand_tilde(Path::new("~/.julia_history"))
+        );
+        assert_eq!(
+            PathBuf::from("/tmp/~history"),
+            expand_tilde(Path::new("/tmp/~history"))
+        );
+    }
+}
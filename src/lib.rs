@@ -1,9 +1,31 @@
+#[allow(dead_code)]
+mod cache;
+
+#[allow(dead_code)]
+mod config;
+
+#[allow(dead_code)]
+mod history;
+
+#[allow(dead_code)]
+mod line_protocol;
+
+#[allow(dead_code)]
+mod logging;
+
 mod lua_client;
 
 #[allow(dead_code)]
-mod process_manager;
+mod metrics;
+
+#[allow(dead_code)]
+pub mod process_manager;
+
+mod pty;
+
+mod request_log;
 
 #[allow(dead_code)]
-mod server;
+pub mod server;
 
 pub use lua_client::*;
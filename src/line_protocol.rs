@@ -0,0 +1,247 @@
+//! A plain text protocol to run cmds from shell pipelines, e.g. with `nc -U`.
+//!
+//! Each request is a line of `CMD_NAME<TAB>INPUT` and each response is a line of
+//! `OK<TAB>OUTPUT` or `ERR<TAB>MESSAGE`. Since a line can't contain a newline, the input, the
+//! output and the message are escaped as below.
+//!
+//! | character       | escaped |
+//! |-----------------|---------|
+//! | backslash       | `\\`    |
+//! | newline         | `\n`    |
+//! | carriage return | `\r`    |
+//! | tab             | `\t`    |
+
+use crate::process_manager::{self, RunOptions};
+use crate::server::{bind_socket, remove_stale_socket, ServerConfig, ServerError};
+
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum LineProtocolError {
+    #[error("missing tab between cmd name and input")]
+    MissingSeparator,
+
+    #[error("invalid escape sequence: \\{0}")]
+    InvalidEscape(char),
+
+    #[error("uid {0} is not allowed to run cmds")]
+    PeerNotAllowed(u32),
+
+    #[error("line is over {0} bytes")]
+    LineTooLong(usize),
+}
+
+/// The max length of a request line unless `max_input_size` is set.
+const DEFAULT_MAX_LINE_LEN: usize = 1 << 20;
+
+/// Room for the cmd name and the escapes of the input, which may double its size.
+fn max_line_len() -> usize {
+    process_manager::max_input_size().map_or(DEFAULT_MAX_LINE_LEN, |max_input_size| {
+        max_input_size.saturating_mul(2).saturating_add(1024)
+    })
+}
+
+/// Reads a line without the newline, `None` at EOF. A line over `max_len` is skipped to the
+/// next newline without being buffered.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> io::Result<Option<Result<String, LineProtocolError>>> {
+    let mut line = Vec::new();
+    let mut too_long = false;
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            if line.is_empty() && !too_long {
+                return Ok(None);
+            }
+            break;
+        }
+        let (chunk_len, found_newline) = match buf.iter().position(|b| *b == b'\n') {
+            Some(newline) => (newline, true),
+            None => (buf.len(), false),
+        };
+        if line.len() + chunk_len > max_len {
+            too_long = true;
+            line = Vec::new();
+        }
+        if !too_long {
+            line.extend_from_slice(&buf[..chunk_len]);
+        }
+        reader.consume(chunk_len + usize::from(found_newline));
+        if found_newline {
+            break;
+        }
+    }
+
+    if too_long {
+        return Ok(Some(Err(LineProtocolError::LineTooLong(max_len))));
+    }
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(|line| Some(Ok(line)))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn unescape(s: &str) -> Result<String, LineProtocolError> {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('t') => unescaped.push('\t'),
+            Some(c) => return Err(LineProtocolError::InvalidEscape(c)),
+            None => return Err(LineProtocolError::InvalidEscape(' ')),
+        }
+    }
+    Ok(unescaped)
+}
+
+fn parse_request(line: &str) -> Result<(String, String), LineProtocolError> {
+    let (cmd_name, input) = line
+        .split_once('\t')
+        .ok_or(LineProtocolError::MissingSeparator)?;
+    Ok((cmd_name.to_string(), unescape(input)?))
+}
+
+pub async fn serve(
+    socket_path: &Path,
+    server_config: Arc<ServerConfig>,
+) -> Result<(), ServerError> {
+    remove_stale_socket(socket_path).await?;
+    if let Some(dir) = socket_path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let listener = bind_socket(socket_path, server_config.socket_mode)?;
+    tracing::info!("line protocol is listening at {}", socket_path.display());
+
+    loop {
+        let (stream, _addr) = listener.accept().await?;
+        tokio::spawn(handle_connection(stream, server_config.clone()));
+    }
+}
+
+async fn handle_connection(stream: UnixStream, server_config: Arc<ServerConfig>) {
+    let uid = stream.peer_cred().ok().map(|cred| cred.uid());
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let result = match read_line(&mut reader, max_line_len()).await {
+            Ok(Some(Ok(line))) => handle_line(&line, uid, &server_config).await,
+            Ok(Some(Err(e))) => Err(e.to_string()),
+            Ok(None) => break,
+            Err(e) => {
+                tracing::warn!("failed to read line protocol request: {}", e);
+                break;
+            }
+        };
+        let response = match result {
+            Ok(output) => format!("OK\t{}\n", escape(&output)),
+            Err(message) => format!("ERR\t{}\n", escape(&message)),
+        };
+        if let Err(e) = writer.write_all(response.as_bytes()).await {
+            tracing::warn!("failed to write line protocol response: {}", e);
+            break;
+        }
+    }
+}
+
+async fn handle_line(
+    line: &str,
+    uid: Option<u32>,
+    server_config: &ServerConfig,
+) -> Result<String, String> {
+    if let Some(allowed_uids) = server_config.allowed_uids.as_ref() {
+        let uid = uid.unwrap_or(u32::MAX);
+        if !allowed_uids.contains(&uid) {
+            return Err(LineProtocolError::PeerNotAllowed(uid).to_string());
+        }
+    }
+
+    let (cmd_name, input) = parse_request(line).map_err(|e| e.to_string())?;
+    let cmd_output = process_manager::run_cmd(&cmd_name, input.into(), RunOptions::default())
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(String::from_utf8_lossy(&cmd_output.output).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        let s = "using CSV\n\tx = \"a\\b\"\r\n";
+        assert_eq!("using CSV\\n\\tx = \"a\\\\b\"\\r\\n", escape(s));
+        assert_eq!(s, unescape(&escape(s)).unwrap());
+
+        assert_eq!(Err(LineProtocolError::InvalidEscape('x')), unescape("\\x"));
+        assert_eq!(Err(LineProtocolError::InvalidEscape(' ')), unescape("a\\"));
+    }
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(
+            ("julia".to_string(), "1+1\n2+2".to_string()),
+            parse_request("julia\t1+1\\n2+2").unwrap()
+        );
+        assert_eq!(
+            ("julia".to_string(), "".to_string()),
+            parse_request("julia\t").unwrap()
+        );
+        assert_eq!(
+            Err(LineProtocolError::MissingSeparator),
+            parse_request("julia")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reject_too_long_line() {
+        let (client, server) = UnixStream::pair().unwrap();
+        tokio::spawn(handle_connection(server, Arc::new(ServerConfig::default())));
+        let (reader, mut writer) = client.into_split();
+        let mut responses = BufReader::new(reader).lines();
+
+        let mut line = vec![b'x'; max_line_len().max(DEFAULT_MAX_LINE_LEN) + 1];
+        line.push(b'\n');
+        writer.write_all(&line).await.unwrap();
+        let response = responses.next_line().await.unwrap().unwrap();
+        assert!(response.starts_with("ERR\tline is over "), "{}", response);
+
+        // the connection is still usable
+        writer.write_all(b"julia\r\n").await.unwrap();
+        assert_eq!(
+            "ERR\tmissing tab between cmd name and input",
+            responses.next_line().await.unwrap().unwrap()
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! Sets up the tracing subscriber from `log_level` and `log_format` of the config.
+//!
+//! `RUST_LOG` overrides `log_level` when it is set. Only a plain level, e.g. `debug`, is
+//! supported since per target directives need the `env-filter` feature of
+//! `tracing-subscriber`.
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+
+const LOG_LEVEL_ENV: &str = "RUST_LOG";
+const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::INFO;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Pretty,
+    Compact,
+    /// One JSON object per line.
+    Json,
+}
+
+pub fn parse_log_level(log_level: &str) -> Option<LevelFilter> {
+    LevelFilter::from_str(log_level.trim()).ok()
+}
+
+/// Resolves the level from `RUST_LOG` and then `log_level`, falls back to `info`.
+fn resolve_log_level(env_log_level: Option<&str>, log_level: Option<&str>) -> LevelFilter {
+    env_log_level
+        .and_then(parse_log_level)
+        .or_else(|| log_level.and_then(parse_log_level))
+        .unwrap_or(DEFAULT_LOG_LEVEL)
+}
+
+pub fn init(log_level: Option<&str>, log_format: Option<LogFormat>) {
+    let env_log_level = std::env::var(LOG_LEVEL_ENV).ok();
+    let level = resolve_log_level(env_log_level.as_deref(), log_level);
+
+    let builder = tracing_subscriber::fmt().with_max_level(level);
+    match log_format {
+        Some(LogFormat::Pretty) => builder.pretty().init(),
+        Some(LogFormat::Compact) => builder.compact().init(),
+        Some(LogFormat::Json) => builder
+            .with_ansi(false)
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .init(),
+        None => builder.init(),
+    }
+
+    if let Some(env_log_level) = env_log_level {
+        if parse_log_level(&env_log_level).is_none() {
+            tracing::warn!(
+                "ignoring {}={:?}, only a plain level is supported",
+                LOG_LEVEL_ENV,
+                env_log_level
+            );
+        }
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(
+            field.name().to_string(),
+            Value::from(format!("{:?}", value)),
+        );
+    }
+}
+
+/// Formats the span fields as the members of a JSON object without the braces, so the
+/// fields recorded later can be appended with a comma.
+struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: tracing_subscriber::field::RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut map = Map::new();
+        fields.record(&mut JsonVisitor(&mut map));
+        let object = Value::Object(map).to_string();
+        writer.write_str(&object[1..object.len() - 1])
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &tracing::span::Record<'_>,
+    ) -> fmt::Result {
+        if !current.fields.is_empty() {
+            current.fields.push(',');
+        }
+        self.format_fields(current.as_writer(), fields)
+    }
+}
+
+struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut fields = Map::new();
+        event.record(&mut JsonVisitor(&mut fields));
+
+        let mut spans = Vec::new();
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                let mut span_fields = extensions
+                    .get::<FormattedFields<JsonFields>>()
+                    .and_then(|formatted| {
+                        serde_json::from_str::<Map<String, Value>>(&format!(
+                            "{{{}}}",
+                            formatted.fields
+                        ))
+                        .ok()
+                    })
+                    .unwrap_or_default();
+                span_fields.insert("name".to_string(), Value::from(span.name()));
+                spans.push(Value::Object(span_fields));
+            }
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs_f64())
+            .unwrap_or_default();
+
+        let mut line = Map::new();
+        line.insert("timestamp".to_string(), Value::from(timestamp));
+        line.insert(
+            "level".to_string(),
+            Value::from(metadata.level().to_string()),
+        );
+        line.insert("target".to_string(), Value::from(metadata.target()));
+        line.insert("fields".to_string(), Value::Object(fields));
+        if !spans.is_empty() {
+            line.insert("spans".to_string(), Value::Array(spans));
+        }
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct TestWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for TestWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_resolve_log_level() {
+        assert_eq!(LevelFilter::INFO, resolve_log_level(None, None));
+        assert_eq!(LevelFilter::WARN, resolve_log_level(None, Some("warn")));
+        assert_eq!(
+            LevelFilter::DEBUG,
+            resolve_log_level(Some("debug"), Some("warn"))
+        );
+        assert_eq!(
+            LevelFilter::WARN,
+            resolve_log_level(Some("dairi=debug"), Some("warn"))
+        );
+        assert_eq!(LevelFilter::INFO, resolve_log_level(None, Some("loud")));
+    }
+
+    #[test]
+    fn test_json_format() {
+        let writer = TestWriter::default();
+        let make_writer = writer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .with_writer(move || make_writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("run_cmd", cmd_name = "julia");
+            let _entered = span.enter();
+            span.record("cmd_name", &"python");
+            tracing::info!(pid = 42, "spawned \"julia\"");
+        });
+
+        let output = String::from_utf8(writer.0.lock().unwrap().clone()).unwrap();
+        let line: Value = serde_json::from_str(output.trim_end()).unwrap();
+        assert_eq!("INFO", line["level"]);
+        assert_eq!("spawned \"julia\"", line["fields"]["message"]);
+        assert_eq!(42, line["fields"]["pid"]);
+        assert_eq!("run_cmd", line["spans"][0]["name"]);
+        assert_eq!("python", line["spans"][0]["cmd_name"]);
+    }
+}
@@ -2,19 +2,26 @@ use mlua::prelude::*;
 
 use axum::{
     body::Body,
-    http::{Error as HttpError, Method, Request, Uri},
+    http::{
+        header::{ACCEPT_ENCODING, CONTENT_ENCODING},
+        Error as HttpError, Method, Request, StatusCode, Uri,
+    },
 };
-use serde_json;
-use std::io;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use futures::Future;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::io::{self, Read};
 use std::pin::Pin;
 use std::string::FromUtf8Error;
+use std::sync::Mutex;
 use std::task::{Context, Poll};
-use tokio::runtime::Runtime; // 0.3.5
+use tokio::runtime::{self, Runtime};
 
 use super::server;
 use hyper::client::connect::{Connected, Connection};
 
-use std::path::PathBuf;
+use std::path::Path;
 use thiserror::Error;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
@@ -37,14 +44,53 @@ pub enum ClientError {
 
     #[error("{0}")]
     FromUtf8Error(#[from] FromUtf8Error),
+
+    #[error("the decompressed response is over {0} bytes")]
+    ResponseTooLarge(u64),
+
+    #[error("{} ({})", .0.error, .0.kind)]
+    ServerError(server::ErrorResponse),
+}
+/// Shared by the calls from Lua so that neither a runtime nor a connection is set up on each
+/// call. The worker thread keeps the pooled connections alive between the calls.
+static RUNTIME: Lazy<Runtime> = Lazy::new(|| {
+    runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .expect("failed to build the dairi client runtime")
+});
+
+type UdsClient = hyper::Client<UdsConnector, Body>;
+
+/// A client per socket, which keeps the connections to the server alive. A connection closed
+/// by the server, e.g. on its restart, is replaced by a new one on the next request.
+static CLIENTS: Lazy<Mutex<HashMap<&'static Path, UdsClient>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn client(socket_path: &'static Path) -> UdsClient {
+    CLIENTS
+        .lock()
+        .unwrap()
+        .entry(socket_path)
+        .or_insert_with(|| hyper::Client::builder().build(UdsConnector { socket_path }))
+        .clone()
+}
+
+/// Replaces the client of the socket, dropping its pooled connections.
+fn renew_client(socket_path: &'static Path) -> UdsClient {
+    let client = hyper::Client::builder().build(UdsConnector { socket_path });
+    CLIENTS.lock().unwrap().insert(socket_path, client.clone());
+    client
 }
+
 // TODO(tacogips) try to use LuaTcpStream
 // https://github.com/khvzak/mlua/blob/master/examples/async_tcp_server.rs
-fn run_cmd(_lua: &Lua, (cmd_name, input): (String, String)) -> LuaResult<String> {
-    let result = Runtime::new().unwrap().block_on(build_client_and_request(
+fn run_cmd(_lua: &Lua, (cmd_name, input): (String, LuaString)) -> LuaResult<String> {
+    let result = RUNTIME.block_on(build_client_and_request_bytes(
         &cmd_name,
         server::default_socket_path(),
-        input,
+        input.as_bytes().to_vec(),
     ));
     match result {
         Ok(result) => Ok(result.output),
@@ -52,39 +98,151 @@ fn run_cmd(_lua: &Lua, (cmd_name, input): (String, String)) -> LuaResult<String>
     }
 }
 
-async fn build_client_and_request(
+pub async fn build_client_and_request(
     cmd_name: &str,
-    socket_path: &'static PathBuf,
+    socket_path: &'static Path,
     input: String,
 ) -> Result<server::RunCmdResponse, ClientError> {
-    let connector = tower::service_fn(move |_: Uri| {
-        let path = socket_path.clone();
-        Box::pin(async move {
-            let stream = UnixStream::connect(path).await?;
-            Ok::<_, io::Error>(ClientConnection { stream })
-        })
-    });
-    let client = hyper::Client::builder().build(connector);
+    request(cmd_name, socket_path, input, None).await
+}
 
+/// Passes the bytes as they are, base64 encoded only if they aren't valid UTF-8.
+pub async fn build_client_and_request_bytes(
+    cmd_name: &str,
+    socket_path: &'static Path,
+    input: Vec<u8>,
+) -> Result<server::RunCmdResponse, ClientError> {
+    match String::from_utf8(input) {
+        Ok(input) => request(cmd_name, socket_path, input, None).await,
+        Err(e) => {
+            let input_base64 = server::encode_base64(e.as_bytes());
+            request(cmd_name, socket_path, String::new(), Some(input_base64)).await
+        }
+    }
+}
+
+async fn request(
+    cmd_name: &str,
+    socket_path: &'static Path,
+    input: String,
+    input_base64: Option<String>,
+) -> Result<server::RunCmdResponse, ClientError> {
     let req_body = server::RunCmdRequest {
         input,
+        input_base64,
         output_size: None,
+        env: None,
+        respawn_for_env: false,
+        wait_output_timeout_milli_sec: None,
     };
     let req_body_bytes = serde_json::to_vec(&req_body)?;
 
-    let request = Request::builder()
-        .method(Method::POST)
-        .header("Content-Type", "application/json")
-        .uri(format!("http://localhost/cmd/{}", cmd_name))
-        .body(Body::from(req_body_bytes))?;
+    let request = || {
+        Request::builder()
+            .method(Method::POST)
+            .header("Content-Type", "application/json")
+            .header(ACCEPT_ENCODING, "gzip, deflate")
+            .uri(format!("http://localhost/cmd/{}", cmd_name))
+            .body(Body::from(req_body_bytes.clone()))
+    };
 
-    let response = client.request(request).await?;
+    let response = match client(socket_path).request(request()?).await {
+        // the pooled connection was closed before any response, e.g. by a restart of the
+        // server, so the request is sent once more on a fresh connection
+        Err(e) if is_closed_before_response(&e) => {
+            renew_client(socket_path).request(request()?).await?
+        }
+        response => response?,
+    };
+    let status = response.status();
+    if status == StatusCode::NO_CONTENT {
+        return Ok(server::RunCmdResponse {
+            output: String::new(),
+            outputs: None,
+            encoding: None,
+            exit_code: None,
+            elapsed_milli_sec: None,
+            output_bytes: None,
+            arranged_input: None,
+            is_error: false,
+        });
+    }
 
+    // the server compresses the response only if `compress_responses` is set
+    let encoding = response.headers().get(CONTENT_ENCODING).cloned();
     let body = hyper::body::to_bytes(response.into_body()).await?;
+    let body = match encoding.as_ref().and_then(|value| value.to_str().ok()) {
+        Some(encoding @ ("gzip" | "deflate")) => {
+            decompress(&body, encoding, MAX_DECOMPRESSED_RESPONSE_SIZE)?.into()
+        }
+        _ => body,
+    };
+    if !status.is_success() {
+        // errors of the server layers, e.g. the request timeout, have no body
+        let error = serde_json::from_slice(&body).unwrap_or_else(|_| server::ErrorResponse {
+            error: status.to_string(),
+            kind: "http_error".to_string(),
+        });
+        return Err(ClientError::ServerError(error));
+    }
     let resp: server::RunCmdResponse = serde_json::from_slice(&body)?;
     Ok(resp)
 }
 
+fn is_closed_before_response(error: &hyper::Error) -> bool {
+    let reset = std::error::Error::source(error)
+        .and_then(|source| source.downcast_ref::<io::Error>())
+        .is_some_and(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe
+            )
+        });
+    reset || error.is_incomplete_message() || error.is_closed() || error.is_canceled()
+}
+
+/// The max size of a decompressed response, so that a small compressed body can't expand
+/// without bound.
+const MAX_DECOMPRESSED_RESPONSE_SIZE: u64 = 64 << 20;
+
+/// Decompresses the gzip or deflate body up to `max_size` bytes.
+fn decompress(body: &[u8], encoding: &str, max_size: u64) -> Result<Vec<u8>, ClientError> {
+    let decoder: Box<dyn Read + '_> = if encoding == "gzip" {
+        Box::new(GzDecoder::new(body))
+    } else {
+        Box::new(ZlibDecoder::new(body))
+    };
+    let mut decompressed = Vec::new();
+    decoder.take(max_size + 1).read_to_end(&mut decompressed)?;
+    if decompressed.len() as u64 > max_size {
+        return Err(ClientError::ResponseTooLarge(max_size));
+    }
+    Ok(decompressed)
+}
+
+#[derive(Clone)]
+struct UdsConnector {
+    socket_path: &'static Path,
+}
+
+impl tower::Service<Uri> for UdsConnector {
+    type Response = ClientConnection;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<ClientConnection>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _uri: Uri) -> Self::Future {
+        let socket_path = self.socket_path;
+        Box::pin(async move {
+            let stream = UnixStream::connect(socket_path).await?;
+            Ok(ClientConnection { stream })
+        })
+    }
+}
+
 struct ClientConnection {
     stream: UnixStream,
 }
@@ -127,7 +285,7 @@ impl Connection for ClientConnection {
 }
 
 #[mlua::lua_module]
-fn dairi(lua: &Lua) -> LuaResult<LuaTable> {
+fn dairi(lua: &Lua) -> LuaResult<LuaTable<'_>> {
     let exports = lua.create_table()?;
     //TODO(tacogips) create_async_function seems not compatible with tokio 1.17
     exports.set("run_cmd", lua.create_function(run_cmd)?)?;
@@ -140,6 +298,162 @@ mod test {
 
     use super::*;
     use crate::server;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use std::convert::Infallible;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::net::UnixListener;
+
+    /// Serves a fixed response and counts the accepted connections.
+    async fn serve_counting_connections(socket_path: &'static Path) -> Arc<AtomicUsize> {
+        let _ = std::fs::remove_file(socket_path);
+        let listener = UnixListener::bind(socket_path).unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(Http::new().serve_connection(
+                    stream,
+                    service_fn(|_| async {
+                        Ok::<_, Infallible>(Response::new(Body::from(r#"{"output":"2\n"}"#)))
+                    }),
+                ));
+            }
+        });
+        connections
+    }
+
+    #[tokio::test]
+    async fn test_reuse_connection() {
+        let socket_path: &'static Path = Box::leak(
+            std::env::temp_dir()
+                .join(format!("dairi-client-{}.sock", std::process::id()))
+                .into_boxed_path(),
+        );
+        let connections = serve_counting_connections(socket_path).await;
+
+        for _ in 0..20 {
+            let response = build_client_and_request("julia", socket_path, "1+1\n".to_string())
+                .await
+                .unwrap();
+            assert_eq!("2\n", response.output);
+        }
+        assert_eq!(1, connections.load(Ordering::SeqCst));
+
+        // a fresh client per call as before opens a connection each
+        for _ in 0..20 {
+            let client = hyper::Client::builder().build::<_, Body>(UdsConnector { socket_path });
+            let request = Request::post("http://localhost/cmd/julia")
+                .body(Body::from(r#"{"input":"1+1\n"}"#))
+                .unwrap();
+            hyper::body::to_bytes(client.request(request).await.unwrap().into_body())
+                .await
+                .unwrap();
+        }
+        assert_eq!(21, connections.load(Ordering::SeqCst));
+
+        std::fs::remove_file(socket_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retry_on_server_restart() {
+        let socket_path: &'static Path = Box::leak(
+            std::env::temp_dir()
+                .join(format!("dairi-client-restart-{}.sock", std::process::id()))
+                .into_boxed_path(),
+        );
+        let _ = std::fs::remove_file(socket_path);
+        let listener = UnixListener::bind(socket_path).unwrap();
+        // the connection is served on the task, so that aborting it closes the connection
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            Http::new()
+                .serve_connection(
+                    stream,
+                    service_fn(|_| async {
+                        Ok::<_, Infallible>(Response::new(Body::from(r#"{"output":"1\n"}"#)))
+                    }),
+                )
+                .await
+        });
+        let response = build_client_and_request("julia", socket_path, "1\n".to_string())
+            .await
+            .unwrap();
+        assert_eq!("1\n", response.output);
+
+        // the pooled connection to the stopped server is closed
+        server.abort();
+        std::fs::remove_file(socket_path).unwrap();
+        let connections = serve_counting_connections(socket_path).await;
+        let response = build_client_and_request("julia", socket_path, "1+1\n".to_string())
+            .await
+            .unwrap();
+        assert_eq!("2\n", response.output);
+        assert_eq!(1, connections.load(Ordering::SeqCst));
+
+        std::fs::remove_file(socket_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_decode_compressed_response() {
+        let socket_path: &'static Path = Box::leak(
+            std::env::temp_dir()
+                .join(format!("dairi-client-gzip-{}.sock", std::process::id()))
+                .into_boxed_path(),
+        );
+        let _ = std::fs::remove_file(socket_path);
+        let listener = UnixListener::bind(socket_path).unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            Http::new()
+                .serve_connection(
+                    stream,
+                    service_fn(|request: Request<Body>| async move {
+                        assert!(request.headers()[ACCEPT_ENCODING]
+                            .to_str()
+                            .unwrap()
+                            .contains("gzip"));
+                        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                        encoder.write_all(br#"{"output":"2\n"}"#).unwrap();
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .header(CONTENT_ENCODING, "gzip")
+                                .body(Body::from(encoder.finish().unwrap()))
+                                .unwrap(),
+                        )
+                    }),
+                )
+                .await
+                .unwrap();
+        });
+
+        let response = build_client_and_request("julia", socket_path, "1+1\n".to_string())
+            .await
+            .unwrap();
+        assert_eq!("2\n", response.output);
+
+        std::fs::remove_file(socket_path).unwrap();
+    }
+
+    #[test]
+    fn test_decompress_up_to_max_size() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0; 1000]).unwrap();
+        let body = encoder.finish().unwrap();
+
+        assert_eq!(vec![0; 1000], decompress(&body, "deflate", 1000).unwrap());
+        assert!(matches!(
+            decompress(&body, "deflate", 999),
+            Err(ClientError::ResponseTooLarge(999))
+        ));
+    }
+
     #[ignore]
     #[tokio::test]
     async fn test_req() {
@@ -149,7 +463,14 @@ mod test {
                 .unwrap();
         assert_eq!(
             server::RunCmdResponse {
-                output: "2\n".to_string()
+                output: "2\n".to_string(),
+                outputs: None,
+                encoding: None,
+                exit_code: None,
+                elapsed_milli_sec: None,
+                output_bytes: None,
+                arranged_input: None,
+                is_error: false,
             },
             result
         )
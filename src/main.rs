@@ -1,10 +1,21 @@
+mod cache;
 mod config;
+mod daemon;
+mod history;
 
+mod line_protocol;
+mod logging;
+mod metrics;
 mod process_manager;
+mod pty;
+mod request_log;
 mod server;
 
 use config::*;
+use process_manager::CmdName;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
+use tokio::io::AsyncReadExt;
 
 const HELP: &str = "\
 dairi
@@ -15,6 +26,15 @@ USAGE:
 
 FLAGS:
   -h, --help            Prints help information
+  -V, --version         Prints the version
+  --check               Validates the config and exits without serving
+  --init                Writes the default config to the config path and exits
+  --run <CMD_NAME>      Passes stdin to the cmd of the running server and prints the output
+  --config <PATH>       Loads the config from PATH instead of $DAIRI_CONFIG or
+                        $HOME/.config/dairi/config.toml
+  --daemonize           Runs the server in the background, detached from the terminal. The
+                        logs are discarded, use a supervisor to keep them
+  --pidfile <PATH>      Writes the pid of the server to PATH, removed when it stops
 ";
 
 #[derive(Debug, Error)]
@@ -22,12 +42,26 @@ pub enum DairiError {
     #[error("{0}")]
     ArgsError(#[from] pico_args::Error),
 }
-pub struct Args {}
+pub struct Args {
+    check: bool,
+    init: bool,
+    run: Option<CmdName>,
+    config: Option<PathBuf>,
+    daemonize: bool,
+    pidfile: Option<PathBuf>,
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build the runtime")
+}
 
+/// The runtime is built after `--daemonize`, since the threads don't survive the fork.
 #[cfg(unix)]
-#[tokio::main]
-async fn main() {
-    let _args = match parse_args() {
+fn main() {
+    let args = match parse_args() {
         Ok(args) => args,
         Err(e) => {
             eprintln!("args error : {}", e);
@@ -35,22 +69,85 @@ async fn main() {
         }
     };
 
-    tracing_subscriber::fmt::init();
+    if args.init {
+        match Config::init_default_config(args.config.as_deref()) {
+            Ok(config_path) => {
+                println!("created the default config at {}", config_path.display());
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if args.check {
+        std::process::exit(check_config(args.config.as_deref()));
+    }
+
+    if let Some(cmd_name) = args.run {
+        std::process::exit(runtime().block_on(run_once(&cmd_name)));
+    }
 
-    let config = match Config::load_from_default_path_or_create() {
+    let config = match Config::load_from_default_path(args.config.as_deref()) {
         Ok(config) => config,
         Err(e) => {
+            logging::init(None, None);
             tracing::error!("{}", e);
             std::process::exit(1);
         }
     };
 
-    if let Err(e) = process_manager::init_cmd_table(config.as_cmd_table()) {
+    logging::init(config.log_level.as_deref(), config.log_format);
+
+    let cmd_table = match config.as_cmd_table() {
+        Ok(cmd_table) => cmd_table,
+        Err(e) => {
+            tracing::error!("invalid config {}: {}", config.path.display(), e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = process_manager::init_cmd_table(cmd_table) {
         tracing::error!("failed to init cmd table:{:?}", e);
         std::process::exit(1);
     };
 
-    if let Err(e) = server::serve().await {
+    if let Some(pidfile) = args.pidfile.as_ref() {
+        if let Err(e) = daemon::check_pidfile(pidfile) {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if args.daemonize {
+        if let Err(e) = daemon::daemonize() {
+            tracing::error!("failed to daemonize: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(pidfile) = args.pidfile.as_ref() {
+        if let Err(e) = daemon::write_pidfile(pidfile) {
+            tracing::error!("failed to write the pidfile {}: {}", pidfile.display(), e);
+            std::process::exit(1);
+        }
+    }
+
+    runtime().block_on(serve(config));
+    if let Some(pidfile) = args.pidfile.as_ref() {
+        daemon::remove_pidfile(pidfile);
+    }
+}
+
+async fn serve(config: Config) {
+    server::apply_server_settings(&config);
+    if config.source_login_shell.unwrap_or(false) {
+        match process_manager::resolve_login_shell_env().await {
+            Ok(login_shell_env) => process_manager::set_login_shell_env(Some(login_shell_env)),
+            Err(e) => tracing::warn!("failed to resolve the env of the login shell: {}", e),
+        }
+    }
+
+    if let Err(e) = server::serve(config.as_server_config()).await {
         tracing::error!("dairi server error: {}", e);
     }
 }
@@ -61,8 +158,95 @@ fn parse_args() -> Result<Args, pico_args::Error> {
         print!("{}", HELP);
         std::process::exit(0);
     }
+    if pargs.contains(["-V", "--version"]) {
+        println!("dairi {}", server::VERSION);
+        std::process::exit(0);
+    }
+
+    Ok(Args {
+        check: pargs.contains("--check"),
+        init: pargs.contains("--init"),
+        run: pargs.opt_value_from_str("--run")?,
+        config: pargs.opt_value_from_str("--config")?,
+        daemonize: pargs.contains("--daemonize"),
+        pidfile: pargs.opt_value_from_str("--pidfile")?,
+    })
+}
+
+/// Passes stdin to the cmd of the running server and prints the output, returns the exit code.
+async fn run_once(cmd_name: &str) -> i32 {
+    let mut input = String::new();
+    if let Err(e) = tokio::io::stdin().read_to_string(&mut input).await {
+        eprintln!("failed to read stdin: {}", e);
+        return 1;
+    }
+
+    match dairi::build_client_and_request(cmd_name, server::default_socket_path(), input).await {
+        Ok(response) => {
+            print!("{}", response.output);
+            0
+        }
+        Err(dairi::ClientError::ServerError(error)) => {
+            eprintln!("{}", error.error);
+            1
+        }
+        Err(e) => {
+            eprintln!(
+                "failed to request to {}: {}",
+                server::default_socket_path().display(),
+                e
+            );
+            1
+        }
+    }
+}
+
+/// Prints the resolved cmds and the problems of the config, returns the exit code.
+fn check_config(config_path: Option<&Path>) -> i32 {
+    let config_path = match Config::explicit_config_path(config_path) {
+        Some(config_path) => config_path,
+        None => match Config::default_config_path() {
+            Ok(config_path) => config_path,
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            }
+        },
+    };
+    let config = match Config::load_from_path(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("failed to load config {}: {}", config_path.display(), e);
+            return 1;
+        }
+    };
+    println!("config: {}", config.path.display());
+
+    // the unresolved env vars are reported by validate below
+    let cmd_table = config.as_cmd_table().unwrap_or_default();
+    let mut names: Vec<&CmdName> = cmd_table.keys().collect();
+    names.sort();
+    for name in names {
+        let cmd = &cmd_table[name];
+        let program = cmd.cmd.split_whitespace().next().unwrap_or("");
+        match find_program(program) {
+            Some(path) => println!("\n[{}] {} ({})", name, cmd.cmd, path.display()),
+            None => println!("\n[{}] {} (program not found)", name, cmd.cmd),
+        }
+        println!("{:#?}", cmd);
+    }
 
-    Ok(Args {})
+    let problems = config.validate();
+    if problems.is_empty() {
+        println!("\nconfig is valid");
+        0
+    } else {
+        println!("\n{} problem(s) found:", problems.len());
+        for problem in problems {
+            println!("  - {}", problem);
+        }
+        1
+    }
 }
 
 #[cfg(not(unix))]
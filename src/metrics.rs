@@ -0,0 +1,187 @@
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the `run_cmd` latency histogram buckets in seconds.
+const LATENCY_BUCKETS_SEC: [f64; 12] = [
+    0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0,
+];
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    run_cmd_total: AtomicU64,
+    spawn_total: AtomicU64,
+    reuse_total: AtomicU64,
+    timeout_total: AtomicU64,
+    success_total: AtomicU64,
+    invocations: Mutex<BTreeMap<String, u64>>,
+    latency: LatencyHistogram,
+}
+
+#[derive(Default)]
+struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_SEC.len()],
+    count: AtomicU64,
+    sum_micro_sec: AtomicU64,
+}
+
+impl Metrics {
+    pub fn inc_invocation(&self, cmd_name: &str) {
+        self.run_cmd_total.fetch_add(1, Ordering::Relaxed);
+        let mut invocations = self.invocations.lock().unwrap();
+        *invocations.entry(cmd_name.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn inc_spawn(&self) {
+        self.spawn_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_reuse(&self) {
+        self.reuse_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_timeout(&self) {
+        self.timeout_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_success(&self) {
+        self.success_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_latency(&self, elapsed: Duration) {
+        let latency = &self.latency;
+        for (bucket, upper_bound) in latency.buckets.iter().zip(LATENCY_BUCKETS_SEC) {
+            if elapsed.as_secs_f64() <= upper_bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        latency.count.fetch_add(1, Ordering::Relaxed);
+        latency
+            .sum_micro_sec
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for (name, help, counter) in [
+            (
+                "dairi_run_cmd_total",
+                "Total number of run_cmd calls.",
+                &self.run_cmd_total,
+            ),
+            (
+                "dairi_process_spawn_total",
+                "Total number of spawned processes.",
+                &self.spawn_total,
+            ),
+            (
+                "dairi_process_reuse_total",
+                "Total number of run_cmd calls served by a running process.",
+                &self.reuse_total,
+            ),
+            (
+                "dairi_cmd_timeout_total",
+                "Total number of timed out run_cmd calls.",
+                &self.timeout_total,
+            ),
+            (
+                "dairi_cmd_success_total",
+                "Total number of succeeded run_cmd calls.",
+                &self.success_total,
+            ),
+        ] {
+            writeln!(text, "# HELP {} {}", name, help).unwrap();
+            writeln!(text, "# TYPE {} counter", name).unwrap();
+            writeln!(text, "{} {}", name, counter.load(Ordering::Relaxed)).unwrap();
+        }
+
+        text.push_str(
+            "# HELP dairi_cmd_invocations_total Total number of run_cmd calls per cmd.\n",
+        );
+        text.push_str("# TYPE dairi_cmd_invocations_total counter\n");
+        for (cmd_name, count) in self.invocations.lock().unwrap().iter() {
+            writeln!(
+                text,
+                "dairi_cmd_invocations_total{{cmd_name=\"{}\"}} {}",
+                escape_label_value(cmd_name),
+                count
+            )
+            .unwrap();
+        }
+
+        let latency = &self.latency;
+        let count = latency.count.load(Ordering::Relaxed);
+        text.push_str("# HELP dairi_run_cmd_duration_seconds Latency of run_cmd calls.\n");
+        text.push_str("# TYPE dairi_run_cmd_duration_seconds histogram\n");
+        for (bucket, upper_bound) in latency.buckets.iter().zip(LATENCY_BUCKETS_SEC) {
+            writeln!(
+                text,
+                "dairi_run_cmd_duration_seconds_bucket{{le=\"{}\"}} {}",
+                upper_bound,
+                bucket.load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+        writeln!(
+            text,
+            "dairi_run_cmd_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            count
+        )
+        .unwrap();
+        writeln!(
+            text,
+            "dairi_run_cmd_duration_seconds_sum {}",
+            latency.sum_micro_sec.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        )
+        .unwrap();
+        writeln!(text, "dairi_run_cmd_duration_seconds_count {}", count).unwrap();
+
+        text
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_render_metrics() {
+        let metrics = Metrics::default();
+        metrics.inc_invocation("julia");
+        metrics.inc_invocation("julia");
+        metrics.inc_invocation("say \"hi\"");
+        metrics.inc_spawn();
+        metrics.inc_reuse();
+        metrics.inc_success();
+        metrics.observe_latency(Duration::from_millis(200));
+
+        let text = metrics.render();
+        assert!(text.contains("dairi_run_cmd_total 3\n"));
+        assert!(text.contains("dairi_process_spawn_total 1\n"));
+        assert!(text.contains("dairi_cmd_timeout_total 0\n"));
+        assert!(text.contains("dairi_cmd_invocations_total{cmd_name=\"julia\"} 2\n"));
+        assert!(text.contains("dairi_cmd_invocations_total{cmd_name=\"say \\\"hi\\\"\"} 1\n"));
+        assert!(text.contains("dairi_run_cmd_duration_seconds_bucket{le=\"0.1\"} 0\n"));
+        assert!(text.contains("dairi_run_cmd_duration_seconds_bucket{le=\"0.25\"} 1\n"));
+        assert!(text.contains("dairi_run_cmd_duration_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(text.contains("dairi_run_cmd_duration_seconds_sum 0.2\n"));
+    }
+}
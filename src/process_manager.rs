@@ -1,26 +1,45 @@
 use thiserror::Error;
 
-use bytes::BytesMut;
+use crate::cache::{CacheConfig, CacheStats, ResponseCache};
+use crate::history;
+use crate::metrics::metrics;
+use crate::pty::{self, PtyMaster};
+use bytes::{Bytes, BytesMut};
+use futures::future::{BoxFuture, Shared};
+use futures::{FutureExt, Stream, StreamExt};
 use once_cell::sync::OnceCell;
+use regex::bytes::Regex as BytesRegex;
 use regex::Regex;
-use std::collections::HashMap;
-use std::process::Stdio;
-
-use sysinfo::{
-    Pid, PidExt, Process, ProcessExt, ProcessRefreshKind, ProcessStatus, RefreshKind, System,
-    SystemExt,
-};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, RwLock};
+
+use sysinfo::{Pid, PidExt, ProcessExt, ProcessRefreshKind, ProcessStatus, System, SystemExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::process::{Child, Command};
 use tokio::select;
-use tokio::sync::{Mutex, MutexGuard};
+use tokio::signal::unix::SignalKind;
+use tokio::sync::{mpsc, Mutex, OwnedMutexGuard};
 use tokio::time::{self, timeout, Duration, Instant};
+use tracing::Instrument;
 
 pub type CmdName = String;
 type Input = String;
-type Output = Vec<u8>;
-const DEFAULT_CMD_TIMEOUT_SEC: u64 = 30;
+pub type Output = Vec<u8>;
+pub const DEFAULT_CMD_TIMEOUT_SEC: u64 = 30;
 const DEFAULT_WAIT_OUTPUT_FINISH_SEC: u64 = 2;
+pub const DEFAULT_KILL_GRACE_MILLI_SEC: u64 = 1000;
+const STDIN_WRITE_TIMEOUT_SEC: u64 = 5;
+const DEFAULT_SPAWN_RETRY_BASE_MILLI_SEC: u64 = 100;
+const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+const DEFAULT_RECENT_OUTPUT_LINES: usize = 100;
+const EARLY_EXIT_WAIT_MILLI_SEC: u64 = 50;
+const LOGIN_SHELL_TIMEOUT_SEC: u64 = 10;
+pub const DEFAULT_SHELL: &str = "/bin/sh";
 
 #[derive(Debug, Error)]
 pub enum ProcessManagerError {
@@ -51,18 +70,392 @@ pub enum ProcessManagerError {
     #[error("empty input not allowed")]
     EmptyInputNotAllowed,
 
+    #[error("process is not running :{0}")]
+    ProcessNotRunning(CmdName),
+
+    #[error("cache is not enabled for cmd :{0}")]
+    CacheNotEnabled(CmdName),
+
+    #[error("timed out writing to stdin, the process seems stuck :{0}")]
+    StdinWriteTimeout(CmdName),
+
+    #[error("too many processes are running, the limit is {0}")]
+    TooManyProcesses(usize),
+
+    #[error("input is {size} bytes, larger than max_input_size {max_input_size}")]
+    InputTooLarge { size: usize, max_input_size: usize },
+
+    #[error("cmd is busy with another request :{0}")]
+    CmdBusy(CmdName),
+
+    #[error("reset_input is not configured for cmd :{0}")]
+    ResetNotConfigured(CmdName),
+
+    #[error("cmd is run more than {rate_limit_per_sec} times per second :{name}")]
+    RateLimited {
+        name: CmdName,
+        rate_limit_per_sec: u32,
+    },
+
+    #[error("program not found: {0}")]
+    ProgramNotFound(String),
+
+    #[error("failed to spawn {program} after {attempts} attempt(s): {source}")]
+    SpawnFailed {
+        program: String,
+        attempts: u32,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("process exited with {} right after the spawn :{name}\n{output}", exit_code.map(|code| code.to_string()).unwrap_or_else(|| "a signal".to_string()))]
+    ProcessExitedEarly {
+        name: CmdName,
+        exit_code: Option<i32>,
+        /// stderr of the process, or stdout if stderr isn't captured separately.
+        output: String,
+    },
+
+    #[error("process is unhealthy ({status:?}) and left as it is since auto_kill_unhealthy is false :{name}\n{error}")]
+    ProcessUnhealthy {
+        name: CmdName,
+        /// `None` if the process is gone.
+        status: Option<ProcessStatus>,
+        error: String,
+    },
+
+    #[error("superseded by a newer request within debounce_milli_sec :{0}")]
+    Superseded(CmdName),
+
+    #[error("input can't be encoded in {encoding:?}: {ch:?}")]
+    InputNotEncodable { encoding: InputEncoding, ch: char },
+
     #[error("{0}")]
     IOError(#[from] std::io::Error),
+
+    /// The error of the in-flight request that the request was coalesced with.
+    #[error("{0}")]
+    Coalesced(Arc<ProcessManagerError>),
+}
+
+impl ProcessManagerError {
+    /// A stable identifier of the error for clients.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProcessManagerError::CmdTableNotInitialize => "cmd_table_not_initialized",
+            ProcessManagerError::CmdNotFound(_) => "cmd_not_found",
+            ProcessManagerError::FailedToGetChildProcessStdin(_) => "failed_to_get_stdin",
+            ProcessManagerError::RegexError(_) => "invalid_regex",
+            ProcessManagerError::Timeout(_) => "timeout",
+            ProcessManagerError::FailedToAddProcessTable(_) => "failed_to_add_process_table",
+            ProcessManagerError::FailedToGetChildProcessStdout(_) => "failed_to_get_stdout",
+            ProcessManagerError::FailedToGetChildProcessStderr(_) => "failed_to_get_stderr",
+            ProcessManagerError::EmptyInputNotAllowed => "empty_input_not_allowed",
+            ProcessManagerError::ProcessNotRunning(_) => "process_not_running",
+            ProcessManagerError::CacheNotEnabled(_) => "cache_not_enabled",
+            ProcessManagerError::StdinWriteTimeout(_) => "stdin_write_timeout",
+            ProcessManagerError::TooManyProcesses(_) => "too_many_processes",
+            ProcessManagerError::InputTooLarge { .. } => "input_too_large",
+            ProcessManagerError::CmdBusy(_) => "cmd_busy",
+            ProcessManagerError::ResetNotConfigured(_) => "reset_not_configured",
+            ProcessManagerError::RateLimited { .. } => "rate_limited",
+            ProcessManagerError::ProgramNotFound(_) => "program_not_found",
+            ProcessManagerError::SpawnFailed { .. } => "spawn_failed",
+            ProcessManagerError::ProcessExitedEarly { .. } => "process_exited_early",
+            ProcessManagerError::ProcessUnhealthy { .. } => "process_unhealthy",
+            ProcessManagerError::Superseded(_) => "superseded",
+            ProcessManagerError::InputNotEncodable { .. } => "input_not_encodable",
+            ProcessManagerError::IOError(_) => "io_error",
+            ProcessManagerError::Coalesced(e) => e.kind(),
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, ProcessManagerError>;
 
-pub struct RunningProcess {
-    running_cmd: &'static Cmd,
-    child: Child,
+struct RunningProcess<C = Child> {
+    running_cmd: Arc<Cmd>,
+    /// Set once the process is added to a process table. Taken by `unregister_pid` before the
+    /// process is killed or waited for, so that `signal_cmd` never reaches the pid once it's
+    /// reaped and may be reused.
+    pid_entry: Option<PidEntry>,
+    child: C,
+    spawned_at: Instant,
+    last_used: Instant,
+    /// The env passed by the request that spawned the process.
+    env: Option<HashMap<String, String>>,
+    /// Taken from the child, or the pty master with `use_pty`. `None` once it's closed.
+    stdin: Option<ProcessStdin>,
+    /// The pty master to read the output from, set if the process is spawned with `use_pty`.
+    pty: Option<PtyMaster>,
+    /// Whether the first interaction after the spawn is done.
+    started: bool,
+    /// Set while an interaction is in progress. It stays set if the interaction is dropped
+    /// halfway, e.g. by a client disconnect, then the process is restarted on the next run
+    /// since its output would leak into the next one.
+    dirty: bool,
+    recent_output: RecentOutput,
 }
 
-#[derive(Debug)]
+/// The latest output lines of a process, the oldest ones are dropped beyond the capacity.
+#[derive(Debug, Default)]
+struct RecentOutput {
+    lines: VecDeque<String>,
+    capacity: usize,
+}
+
+impl RecentOutput {
+    fn new(capacity: usize) -> Self {
+        Self {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, output: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        for line in String::from_utf8_lossy(output).lines() {
+            if self.lines.len() == self.capacity {
+                self.lines.pop_front();
+            }
+            self.lines.push_back(line.to_string());
+        }
+    }
+
+    /// Returns the last `n` lines, oldest first.
+    fn last(&self, n: usize) -> Vec<String> {
+        let skip = self.lines.len().saturating_sub(n);
+        self.lines.iter().skip(skip).cloned().collect()
+    }
+}
+
+type ProcessStdin = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// The stdin and the outputs of a process, which are the pty master with `use_pty`.
+struct ProcessIo<'a> {
+    stdin: &'a mut Option<ProcessStdin>,
+    stdout: &'a mut (dyn AsyncRead + Unpin + Send),
+    stderr: Option<&'a mut (dyn AsyncRead + Unpin + Send)>,
+}
+
+/// The operations on a spawned process the process table needs, of which the real one is the
+/// tokio child and the tests have a mock.
+trait ProcessChild: Sized + Send {
+    fn id(&self) -> Option<u32>;
+
+    /// Returns the exit status if the process has exited, `None` while it's alive.
+    fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>>;
+
+    fn wait(&mut self) -> impl Future<Output = std::io::Result<ExitStatus>> + Send;
+
+    fn signal(&self, signal: Signal) -> Result<()>;
+
+    fn kill(&mut self) -> impl Future<Output = std::io::Result<()>> + Send;
+
+    /// The stdin to write the input to and the outputs to read.
+    fn io<'a>(
+        running_process: &'a mut RunningProcess<Self>,
+        name: &CmdName,
+    ) -> Result<ProcessIo<'a>>;
+
+    /// Registers the pid in `PID_TABLE` for `signal_cmd`, once the process is added to a
+    /// process table.
+    fn register_pid(&self, name: &CmdName) -> Option<PidEntry> {
+        self.id().map(|pid| PidEntry::register(name.clone(), pid))
+    }
+}
+
+/// Spawns the processes and keeps the table of the persistent ones. `run_cmd` runs on it so
+/// that how the processes are reused and respawned can be tested without the real processes.
+trait ProcessBackend: Sync {
+    type Child: ProcessChild;
+
+    fn spawn(
+        &self,
+        cmd: Arc<Cmd>,
+        env: Option<&HashMap<String, String>>,
+    ) -> impl Future<Output = Result<RunningProcess<Self::Child>>> + Send;
+
+    fn process_table(&self) -> &Mutex<ProcessTable<Self::Child>>;
+}
+
+/// Spawns the real processes with `tokio::process`.
+struct TokioProcessBackend;
+
+impl ProcessBackend for TokioProcessBackend {
+    type Child = Child;
+
+    async fn spawn(
+        &self,
+        cmd: Arc<Cmd>,
+        env: Option<&HashMap<String, String>>,
+    ) -> Result<RunningProcess> {
+        spawn_process(cmd, env).await
+    }
+
+    fn process_table(&self) -> &Mutex<ProcessTable> {
+        process_table()
+    }
+}
+
+impl ProcessChild for Child {
+    fn id(&self) -> Option<u32> {
+        Child::id(self)
+    }
+
+    fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        Child::try_wait(self)
+    }
+
+    async fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        Child::wait(self).await
+    }
+
+    fn signal(&self, signal: Signal) -> Result<()> {
+        send_signal(self, signal)
+    }
+
+    async fn kill(&mut self) -> std::io::Result<()> {
+        Child::kill(self).await
+    }
+
+    fn io<'a>(running_process: &'a mut RunningProcess, name: &CmdName) -> Result<ProcessIo<'a>> {
+        if running_process.stdin.is_none() {
+            return Err(ProcessManagerError::FailedToGetChildProcessStdin(
+                name.clone(),
+            ));
+        }
+        if let Some(pty) = running_process.pty.as_mut() {
+            return Ok(ProcessIo {
+                stdin: &mut running_process.stdin,
+                stdout: pty,
+                stderr: None,
+            });
+        }
+
+        let child = &mut running_process.child;
+        let stdout = child
+            .stdout
+            .as_mut()
+            .ok_or_else(|| ProcessManagerError::FailedToGetChildProcessStdout(name.clone()))?;
+        let cmd = &running_process.running_cmd;
+        // the redirected stderr is read from stdout
+        let stderr = if cmd.capture_stderr.unwrap_or(true)
+            && !cmd.redirect_stderr_to_stdout.unwrap_or(false)
+        {
+            let stderr = child
+                .stderr
+                .as_mut()
+                .ok_or_else(|| ProcessManagerError::FailedToGetChildProcessStderr(name.clone()))?;
+            Some(stderr as &mut (dyn AsyncRead + Unpin + Send))
+        } else {
+            None
+        };
+        Ok(ProcessIo {
+            stdin: &mut running_process.stdin,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+impl<C: ProcessChild> RunningProcess<C> {
+    fn unregister_pid(&mut self) {
+        self.pid_entry = None;
+    }
+
+    /// Returns the timeout of the next interaction, `spawn_timeout_sec` for the first one.
+    fn next_timeout_sec(&mut self) -> u64 {
+        let cmd = &self.running_cmd;
+        let timeout_sec = cmd.timeout_sec.unwrap_or(DEFAULT_CMD_TIMEOUT_SEC);
+        if std::mem::replace(&mut self.started, true) {
+            timeout_sec
+        } else {
+            cmd.spawn_timeout_sec.unwrap_or(timeout_sec)
+        }
+    }
+
+    fn exceeded_max_lifetime(&self) -> bool {
+        match self.running_cmd.max_lifetime_sec {
+            Some(max_lifetime_sec) => {
+                self.spawned_at.elapsed() >= Duration::from_secs(max_lifetime_sec)
+            }
+            None => false,
+        }
+    }
+
+    fn io(&mut self, name: &CmdName) -> Result<ProcessIo<'_>> {
+        C::io(self, name)
+    }
+}
+
+/// A stream of input bytes passed to stdin as they are received.
+pub type RawInput = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>;
+
+pub enum CmdInput {
+    /// Arranged with the cmd settings such as `join_input_newline_with` before passed.
+    Text(Input),
+    /// Passed to stdin as is, without being buffered whole.
+    Raw(RawInput),
+}
+
+impl From<Input> for CmdInput {
+    fn from(input: Input) -> Self {
+        CmdInput::Text(input)
+    }
+}
+
+/// Per-request overrides of the cmd settings.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RunOptions {
+    pub output_size: Option<usize>,
+    pub env: Option<RequestEnv>,
+    /// The idle window to wait for more output. It's still bounded by the cmd's `timeout_sec`.
+    pub wait_output_timeout_milli_sec: Option<u64>,
+    /// Receives the output chunk by chunk as it's read, too. Such a request isn't coalesced.
+    pub output_chunks: Option<OutputChunks>,
+}
+
+/// The output chunks as they're read from the process, before the output is arranged. A
+/// cached output is sent as a single chunk.
+#[derive(Debug, Clone)]
+pub struct OutputChunks(pub mpsc::UnboundedSender<Output>);
+
+impl PartialEq for OutputChunks {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.same_channel(&other.0)
+    }
+}
+
+/// Env vars passed with a request. They only take effect when the process is spawned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestEnv {
+    pub vars: HashMap<String, String>,
+    /// Respawns the running process if it was spawned with other env vars. Otherwise the env
+    /// vars are ignored with a warning.
+    pub respawn: bool,
+}
+
+/// What to do when a spawn would exceed `max_processes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcessLimitPolicy {
+    /// Stops the least recently used process to make room.
+    EvictLru,
+    /// Fails the run with `TooManyProcesses`.
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProcessLimit {
+    pub max_processes: usize,
+    pub policy: ProcessLimitPolicy,
+}
+
+/// Serialized for `GET /cmd/:cmd_name/config`, `null` for the defaults.
+#[derive(Debug, Default, PartialEq, Serialize)]
 pub struct Cmd {
     pub name: CmdName,
     pub cmd: String,
@@ -74,381 +467,4866 @@ pub struct Cmd {
     pub no_empty_input: bool,
     pub timeout_sec: Option<u64>,
     pub wait_output_timeout_milli_sec: Option<u64>,
+    pub timeout_signal: Option<Signal>,
+    pub cache: Option<CacheConfig>,
+    pub quit_cmd: Option<String>,
+    pub prompt_regex: Option<String>,
+    pub prompt_output: Option<PromptOutput>,
+    pub skip_leading_output_lines: Option<usize>,
+    pub use_shell: bool,
+    pub shell: Option<String>,
+    pub init_input: Option<String>,
+    pub capture_stderr: Option<bool>,
+    pub output_encoding: Option<OutputEncoding>,
+    pub spawn_retries: Option<u32>,
+    pub spawn_retry_base_milli_sec: Option<u64>,
+    /// The inputs are appended to this file. `~` is expanded in the config.
+    pub history_file: Option<PathBuf>,
+    pub output_filter_regex: Option<String>,
+    pub output_replace: Option<String>,
+    pub strip_ansi: Option<bool>,
+    pub use_pty: Option<bool>,
+    pub spawn_timeout_sec: Option<u64>,
+    pub persistent: Option<bool>,
+    pub on_concurrent_request: Option<ConcurrentRequestPolicy>,
+    pub strip_input_echo: Option<bool>,
+    pub reset_input: Option<String>,
+    pub read_buffer_size: Option<usize>,
+    pub args: Vec<String>,
+    pub env: HashMap<String, String>,
+    pub wait_for_exit: Option<bool>,
+    /// The process is usable once its output after the spawn matches this, e.g. the first
+    /// prompt of the REPL. The output until then is discarded.
+    pub ready_regex: Option<String>,
+    /// The process is restarted on the next run once it has been running this long.
+    pub max_lifetime_sec: Option<u64>,
+    pub recent_output_lines: Option<usize>,
+    pub rate_limit_per_sec: Option<u32>,
+    /// The order of the input arrangements, `DEFAULT_INPUT_PIPELINE` if not set.
+    pub input_pipeline: Option<Vec<InputStep>>,
+    /// Run instead when the program of this cmd can't be spawned.
+    pub fallback: Option<CmdName>,
+    pub redirect_stderr_to_stdout: Option<bool>,
+    pub max_output_lines: Option<usize>,
+    /// Ends each line of the text input, `"\n"` if not set.
+    pub input_newline: Option<String>,
+    pub input_encoding: Option<InputEncoding>,
+    pub debounce_milli_sec: Option<u64>,
+    /// Ends the output as soon as it is read, instead of waiting for the output to pause.
+    pub output_sentinel: Option<String>,
+    pub error_regex: Option<String>,
 }
 
-impl Cmd {
-    pub fn new(
-        name: CmdName,
-        cmd: String,
-        output_size: usize,
-        auto_trailing_newline: bool,
-        join_input_newline_with: Option<String>,
-        truncate_line_regex: Option<String>,
-        remove_empty_line: bool,
-        no_empty_input: bool,
-        timeout_sec: Option<u64>,
-        wait_output_timeout_milli_sec: Option<u64>,
-    ) -> Self {
-        Self {
-            name,
-            cmd,
-            output_size,
-            auto_trailing_newline,
-            join_input_newline_with,
-            truncate_line_regex,
-            remove_empty_line,
-            no_empty_input,
-            timeout_sec,
-            wait_output_timeout_milli_sec,
-        }
-    }
+/// A step of the input arrangement, applied when its setting of the cmd is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputStep {
+    /// `truncate_line_regex`
+    TruncateLine,
+    /// `remove_empty_line`
+    RemoveEmptyLine,
+    /// `join_input_newline_with`
+    JoinInputNewline,
+    /// `auto_trailing_newline`
+    AutoTrailingNewline,
 }
 
-pub type CmdTable = HashMap<CmdName, Cmd>;
-static CMD_TABLE: OnceCell<CmdTable> = OnceCell::new();
-
-type ProcessTable = HashMap<CmdName, RunningProcess>;
-static PROCESS_TABLE: OnceCell<Mutex<ProcessTable>> = OnceCell::new();
+pub const DEFAULT_INPUT_PIPELINE: &[InputStep] = &[
+    InputStep::TruncateLine,
+    InputStep::RemoveEmptyLine,
+    InputStep::JoinInputNewline,
+    InputStep::AutoTrailingNewline,
+];
 
-pub fn init_cmd_table(
-    cmd_table: HashMap<CmdName, Cmd>,
-) -> std::result::Result<(), HashMap<CmdName, Cmd>> {
-    CMD_TABLE.set(cmd_table)
+/// What to do with a request while another one is running the same cmd.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConcurrentRequestPolicy {
+    /// Waits for the running request, so that the inputs and outputs don't interleave.
+    #[default]
+    Queue,
+    /// Fails the request with `CmdBusy`.
+    Reject,
 }
 
-fn get_cmd_from_table(cmd_name: &CmdName) -> Result<&'static Cmd> {
-    let cmd_table = CMD_TABLE
-        .get()
-        .ok_or_else(|| ProcessManagerError::CmdTableNotInitialize)?;
-
-    cmd_table
-        .get(cmd_name)
-        .ok_or_else(|| ProcessManagerError::CmdNotFound(cmd_name.clone()))
+/// How the output is rearranged when it contains the REPL prompts matching `prompt_regex`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptOutput {
+    /// Concatenate the results between the prompts.
+    Joined,
+    /// Return each result between the prompts as an element of a list.
+    List,
 }
 
-fn process_table() -> &'static Mutex<ProcessTable> {
-    PROCESS_TABLE.get_or_init(|| Mutex::new(HashMap::new()))
+/// How the output bytes are encoded into the response.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputEncoding {
+    /// Replaces invalid UTF-8 sequences with U+FFFD.
+    #[default]
+    Utf8Lossy,
+    /// Fails the request if the output isn't valid UTF-8.
+    Utf8Strict,
+    /// Encodes the output as base64, for binary output.
+    Base64,
 }
 
-fn add_to_process_table(
-    process_table: &mut MutexGuard<ProcessTable>,
-    running_process: RunningProcess,
-) -> Result<()> {
-    let cmd_name = running_process.running_cmd.cmd.clone();
-    process_table.insert(cmd_name, running_process);
-    Ok(())
+/// How the text input is encoded into the bytes passed to stdin.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InputEncoding {
+    #[default]
+    Utf8,
+    /// ISO-8859-1. Fails the request if the input has a char beyond U+00FF.
+    Latin1,
 }
 
-fn is_health_process(p: &Process) -> bool {
-    match p.status() {
-        ProcessStatus::Run
-        | ProcessStatus::Idle
-        | ProcessStatus::Sleep
-        | ProcessStatus::Tracing => true,
-        _ => false,
+impl InputEncoding {
+    fn encode(self, input: &str) -> Result<Vec<u8>> {
+        match self {
+            InputEncoding::Utf8 => Ok(input.as_bytes().to_vec()),
+            InputEncoding::Latin1 => input
+                .chars()
+                .map(|ch| {
+                    u8::try_from(u32::from(ch))
+                        .map_err(|_| ProcessManagerError::InputNotEncodable { encoding: self, ch })
+                })
+                .collect(),
+        }
     }
 }
 
-pub async fn run_cmd(name: &CmdName, input: Input, output_size: Option<usize>) -> Result<Output> {
-    // TODO(tacogips) TOBE run concurrently. this mutex hold the lock until the process ends
-    let mut proceses = process_table().lock().await;
-    if let Some(running_process) = proceses.get_mut(name) {
-        if let Some(pid) = running_process.child.id() {
-            let target_pid = Pid::from_u32(pid);
-
-            let refresh_kind = RefreshKind::new();
-            let refresh_kind = refresh_kind.with_processes(ProcessRefreshKind::everything());
-            let sys = System::new_with_specifics(refresh_kind);
-            if let Some(os_process) = sys.process(target_pid) {
-                if is_health_process(os_process) {
-                    tracing::debug!("run existing process {}, {}", name, input);
-
-                    let timeout_sec = running_process
-                        .running_cmd
-                        .timeout_sec
-                        .unwrap_or(DEFAULT_CMD_TIMEOUT_SEC);
-                    return timeout(
-                        Duration::from_secs(timeout_sec),
-                        pass_input_to_process(
-                            name,
-                            &mut running_process.child,
-                            input,
-                            output_size.unwrap_or(running_process.running_cmd.output_size),
-                            running_process.running_cmd.auto_trailing_newline,
-                            running_process.running_cmd.join_input_newline_with.as_ref(),
-                            running_process.running_cmd.truncate_line_regex.as_ref(),
-                            running_process.running_cmd.remove_empty_line,
-                            running_process.running_cmd.no_empty_input,
-                            running_process.running_cmd.wait_output_timeout_milli_sec,
-                        ),
-                    )
-                    .await?;
-                } else {
-                    // kill zomibie process
-                    os_process.kill();
-                }
-            }
-        }
-    };
+#[derive(Debug, Clone, PartialEq)]
+pub struct CmdOutput {
+    pub output: Output,
+    pub segments: Option<Vec<Output>>,
+    pub encoding: OutputEncoding,
+    /// Set for the cmds with `wait_for_exit` unless the process was killed by a signal.
+    pub exit_code: Option<i32>,
+    /// The bytes read from the process before the output is arranged.
+    pub output_bytes: usize,
+    /// From the start of the run including the spawn, excluding the wait for the cmd lock.
+    pub elapsed: Duration,
+    /// Whether the output matches `error_regex` of the cmd.
+    pub is_error: bool,
+}
 
-    tracing::debug!("spawn process: {}", name);
-    let spawned_process = spawn_process(name).await?;
-    add_to_process_table(&mut proceses, spawned_process)?;
-    tracing::debug!("process spawend: {}", name);
-
-    match proceses.get_mut(name) {
-        Some(p) => {
-            let timeout_sec = p.running_cmd.timeout_sec.unwrap_or(DEFAULT_CMD_TIMEOUT_SEC);
-
-            let output = timeout(
-                Duration::from_secs(timeout_sec),
-                pass_input_to_process(
-                    name,
-                    &mut p.child,
-                    input,
-                    output_size.unwrap_or(p.running_cmd.output_size),
-                    p.running_cmd.auto_trailing_newline,
-                    p.running_cmd.join_input_newline_with.as_ref(),
-                    p.running_cmd.truncate_line_regex.as_ref(),
-                    p.running_cmd.remove_empty_line,
-                    p.running_cmd.no_empty_input,
-                    p.running_cmd.wait_output_timeout_milli_sec,
-                ),
-            )
-            .await??;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Signal {
+    Int,
+    Term,
+    Kill,
+    Hup,
+}
 
-            tracing::debug!("input passed the process: {}", name);
-            Ok(output)
+impl Signal {
+    /// Parses a signal name like `INT` or `SIGINT`, case insensitively.
+    pub fn from_name(name: &str) -> Option<Signal> {
+        let name = name.trim().to_ascii_uppercase();
+        match name.strip_prefix("SIG").unwrap_or(&name) {
+            "INT" => Some(Signal::Int),
+            "TERM" => Some(Signal::Term),
+            "KILL" => Some(Signal::Kill),
+            "HUP" => Some(Signal::Hup),
+            _ => None,
         }
-        None => Err(ProcessManagerError::FailedToAddProcessTable(name.clone())),
     }
-}
 
-fn arrange_input(
-    mut input: String,
-    auto_trailing_newline: bool,
-    join_new_lines_with: Option<&String>,
-    truncate_line_regex: Option<&String>,
-    remove_empty_line: bool,
-) -> Result<String> {
-    if let Some(truncate_line_regex) = truncate_line_regex {
-        let re = Regex::new(truncate_line_regex)?;
-        let mut ss = Vec::<String>::new();
-        for each in input.split("\n") {
-            ss.push(re.replace_all(each, "").to_string());
+    fn as_raw(&self) -> libc::c_int {
+        match self {
+            Signal::Int => libc::SIGINT,
+            Signal::Term => libc::SIGTERM,
+            Signal::Kill => libc::SIGKILL,
+            Signal::Hup => libc::SIGHUP,
         }
-        input = ss.join("\n")
     }
-    if remove_empty_line {
-        let empty_line_regex: Regex = Regex::new(r"^[\s\t]+$").unwrap();
-        let mut ss = Vec::<String>::new();
-        for each in input.split("\n") {
-            if !each.is_empty() && !empty_line_regex.is_match(each) {
-                ss.push(each.to_string());
-            }
+}
+
+pub type CmdTable = HashMap<CmdName, Cmd>;
+type SharedCmdTable = HashMap<CmdName, Arc<Cmd>>;
+static CMD_TABLE: OnceCell<RwLock<SharedCmdTable>> = OnceCell::new();
+
+type ProcessTable<C = Child> = HashMap<CmdName, RunningProcess<C>>;
+static PROCESS_TABLE: OnceCell<Mutex<ProcessTable>> = OnceCell::new();
+
+static PROCESS_LIMIT: RwLock<Option<ProcessLimit>> = RwLock::new(None);
+
+static MAX_INPUT_SIZE: RwLock<Option<usize>> = RwLock::new(None);
+static MAX_OUTPUT_SIZE_LIMIT: RwLock<Option<usize>> = RwLock::new(None);
+
+type CmdLockTable = HashMap<CmdName, Arc<Mutex<()>>>;
+static CMD_LOCK_TABLE: OnceCell<std::sync::Mutex<CmdLockTable>> = OnceCell::new();
+
+type CacheTable = HashMap<CmdName, ResponseCache>;
+static CACHE_TABLE: OnceCell<Mutex<CacheTable>> = OnceCell::new();
+
+static COALESCE_REQUESTS: RwLock<bool> = RwLock::new(false);
+static KILL_GRACE_MILLI_SEC: RwLock<Option<u64>> = RwLock::new(None);
+static AUTO_KILL_UNHEALTHY: RwLock<Option<bool>> = RwLock::new(None);
+/// The env of the login shell with `source_login_shell`, resolved once at the startup.
+static LOGIN_SHELL_ENV: RwLock<Option<HashMap<String, String>>> = RwLock::new(None);
+
+/// Refills `rate_per_sec` tokens per second up to `rate_per_sec`, a run takes one.
+#[derive(Debug)]
+struct TokenBucket {
+    rate_per_sec: u32,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate_per_sec: u32, now: Instant) -> Self {
+        Self {
+            rate_per_sec,
+            tokens: rate_per_sec as f64,
+            refilled_at: now,
         }
-        input = ss.join("\n")
     }
 
-    //TODO(tacogips) retain the trailing new line
-    if let Some(rep) = join_new_lines_with {
-        input = input.replace("\n", &rep);
-    }
-    if auto_trailing_newline {
-        input = format!("{}\n", input);
+    fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        let rate_per_sec = self.rate_per_sec as f64;
+        self.tokens = (self.tokens + elapsed * rate_per_sec).min(rate_per_sec);
+        self.refilled_at = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
     }
-
-    Ok(input)
 }
 
-async fn pass_input_to_process(
-    name: &CmdName,
-    child: &mut Child,
+type RateLimitTable = HashMap<CmdName, TokenBucket>;
+static RATE_LIMIT_TABLE: OnceCell<std::sync::Mutex<RateLimitTable>> = OnceCell::new();
+
+/// The requests that would pass the same input to the same cmd and get the same output.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct InFlightKey {
+    name: CmdName,
     input: Input,
-    max_output_size: usize,
-    auto_trailing_newline: bool,
-    join_input_new_lines_with: Option<&String>,
-    truncate_line_regex: Option<&String>,
-    remove_empty_line: bool,
-    no_empty_input: bool,
+    output_size: Option<usize>,
     wait_output_timeout_milli_sec: Option<u64>,
-) -> Result<Output> {
-    let input = arrange_input(
-        input,
-        auto_trailing_newline,
-        join_input_new_lines_with,
-        truncate_line_regex,
-        remove_empty_line,
-    )?;
-    if no_empty_input {
-        if input.is_empty() || Regex::new(r"^[\s\n]+$")?.is_match(&input) {
-            return Err(ProcessManagerError::EmptyInputNotAllowed);
+}
+
+type InFlightRun =
+    Shared<BoxFuture<'static, std::result::Result<CmdOutput, Arc<ProcessManagerError>>>>;
+type InFlightTable = HashMap<InFlightKey, InFlightRun>;
+static IN_FLIGHT_TABLE: OnceCell<std::sync::Mutex<InFlightTable>> = OnceCell::new();
+
+/// The pids of the processes in the process tables, to send a signal without the process table
+/// lock which the interaction in progress holds until it ends.
+type PidTable = HashMap<CmdName, u32>;
+static PID_TABLE: OnceCell<std::sync::Mutex<PidTable>> = OnceCell::new();
+
+fn pid_table() -> &'static std::sync::Mutex<PidTable> {
+    PID_TABLE.get_or_init(|| std::sync::Mutex::new(HashMap::new()))
+}
+
+/// Registers the pid of a process in `PID_TABLE` while the process is kept.
+struct PidEntry {
+    name: CmdName,
+    pid: u32,
+}
+
+impl PidEntry {
+    fn register(name: CmdName, pid: u32) -> Self {
+        pid_table().lock().unwrap().insert(name.clone(), pid);
+        Self { name, pid }
+    }
+}
+
+impl Drop for PidEntry {
+    fn drop(&mut self) {
+        let mut pid_table = pid_table().lock().unwrap();
+        // the entry may already be the one of the respawned process
+        if pid_table.get(&self.name) == Some(&self.pid) {
+            pid_table.remove(&self.name);
         }
     }
+}
 
-    tracing::info!("cmd:{}, input:  {}", name, input);
-    let child_stdin = child
-        .stdin
-        .as_mut()
-        .ok_or_else(|| ProcessManagerError::FailedToGetChildProcessStdin(name.clone()))?;
+/// The number of the requests to each cmd with `debounce_milli_sec`, to tell if a newer one came.
+type DebounceTable = HashMap<CmdName, u64>;
+static DEBOUNCE_TABLE: OnceCell<std::sync::Mutex<DebounceTable>> = OnceCell::new();
 
-    let child_stdout = child
-        .stdout
-        .as_mut()
-        .ok_or_else(|| ProcessManagerError::FailedToGetChildProcessStdout(name.clone()))?;
+fn into_shared_cmd_table(cmd_table: CmdTable) -> SharedCmdTable {
+    cmd_table
+        .into_iter()
+        .map(|(name, cmd)| (name, Arc::new(cmd)))
+        .collect()
+}
 
-    let child_stderr = child
-        .stderr
-        .as_mut()
-        .ok_or_else(|| ProcessManagerError::FailedToGetChildProcessStderr(name.clone()))?;
+pub fn init_cmd_table(cmd_table: CmdTable) -> std::result::Result<(), CmdTable> {
+    CMD_TABLE
+        .set(RwLock::new(into_shared_cmd_table(cmd_table)))
+        .map_err(|cmd_table| {
+            cmd_table
+                .into_inner()
+                .unwrap()
+                .into_iter()
+                .map(|(name, cmd)| (name, Arc::try_unwrap(cmd).unwrap()))
+                .collect()
+        })
+}
 
-    tracing::debug!(" passing to stdin of process :{} {}", name, input);
+fn get_cmd_from_table(cmd_name: &CmdName) -> Result<Arc<Cmd>> {
+    let cmd_table = CMD_TABLE
+        .get()
+        .ok_or(ProcessManagerError::CmdTableNotInitialize)?;
 
-    child_stdin.write_all(input.as_bytes()).await?;
-    tracing::debug!(" reading from stdout of process :{}", name);
+    cmd_table
+        .read()
+        .unwrap()
+        .get(cmd_name)
+        .cloned()
+        .ok_or_else(|| ProcessManagerError::CmdNotFound(cmd_name.clone()))
+}
 
-    let mut std_out_read_buf = BytesMut::with_capacity(max_output_size);
-    let mut std_out_reader = BufReader::with_capacity(max_output_size, child_stdout);
+/// Takes a token of the cmd with `rate_limit_per_sec`. The bucket is renewed when the rate is
+/// changed by a reload.
+fn check_rate_limit(cmd: &Cmd) -> Result<()> {
+    let rate_limit_per_sec = match cmd.rate_limit_per_sec {
+        Some(rate_limit_per_sec) => rate_limit_per_sec,
+        None => return Ok(()),
+    };
+    let now = Instant::now();
+    let mut rate_limit_table = RATE_LIMIT_TABLE
+        .get_or_init(|| std::sync::Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap();
+    let bucket = rate_limit_table
+        .entry(cmd.name.clone())
+        .or_insert_with(|| TokenBucket::new(rate_limit_per_sec, now));
+    if bucket.rate_per_sec != rate_limit_per_sec {
+        *bucket = TokenBucket::new(rate_limit_per_sec, now);
+    }
 
-    let mut std_err_read_buf = BytesMut::with_capacity(max_output_size);
-    let mut std_err_reader = BufReader::with_capacity(max_output_size, child_stderr);
+    if bucket.try_take(now) {
+        Ok(())
+    } else {
+        tracing::warn!("rate limited: {}", cmd.name);
+        Err(ProcessManagerError::RateLimited {
+            name: cmd.name.clone(),
+            rate_limit_per_sec,
+        })
+    }
+}
 
-    let latest_read_at: Mutex<Option<Instant>> = Mutex::new(None);
-    let mut result = Output::new();
+/// Waits `debounce_milli_sec` of the cmd, and fails with `Superseded` if another request to the
+/// cmd came meanwhile, so only the latest of the rapid requests runs.
+async fn debounce(cmd: &Cmd) -> Result<()> {
+    let debounce_milli_sec = match cmd.debounce_milli_sec {
+        Some(debounce_milli_sec) => debounce_milli_sec,
+        None => return Ok(()),
+    };
+    let debounce_table = DEBOUNCE_TABLE.get_or_init(|| std::sync::Mutex::new(HashMap::new()));
+    let generation = {
+        let mut debounce_table = debounce_table.lock().unwrap();
+        let generation = debounce_table.entry(cmd.name.clone()).or_default();
+        *generation += 1;
+        *generation
+    };
 
-    let wait_duration_sequential_output = Duration::from_millis(
-        wait_output_timeout_milli_sec.unwrap_or(DEFAULT_WAIT_OUTPUT_FINISH_SEC),
-    );
-    let mut check_output_finished_interval = time::interval(Duration::from_millis(100));
+    time::sleep(Duration::from_millis(debounce_milli_sec)).await;
+    if debounce_table.lock().unwrap()[&cmd.name] != generation {
+        tracing::debug!("superseded by a newer request: {}", cmd.name);
+        return Err(ProcessManagerError::Superseded(cmd.name.clone()));
+    }
+    Ok(())
+}
 
-    // wait output ends during `wait_duration_sequential_output` seconds elapsed
-    loop {
-        select! {
-            std_out = std_out_reader.read_buf(&mut std_out_read_buf) => {
-                match std_out {
-                    Err(e) => {
-                        tracing::debug!(" read stdout error :{}", e);
-                        return Err(ProcessManagerError::IOError(e))
-                    }
-                    Ok(read_size) => {
-                        tracing::debug!(
-                            " finished to read from stdout of process :{:?}",
-                            String::from_utf8(std_out_read_buf[..read_size].to_vec())
-                        );
+/// Returns the cmd as loaded from the config.
+pub fn cmd_config(cmd_name: &CmdName) -> Result<Arc<Cmd>> {
+    get_cmd_from_table(cmd_name)
+}
 
-                        result.append(&mut std_out_read_buf[..read_size].to_vec());
-                        std_out_read_buf.clear();
+fn process_table() -> &'static Mutex<ProcessTable> {
+    PROCESS_TABLE.get_or_init(|| Mutex::new(HashMap::new()))
+}
 
-                        let mut read_at =  latest_read_at.lock().await;
-                        read_at.replace(Instant::now());
-                        drop(read_at);
-                        continue
-                    }
-                }
-            }
+/// Takes the lock of the cmd held while a request is running it.
+async fn lock_cmd(name: &CmdName, policy: ConcurrentRequestPolicy) -> Result<OwnedMutexGuard<()>> {
+    let cmd_lock = CMD_LOCK_TABLE
+        .get_or_init(|| std::sync::Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap()
+        .entry(name.clone())
+        .or_default()
+        .clone();
 
-            std_err = std_err_reader.read_buf(&mut std_err_read_buf) => {
-                match std_err {
-                    Err(e) => {
-                        tracing::debug!(" read stdout error :{}", e);
-                        return Err(ProcessManagerError::IOError(e))
-                    }
-                    Ok(read_size) => {
-                        tracing::debug!(
-                            " finished to read from stdout of process :{:?}",
-                            String::from_utf8(std_err_read_buf[..read_size].to_vec())
-                        );
-                        result.append(&mut std_err_read_buf[..read_size].to_vec());
-                        std_err_read_buf.clear();
+    match policy {
+        ConcurrentRequestPolicy::Queue => Ok(cmd_lock.lock_owned().await),
+        ConcurrentRequestPolicy::Reject => cmd_lock
+            .try_lock_owned()
+            .map_err(|_| ProcessManagerError::CmdBusy(name.clone())),
+    }
+}
 
-                        let mut read_at =  latest_read_at.lock().await;
-                        read_at.replace(Instant::now());
-                        drop(read_at);
-                        continue
-                    }
-                }
-            }
+fn cache_table() -> &'static Mutex<CacheTable> {
+    CACHE_TABLE.get_or_init(|| Mutex::new(HashMap::new()))
+}
 
-            check_at = check_output_finished_interval.tick() => {
-                let read_at =  latest_read_at.lock().await;
-                if let Some(latest_read_at) = *read_at {
-                    let duration_since_checked = check_at.duration_since(latest_read_at);
-                    if duration_since_checked  >= wait_duration_sequential_output {
-                        break
-                    }
-                }
+pub fn set_process_limit(process_limit: Option<ProcessLimit>) {
+    *PROCESS_LIMIT.write().unwrap() = process_limit;
+}
 
-            }
-        }
-    }
-    Ok(result)
+fn process_limit() -> Option<ProcessLimit> {
+    *PROCESS_LIMIT.read().unwrap()
 }
 
-async fn spawn_process(name: &CmdName) -> Result<RunningProcess> {
-    let cmd: &'static Cmd = get_cmd_from_table(name)?;
-    let child = Command::new(cmd.cmd.clone())
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
+pub fn set_max_input_size(max_input_size: Option<usize>) {
+    *MAX_INPUT_SIZE.write().unwrap() = max_input_size;
+}
 
-    let running_process = RunningProcess {
-        running_cmd: cmd,
-        child,
-    };
+/// The max bytes of an input, unlimited if `None`.
+pub fn max_input_size() -> Option<usize> {
+    *MAX_INPUT_SIZE.read().unwrap()
+}
 
-    Ok(running_process)
+pub fn set_max_output_size_limit(max_output_size_limit: Option<usize>) {
+    *MAX_OUTPUT_SIZE_LIMIT.write().unwrap() = max_output_size_limit;
 }
 
-#[cfg(test)]
-mod test {
+fn max_output_size_limit() -> Option<usize> {
+    *MAX_OUTPUT_SIZE_LIMIT.read().unwrap()
+}
 
-    use super::*;
+pub fn set_coalesce_requests(coalesce_requests: bool) {
+    *COALESCE_REQUESTS.write().unwrap() = coalesce_requests;
+}
 
-    #[test]
-    fn test_arrange_input() {
-        {
-            let input = arrange_input(
-                "aaa\nbbb".to_string(),
-                false,
-                Some(&";".to_string()),
-                None,
-                false,
+fn coalesce_requests() -> bool {
+    *COALESCE_REQUESTS.read().unwrap()
+}
+
+pub fn set_kill_grace_milli_sec(kill_grace_milli_sec: Option<u64>) {
+    *KILL_GRACE_MILLI_SEC.write().unwrap() = kill_grace_milli_sec;
+}
+
+pub fn set_login_shell_env(login_shell_env: Option<HashMap<String, String>>) {
+    *LOGIN_SHELL_ENV.write().unwrap() = login_shell_env;
+}
+
+/// Runs `$SHELL -l -c env` to get the env that the rc files of the user set up, such as the
+/// `PATH` entries missing when the server is started by an editor.
+pub async fn resolve_login_shell_env() -> Result<HashMap<String, String>> {
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| DEFAULT_SHELL.to_string());
+    let output = timeout(
+        Duration::from_secs(LOGIN_SHELL_TIMEOUT_SEC),
+        Command::new(&shell)
+            .args(["-l", "-c", "env"])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await??;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "{} -l -c env exited with {}",
+            shell, output.status
+        ))
+        .into());
+    }
+    Ok(parse_env(&output.stdout))
+}
+
+/// Parses the `KEY=value` lines printed by `env`. The lines without `=`, such as the rest of
+/// a multi-line value, are skipped.
+fn parse_env(output: &[u8]) -> HashMap<String, String> {
+    String::from_utf8_lossy(output)
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .filter(|(key, _)| !key.is_empty())
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+pub fn set_auto_kill_unhealthy(auto_kill_unhealthy: Option<bool>) {
+    *AUTO_KILL_UNHEALTHY.write().unwrap() = auto_kill_unhealthy;
+}
+
+fn auto_kill_unhealthy() -> bool {
+    AUTO_KILL_UNHEALTHY.read().unwrap().unwrap_or(true)
+}
+
+/// How long a stopped process is given to exit before it is killed.
+fn kill_grace() -> Duration {
+    Duration::from_millis(
+        KILL_GRACE_MILLI_SEC
+            .read()
+            .unwrap()
+            .unwrap_or(DEFAULT_KILL_GRACE_MILLI_SEC),
+    )
+}
+
+/// The `output_size` of the request or the cmd, clamped to `limit`.
+fn effective_output_size(
+    name: &CmdName,
+    options: &RunOptions,
+    cmd: &Cmd,
+    limit: Option<usize>,
+) -> usize {
+    let output_size = options.output_size.unwrap_or(cmd.output_size);
+    match limit {
+        Some(limit) if output_size > limit => {
+            tracing::info!(
+                cmd_name = %name,
+                "clamping output_size {} to max_output_size_limit {}",
+                output_size,
+                limit
             );
-            assert_eq!("aaa;bbb".to_string(), input.unwrap());
+            limit
         }
+        _ => output_size,
+    }
+}
 
-        {
-            let input = arrange_input(
-                "
-                # sss
-                aaa # ddd
- \n\nbbb"
-                    .to_string(),
-                true,
-                Some(&";".to_string()),
-                Some(&"#.*".to_string()),
-                false,
-            );
-            assert_eq!(
-                ";                ;                aaa ; ;;bbb\n".to_string(),
-                input.unwrap()
-            );
+/// Checks the text input before it's passed. The size of a raw input is only known as it
+/// streams, so it's checked by `pass_input_to_process` with `check_input_len`.
+fn check_input_size(input: &CmdInput, max_input_size: Option<usize>) -> Result<()> {
+    match input {
+        CmdInput::Text(input) => check_input_len(input.len(), max_input_size),
+        CmdInput::Raw(_) => Ok(()),
+    }
+}
+
+fn check_input_len(size: usize, max_input_size: Option<usize>) -> Result<()> {
+    match max_input_size {
+        Some(max_input_size) if size > max_input_size => Err(ProcessManagerError::InputTooLarge {
+            size,
+            max_input_size,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Makes sure one more process can be spawned without exceeding the limit.
+async fn make_room_for_spawn<C: ProcessChild>(
+    proceses: &mut ProcessTable<C>,
+    process_limit: Option<ProcessLimit>,
+) -> Result<()> {
+    let ProcessLimit {
+        max_processes,
+        policy,
+    } = match process_limit {
+        Some(process_limit) => process_limit,
+        None => return Ok(()),
+    };
+
+    while proceses.len() >= max_processes {
+        if policy == ProcessLimitPolicy::Reject {
+            return Err(ProcessManagerError::TooManyProcesses(max_processes));
         }
 
+        let lru_name = match proceses
+            .iter()
+            .min_by_key(|(_, running_process)| running_process.last_used)
         {
-            let input = arrange_input(
-                "
-                # sss
-                aaa # ddd
- \n\nbbb"
-                    .to_string(),
-                true,
-                Some(&";".to_string()),
-                Some(&"#.*".to_string()),
-                true,
+            Some((name, _)) => name.clone(),
+            None => break,
+        };
+        if let Some(mut running_process) = proceses.remove(&lru_name) {
+            tracing::info!("evicting the least recently used process: {}", lru_name);
+            stop_process(&lru_name, &mut running_process).await;
+        }
+    }
+    Ok(())
+}
+
+fn add_to_process_table<C: ProcessChild>(
+    process_table: &mut ProcessTable<C>,
+    mut running_process: RunningProcess<C>,
+) -> Result<()> {
+    let cmd_name = running_process.running_cmd.name.clone();
+    running_process.pid_entry = running_process.child.register_pid(&cmd_name);
+    tracing::debug!(
+        cmd_name = %cmd_name,
+        pid = running_process.child.id(),
+        "added to process table"
+    );
+    process_table.insert(cmd_name, running_process);
+    Ok(())
+}
+
+/// The cpu usage is the usage between two refreshes of the process with this interval.
+const CPU_USAGE_SAMPLING_MILLI_SEC: u64 = 200;
+
+#[derive(Debug, Serialize)]
+pub struct ProcessStats {
+    pub cmd_name: CmdName,
+    pub pid: u32,
+    pub status: String,
+    /// Resident set size.
+    pub memory_bytes: u64,
+    /// Percentage of one cpu sampled over 200ms, can exceed 100 on multiple cores.
+    pub cpu_usage: f32,
+    pub idle_sec: u64,
+}
+
+/// Returns the stats of the running processes. This takes the sampling interval of the cpu
+/// usage, without holding the process table lock meanwhile.
+pub async fn process_stats() -> Vec<ProcessStats> {
+    let running_processes: Vec<(CmdName, u32, Duration)> = process_table()
+        .lock()
+        .await
+        .iter()
+        .filter_map(|(name, running_process)| {
+            let pid = running_process.child.id()?;
+            Some((name.clone(), pid, running_process.last_used.elapsed()))
+        })
+        .collect();
+    sample_process_stats(running_processes).await
+}
+
+async fn sample_process_stats(
+    running_processes: Vec<(CmdName, u32, Duration)>,
+) -> Vec<ProcessStats> {
+    let mut sys = System::new();
+    let refresh = |sys: &mut System| {
+        for (_, pid, _) in running_processes.iter() {
+            sys.refresh_process_specifics(Pid::from_u32(*pid), ProcessRefreshKind::everything());
+        }
+    };
+    refresh(&mut sys);
+    time::sleep(Duration::from_millis(CPU_USAGE_SAMPLING_MILLI_SEC)).await;
+    refresh(&mut sys);
+
+    running_processes
+        .into_iter()
+        .filter_map(|(cmd_name, pid, idle)| {
+            let os_process = sys.process(Pid::from_u32(pid))?;
+            Some(ProcessStats {
+                cmd_name,
+                pid,
+                status: format!("{:?}", os_process.status()),
+                // sysinfo reports it in KB
+                memory_bytes: os_process.memory() * 1024,
+                cpu_usage: os_process.cpu_usage(),
+                idle_sec: idle.as_secs(),
+            })
+        })
+        .collect()
+}
+
+/// Runs the cmd. With `coalesce_requests`, a request identical to the one in flight waits for
+/// it and shares its result instead of passing the input again.
+pub async fn run_cmd(name: &CmdName, input: CmdInput, options: RunOptions) -> Result<CmdOutput> {
+    run_cmd_on(&TokioProcessBackend, name, input, options).await
+}
+
+async fn run_cmd_on<B: ProcessBackend>(
+    backend: &'static B,
+    name: &CmdName,
+    input: CmdInput,
+    options: RunOptions,
+) -> Result<CmdOutput> {
+    let key = match (&input, &options.env) {
+        (CmdInput::Text(input), None) if coalesce_requests() && options.output_chunks.is_none() => {
+            InFlightKey {
+                name: name.clone(),
+                input: input.clone(),
+                output_size: options.output_size,
+                wait_output_timeout_milli_sec: options.wait_output_timeout_milli_sec,
+            }
+        }
+        _ => return run_cmd_uncoalesced(backend, name, input, options).await,
+    };
+
+    let in_flight_run = {
+        let mut in_flight_table = IN_FLIGHT_TABLE
+            .get_or_init(|| std::sync::Mutex::new(HashMap::new()))
+            .lock()
+            .unwrap();
+        match in_flight_table.get(&key) {
+            Some(in_flight_run) => {
+                tracing::debug!("coalescing with the request in flight: {}", name);
+                in_flight_run.clone()
+            }
+            None => {
+                let name = name.clone();
+                let run_key = key.clone();
+                // runs on a task so that it completes even if all the requests waiting for it
+                // are dropped, instead of being parked with the locks held
+                let run = tokio::spawn(async move {
+                    let result = run_cmd_uncoalesced(backend, &name, input, options).await;
+                    if let Some(in_flight_table) = IN_FLIGHT_TABLE.get() {
+                        in_flight_table.lock().unwrap().remove(&run_key);
+                    }
+                    result
+                });
+                let in_flight_run = async move {
+                    match run.await {
+                        Ok(result) => result.map_err(Arc::new),
+                        Err(e) => Err(Arc::new(std::io::Error::other(e).into())),
+                    }
+                }
+                .boxed()
+                .shared();
+                in_flight_table.insert(key, in_flight_run.clone());
+                in_flight_run
+            }
+        }
+    };
+
+    // the last one to get the error takes it back as is
+    in_flight_run
+        .await
+        .map_err(|e| Arc::try_unwrap(e).unwrap_or_else(ProcessManagerError::Coalesced))
+}
+
+#[tracing::instrument(skip_all, fields(cmd_name = %name))]
+async fn run_cmd_uncoalesced<B: ProcessBackend>(
+    backend: &B,
+    name: &CmdName,
+    input: CmdInput,
+    options: RunOptions,
+) -> Result<CmdOutput> {
+    let mut name = name.clone();
+    let mut input = input;
+    let mut tried = vec![name.clone()];
+    loop {
+        let cmd = get_cmd_from_table(&name)?;
+        // a raw input is consumed by the first try
+        let fallback = match (&input, cmd.fallback.as_ref()) {
+            (CmdInput::Text(input), Some(fallback)) => Some((fallback.clone(), input.clone())),
+            _ => None,
+        };
+
+        match run_cmd_once(backend, &name, &cmd, input, &options).await {
+            Err(
+                e @ (ProcessManagerError::ProgramNotFound(_)
+                | ProcessManagerError::SpawnFailed { .. }),
+            ) => match fallback {
+                Some((fallback, _)) if tried.contains(&fallback) => {
+                    tracing::warn!("fallback cycle at {}: {}", fallback, name);
+                    return Err(e);
+                }
+                Some((fallback, fallback_input)) => {
+                    tracing::warn!("falling back to {}: {}", fallback, e);
+                    tried.push(fallback.clone());
+                    name = fallback;
+                    input = fallback_input.into();
+                }
+                None => return Err(e),
+            },
+            result => return result,
+        }
+    }
+}
+
+async fn run_cmd_once<B: ProcessBackend>(
+    backend: &B,
+    name: &CmdName,
+    cmd: &Arc<Cmd>,
+    input: CmdInput,
+    options: &RunOptions,
+) -> Result<CmdOutput> {
+    debounce(cmd).await?;
+    check_rate_limit(cmd)?;
+    check_input_size(&input, max_input_size())?;
+    let _cmd_lock = lock_cmd(name, cmd.on_concurrent_request.unwrap_or_default()).await?;
+    run_cmd_locked(backend, name, cmd, input, options).await
+}
+
+async fn run_cmd_locked<B: ProcessBackend>(
+    backend: &B,
+    name: &CmdName,
+    cmd: &Arc<Cmd>,
+    input: CmdInput,
+    options: &RunOptions,
+) -> Result<CmdOutput> {
+    metrics().inc_invocation(name);
+
+    let started_at = Instant::now();
+    let result = run_cmd_with_cache(backend, name, cmd, input, options).await;
+    let elapsed = started_at.elapsed();
+    metrics().observe_latency(elapsed);
+
+    let (output, exit_code) = result?;
+    metrics().inc_success();
+    let output_bytes = output.len();
+    let mut cmd_output = arrange_output(output, cmd)?;
+    cmd_output.exit_code = exit_code;
+    cmd_output.output_bytes = output_bytes;
+    cmd_output.elapsed = elapsed;
+    Ok(cmd_output)
+}
+
+/// Passes the inputs one by one as `run_cmd` does, holding the lock of the cmd for the whole
+/// batch so that they go to the same process. The batch is debounced as one request, and each
+/// input takes a token of `rate_limit_per_sec`. A failed input doesn't stop the rest.
+#[tracing::instrument(skip_all, fields(cmd_name = %name, inputs = inputs.len()))]
+pub async fn run_batch(
+    name: &CmdName,
+    inputs: Vec<Input>,
+    options: RunOptions,
+) -> Result<Vec<Result<CmdOutput>>> {
+    let cmd = get_cmd_from_table(name)?;
+    run_batch_on(&TokioProcessBackend, &cmd, inputs, &options).await
+}
+
+async fn run_batch_on<B: ProcessBackend>(
+    backend: &B,
+    cmd: &Arc<Cmd>,
+    inputs: Vec<Input>,
+    options: &RunOptions,
+) -> Result<Vec<Result<CmdOutput>>> {
+    debounce(cmd).await?;
+    let _cmd_lock = lock_cmd(&cmd.name, cmd.on_concurrent_request.unwrap_or_default()).await?;
+
+    let mut results = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let input = CmdInput::from(input);
+        let result =
+            match check_rate_limit(cmd).and_then(|_| check_input_size(&input, max_input_size())) {
+                Ok(()) => run_cmd_locked(backend, &cmd.name, cmd, input, options).await,
+                Err(e) => Err(e),
+            };
+        results.push(result);
+    }
+    Ok(results)
+}
+
+/// Holds the lock of a cmd for the inputs in a row, e.g. of a WebSocket connection, so that the
+/// other requests to the cmd wait or are rejected by `on_concurrent_request` until it's dropped.
+pub struct CmdSession {
+    cmd: Arc<Cmd>,
+    /// The process which the last input was passed to.
+    pid: Option<u32>,
+    _cmd_lock: OwnedMutexGuard<()>,
+}
+
+pub async fn open_session(name: &CmdName) -> Result<CmdSession> {
+    let cmd = get_cmd_from_table(name)?;
+    let cmd_lock = lock_cmd(name, cmd.on_concurrent_request.unwrap_or_default()).await?;
+    Ok(CmdSession {
+        cmd,
+        pid: None,
+        _cmd_lock: cmd_lock,
+    })
+}
+
+impl CmdSession {
+    /// Runs the input as `run_cmd` does, with the lock of the session.
+    pub async fn run(&mut self, input: CmdInput, options: RunOptions) -> Result<CmdOutput> {
+        check_rate_limit(&self.cmd)?;
+        check_input_size(&input, max_input_size())?;
+        let result = run_cmd_locked(
+            &TokioProcessBackend,
+            &self.cmd.name,
+            &self.cmd,
+            input,
+            &options,
+        )
+        .await;
+        self.pid = pid_table().lock().unwrap().get(&self.cmd.name).copied();
+        result
+    }
+
+    /// Resolves once the process of the last input has exited or been removed since, e.g. by
+    /// `exit` in the REPL. Never resolves for a one-shot cmd. The child is checked with
+    /// `try_wait` on each `SIGCHLD`, since waiting on it would hold the process table lock that
+    /// the runs of the other cmds need.
+    pub async fn process_exit(&self) {
+        let pid = match self.pid {
+            Some(pid) => pid,
+            None => return futures::future::pending().await,
+        };
+        let mut child_signals = match tokio::signal::unix::signal(SignalKind::child()) {
+            Ok(child_signals) => child_signals,
+            Err(e) => {
+                tracing::warn!("failed to listen to SIGCHLD: {}", e);
+                return futures::future::pending().await;
+            }
+        };
+        while !process_exited(&TokioProcessBackend, &self.cmd.name, pid).await {
+            child_signals.recv().await;
+        }
+    }
+}
+
+/// Whether the process of the pid has exited or isn't in the table anymore.
+async fn process_exited<B: ProcessBackend>(backend: &B, name: &CmdName, pid: u32) -> bool {
+    let mut processes = backend.process_table().lock().await;
+    match processes.get_mut(name) {
+        Some(running_process) if running_process.child.id() == Some(pid) => {
+            !matches!(running_process.child.try_wait(), Ok(None))
+        }
+        _ => true,
+    }
+}
+
+async fn run_cmd_with_cache<B: ProcessBackend>(
+    backend: &B,
+    name: &CmdName,
+    cmd: &Arc<Cmd>,
+    input: CmdInput,
+    options: &RunOptions,
+) -> Result<(Output, Option<i32>)> {
+    // the output may depend on the env. the cache doesn't keep the exit code
+    let cache_key = match (&input, &options.env) {
+        (CmdInput::Text(input), None) if !cmd.wait_for_exit.unwrap_or(false) => cmd
+            .cache
+            .map(|_| ResponseCache::key(input, options.output_size)),
+        _ => None,
+    };
+
+    if let (Some(cache_config), Some(key)) = (cmd.cache, cache_key) {
+        let mut caches = cache_table().lock().await;
+        let cache = caches
+            .entry(name.clone())
+            .or_insert_with(|| ResponseCache::new(cache_config));
+        if let Some(output) = cache.get(key) {
+            tracing::debug!("cache hit: {}", name);
+            if let Some(OutputChunks(output_chunks)) = options.output_chunks.as_ref() {
+                let _ = output_chunks.send(output.clone());
+            }
+            return Ok((output, None));
+        }
+    }
+
+    let (output, exit_code) = if cmd.persistent.unwrap_or(true) {
+        // TODO(tacogips) TOBE run concurrently. this mutex hold the lock until the process ends
+        let mut proceses = backend.process_table().lock().await;
+        let output = run_cmd_in_process_table(backend, &mut proceses, cmd, input, options).await?;
+        (output, None)
+    } else {
+        run_one_shot(backend, name, cmd, input, options).await?
+    };
+
+    if let Some(key) = cache_key {
+        if let Some(cache) = cache_table().lock().await.get_mut(name) {
+            cache.insert(key, output.clone());
+        }
+    }
+    Ok((output, exit_code))
+}
+
+pub async fn cache_stats(name: &CmdName) -> Result<CacheStats> {
+    let cmd = get_cmd_from_table(name)?;
+    let cache_config = cmd
+        .cache
+        .ok_or_else(|| ProcessManagerError::CacheNotEnabled(name.clone()))?;
+
+    let mut caches = cache_table().lock().await;
+    let cache = caches
+        .entry(name.clone())
+        .or_insert_with(|| ResponseCache::new(cache_config));
+    Ok(cache.stats())
+}
+
+pub async fn flush_cache(name: &CmdName) -> Result<()> {
+    let cmd = get_cmd_from_table(name)?;
+    if cmd.cache.is_none() {
+        return Err(ProcessManagerError::CacheNotEnabled(name.clone()));
+    }
+
+    if let Some(cache) = cache_table().lock().await.get_mut(name) {
+        cache.flush();
+    }
+    Ok(())
+}
+
+/// Passes the input to the running process of the cmd, or to a process spawned with `cmd` if
+/// there is none.
+async fn run_cmd_in_process_table<B: ProcessBackend>(
+    backend: &B,
+    proceses: &mut ProcessTable<B::Child>,
+    cmd: &Arc<Cmd>,
+    input: CmdInput,
+    options: &RunOptions,
+) -> Result<Output> {
+    let name = &cmd.name;
+    let env = options.env.as_ref();
+    // a process respawned for the request env keeps the cmd it was running with
+    let mut respawn_cmd = None;
+    // a process restarted for its lifetime keeps the env too
+    let mut respawn_env = None;
+
+    if let Some(running_process) = proceses.get_mut(name) {
+        let pid = running_process.child.id();
+        match running_process.child.try_wait() {
+            Ok(None) => {
+                let env_changed = match env {
+                    Some(env) => running_process.env.as_ref() != Some(&env.vars),
+                    None => false,
+                };
+                if running_process.dirty {
+                    tracing::info!(cmd_name = %name, pid, "restarting process whose last interaction was cancelled");
+                    respawn_cmd = Some(running_process.running_cmd.clone());
+                    respawn_env = running_process.env.take();
+                } else if running_process.exceeded_max_lifetime() {
+                    tracing::info!(cmd_name = %name, pid, "restarting process which exceeded max_lifetime_sec");
+                    respawn_cmd = Some(running_process.running_cmd.clone());
+                    respawn_env = running_process.env.take();
+                } else if env_changed && env.map(|env| env.respawn).unwrap_or(false) {
+                    tracing::info!(cmd_name = %name, pid, "respawning process with the request env");
+                    respawn_cmd = Some(running_process.running_cmd.clone());
+                } else {
+                    if env_changed {
+                        tracing::warn!(
+                            cmd_name = %name,
+                            pid,
+                            "ignoring the request env since the process is already running"
+                        );
+                    }
+                    tracing::debug!(cmd_name = %name, pid, "reusing running process");
+                    metrics().inc_reuse();
+                    return pass_input_with_timeout(proceses, name, input, options).await;
+                }
+            }
+            Ok(Some(status)) => {
+                tracing::warn!(cmd_name = %name, pid, "process exited with {}, respawning it", status)
+            }
+            Err(e) => check_unhealthy_process(name, pid, e, auto_kill_unhealthy())?,
+        }
+    };
+
+    if respawn_cmd.is_some() {
+        if let Some(mut running_process) = proceses.remove(name) {
+            stop_process(name, &mut running_process).await;
+        }
+    }
+    remove_and_reap(proceses, name).await;
+    make_room_for_spawn(proceses, process_limit()).await?;
+
+    let cmd = match respawn_cmd {
+        Some(cmd) => cmd,
+        None => cmd.clone(),
+    };
+    let env = env.map(|env| &env.vars).or(respawn_env.as_ref());
+    let spawned_process = backend.spawn(cmd, env).await?;
+    add_to_process_table(proceses, spawned_process)?;
+
+    let output = pass_input_with_timeout(proceses, name, input, options).await?;
+    tracing::debug!(cmd_name = %name, "input passed to the process");
+    Ok(output)
+}
+
+/// Lets the process whose state can't be checked be killed and respawned, or with
+/// `auto_kill_unhealthy = false` fails with the status of the process so that it can be
+/// investigated.
+fn check_unhealthy_process(
+    name: &CmdName,
+    pid: Option<u32>,
+    error: std::io::Error,
+    auto_kill: bool,
+) -> Result<()> {
+    if auto_kill {
+        tracing::warn!(cmd_name = %name, pid, "failed to check the process, respawning it: {}", error);
+        return Ok(());
+    }
+    let status = pid.and_then(|pid| {
+        let pid = Pid::from_u32(pid);
+        let mut sys = System::new();
+        sys.refresh_process_specifics(pid, ProcessRefreshKind::new());
+        sys.process(pid).map(|os_process| os_process.status())
+    });
+    tracing::warn!(cmd_name = %name, pid, "failed to check the process ({:?}), leaving it: {}", status, error);
+    Err(ProcessManagerError::ProcessUnhealthy {
+        name: name.clone(),
+        status,
+        error: error.to_string(),
+    })
+}
+
+/// Removes the process from the table and waits for it to exit, killing it if it's still
+/// running, so that neither a zombie nor a stale entry is left.
+async fn remove_and_reap<C: ProcessChild>(proceses: &mut ProcessTable<C>, name: &CmdName) {
+    let mut running_process = match proceses.remove(name) {
+        Some(running_process) => running_process,
+        None => return,
+    };
+
+    running_process.unregister_pid();
+    match running_process.child.try_wait() {
+        Ok(Some(status)) => {
+            tracing::debug!(cmd_name = %name, "reaped process exited with {}", status)
+        }
+        _ => terminate_process(name, &mut running_process.child, Signal::Kill).await,
+    }
+}
+
+/// Passes the input under the command's timeout. A process that timed out is still busy with
+/// the computation, so it is terminated and removed from the table instead of being reused.
+async fn pass_input_with_timeout<C: ProcessChild>(
+    proceses: &mut ProcessTable<C>,
+    name: &CmdName,
+    input: CmdInput,
+    options: &RunOptions,
+) -> Result<Output> {
+    let running_process = proceses
+        .get_mut(name)
+        .ok_or_else(|| ProcessManagerError::FailedToAddProcessTable(name.clone()))?;
+    running_process.last_used = Instant::now();
+    running_process.dirty = true;
+    let running_cmd = running_process.running_cmd.clone();
+    let timeout_sec = running_process.next_timeout_sec();
+    let span = tracing::debug_span!("process", cmd_name = %name, pid = running_process.child.id());
+
+    let result = timeout(
+        Duration::from_secs(timeout_sec),
+        pass_input_to_process(
+            name,
+            running_process.io(name)?,
+            input,
+            effective_output_size(name, options, &running_cmd, max_output_size_limit()),
+            options.wait_output_timeout_milli_sec,
+            options.output_chunks.as_ref(),
+            &running_cmd,
+        )
+        .instrument(span),
+    )
+    .await;
+    running_process.dirty = false;
+
+    match result {
+        Ok(Err(ProcessManagerError::StdinWriteTimeout(name))) => {
+            tracing::warn!("process is not reading stdin, terminating: {}", name);
+            running_process.unregister_pid();
+            terminate_process(&name, &mut running_process.child, Signal::Kill).await;
+            proceses.remove(&name);
+            Err(ProcessManagerError::StdinWriteTimeout(name))
+        }
+        Ok(Ok(output)) => {
+            running_process.recent_output.push(&output);
+            Ok(output)
+        }
+        Ok(Err(e @ ProcessManagerError::InputTooLarge { .. })) => {
+            // the raw input until the limit has been passed, so the process is restarted on
+            // the next run
+            running_process.dirty = true;
+            Err(e)
+        }
+        Ok(Err(e)) => Err(e),
+        Err(elapsed) => {
+            metrics().inc_timeout();
+            let signal = running_cmd.timeout_signal.unwrap_or(Signal::Kill);
+            tracing::warn!(
+                "cmd timed out after {} sec, terminating with {:?}: {}",
+                timeout_sec,
+                signal,
+                running_cmd.name
             );
-            assert_eq!("                aaa ;bbb\n".to_string(), input.unwrap());
+            running_process.unregister_pid();
+            terminate_process(name, &mut running_process.child, signal).await;
+            proceses.remove(name);
+            Err(ProcessManagerError::Timeout(elapsed))
+        }
+    }
+}
+
+/// Runs a non-persistent cmd on a fresh process, which isn't added to the process table. The
+/// process exits when its stdin is closed after the input, and is reaped here. Returns the
+/// exit code too with `wait_for_exit`.
+async fn run_one_shot<B: ProcessBackend>(
+    backend: &B,
+    name: &CmdName,
+    cmd: &Arc<Cmd>,
+    input: CmdInput,
+    options: &RunOptions,
+) -> Result<(Output, Option<i32>)> {
+    let env = options.env.as_ref().map(|env| &env.vars);
+    let mut running_process = backend.spawn(cmd.clone(), env).await?;
+    let timeout_sec = running_process.next_timeout_sec();
+    let span = tracing::debug_span!("process", cmd_name = %name, pid = running_process.child.id());
+
+    let result = timeout(Duration::from_secs(timeout_sec), async {
+        let output = pass_input_to_process(
+            name,
+            running_process.io(name)?,
+            input,
+            effective_output_size(name, options, cmd, max_output_size_limit()),
+            options.wait_output_timeout_milli_sec,
+            options.output_chunks.as_ref(),
+            cmd,
+        )
+        .instrument(span)
+        .await?;
+
+        if !cmd.wait_for_exit.unwrap_or(false) {
+            return Ok((output, None));
+        }
+        let status = running_process.child.wait().await?;
+        tracing::debug!("one-shot process exited with {}: {}", status, name);
+        Ok((output, status.code()))
+    })
+    .await;
+
+    match timeout(kill_grace(), running_process.child.wait()).await {
+        Ok(Ok(status)) => tracing::debug!("one-shot process exited with {}: {}", status, name),
+        _ => terminate_process(name, &mut running_process.child, Signal::Kill).await,
+    }
+
+    result.map_err(|elapsed| {
+        metrics().inc_timeout();
+        tracing::warn!("cmd timed out after {} sec: {}", timeout_sec, name);
+        ProcessManagerError::Timeout(elapsed)
+    })?
+}
+
+/// Sends `signal` and waits for the child to exit, falling back to SIGKILL when it is still
+/// alive after the grace period.
+async fn terminate_process<C: ProcessChild>(name: &CmdName, child: &mut C, signal: Signal) {
+    terminate_process_within(name, child, signal, kill_grace()).await
+}
+
+async fn terminate_process_within<C: ProcessChild>(
+    name: &CmdName,
+    child: &mut C,
+    signal: Signal,
+    grace: Duration,
+) {
+    if signal != Signal::Kill {
+        match child.signal(signal) {
+            Ok(()) => {
+                if let Ok(Ok(status)) = timeout(grace, child.wait()).await {
+                    tracing::debug!("process exited with {}: {}", status, name);
+                    return;
+                }
+            }
+            Err(e) => tracing::warn!("failed to send {:?} to {}: {}", signal, name, e),
+        }
+    }
+
+    if let Err(e) = child.kill().await {
+        tracing::warn!("failed to kill process {}: {}", name, e);
+    }
+}
+
+/// Asks the process to quit with its `quit_cmd` so the REPL can flush its state, and terminates
+/// it when it has no `quit_cmd` or doesn't exit within the grace period.
+async fn stop_process<C: ProcessChild>(name: &CmdName, running_process: &mut RunningProcess<C>) {
+    running_process.unregister_pid();
+    let running_cmd = running_process.running_cmd.clone();
+    if let Some(quit_cmd) = running_cmd.quit_cmd.as_ref() {
+        if let Some(child_stdin) = running_process.stdin.as_mut() {
+            tracing::debug!("sending quit cmd to process {}: {}", name, quit_cmd);
+            match child_stdin.write_all(quit_cmd.as_bytes()).await {
+                Ok(()) => {
+                    if let Ok(Ok(status)) =
+                        timeout(kill_grace(), running_process.child.wait()).await
+                    {
+                        tracing::debug!("process quit with {}: {}", status, name);
+                        return;
+                    }
+                }
+                Err(e) => tracing::warn!("failed to send quit cmd to {}: {}", name, e),
+            }
+        }
+    }
+
+    terminate_process(name, &mut running_process.child, Signal::Term).await;
+}
+
+pub async fn kill_cmd(name: &CmdName) -> Result<()> {
+    let mut proceses = process_table().lock().await;
+    let mut running_process = proceses
+        .remove(name)
+        .ok_or_else(|| ProcessManagerError::ProcessNotRunning(name.clone()))?;
+
+    tracing::info!("killing process: {}", name);
+    stop_process(name, &mut running_process).await;
+    Ok(())
+}
+
+/// Sends EOF to the running process, so the REPL finishes like by `Ctrl-D`. The process is
+/// removed since its stdin can't be reopened, and it is respawned on the next input. It is
+/// terminated if it doesn't exit within the grace period.
+pub async fn eof_cmd(name: &CmdName) -> Result<()> {
+    let cmd = get_cmd_from_table(name)?;
+    let _cmd_lock = lock_cmd(name, cmd.on_concurrent_request.unwrap_or_default()).await?;
+
+    let mut proceses = process_table().lock().await;
+    let mut running_process = proceses
+        .remove(name)
+        .ok_or_else(|| ProcessManagerError::ProcessNotRunning(name.clone()))?;
+    drop(proceses);
+    running_process.unregister_pid();
+
+    tracing::info!("sending EOF to process: {}", name);
+    if let Some(mut stdin) = running_process.stdin.take() {
+        // the terminal stays open while the master is
+        if running_process.pty.is_some() {
+            if let Err(e) = stdin.write_all(&[pty::EOF]).await {
+                tracing::warn!("failed to send EOF to {}: {}", name, e);
+            }
         }
     }
+    match timeout(kill_grace(), running_process.child.wait()).await {
+        Ok(Ok(status)) => tracing::debug!("process exited with {}: {}", status, name),
+        _ => terminate_process(name, &mut running_process.child, Signal::Term).await,
+    }
+    Ok(())
+}
+
+/// Returns the last `n` output lines of the running process.
+pub async fn recent_output(name: &CmdName, n: usize) -> Result<Vec<String>> {
+    let proceses = process_table().lock().await;
+    let running_process = proceses
+        .get(name)
+        .ok_or_else(|| ProcessManagerError::ProcessNotRunning(name.clone()))?;
+    Ok(running_process.recent_output.last(n))
+}
+
+/// Sends the signal to the running process without removing it, e.g. `INT` to interrupt a
+/// runaway computation of the REPL. This doesn't wait for the process table lock, which the
+/// interaction to interrupt holds.
+pub async fn signal_cmd(name: &CmdName, signal: Signal) -> Result<()> {
+    // held while sending so that the pid isn't unregistered and reused meanwhile
+    let pid_table = pid_table().lock().unwrap();
+    let pid = pid_table
+        .get(name)
+        .ok_or_else(|| ProcessManagerError::ProcessNotRunning(name.clone()))?;
+
+    tracing::info!("sending {:?} to process: {}", signal, name);
+    send_signal_to_pid(*pid, signal)
+}
+
+/// Passes the `reset_input` to the running process to clear its state, which is faster than a
+/// restart and keeps what the REPL has loaded.
+pub async fn reset_cmd(name: &CmdName) -> Result<()> {
+    let cmd = get_cmd_from_table(name)?;
+    let reset_input = cmd
+        .reset_input
+        .clone()
+        .ok_or_else(|| ProcessManagerError::ResetNotConfigured(name.clone()))?;
+    let _cmd_lock = lock_cmd(name, cmd.on_concurrent_request.unwrap_or_default()).await?;
+
+    let mut proceses = process_table().lock().await;
+    reset_process_in_table(&mut proceses, name, reset_input).await
+}
+
+async fn reset_process_in_table(
+    proceses: &mut ProcessTable,
+    name: &CmdName,
+    reset_input: String,
+) -> Result<()> {
+    let running_process = proceses
+        .get_mut(name)
+        .ok_or_else(|| ProcessManagerError::ProcessNotRunning(name.clone()))?;
+
+    tracing::info!("resetting process: {}", name);
+    running_process.last_used = Instant::now();
+    running_process.dirty = true;
+    pass_input_discarding_output(running_process, reset_input, "reset").await?;
+    running_process.dirty = false;
+    Ok(())
+}
+
+pub async fn restart_cmd(name: &CmdName) -> Result<()> {
+    let cmd = get_cmd_from_table(name)?;
+    restart_process(&TokioProcessBackend, cmd).await
+}
+
+async fn restart_process<B: ProcessBackend>(backend: &B, cmd: Arc<Cmd>) -> Result<()> {
+    let name = cmd.name.clone();
+    let mut proceses = backend.process_table().lock().await;
+    if let Some(mut running_process) = proceses.remove(&name) {
+        tracing::info!("stopping process to restart: {}", name);
+        stop_process(&name, &mut running_process).await;
+    }
+    make_room_for_spawn(&mut proceses, process_limit()).await?;
+
+    add_to_process_table(&mut proceses, backend.spawn(cmd, None).await?)?;
+    tracing::info!("process restarted: {}", name);
+    Ok(())
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ReloadSummary {
+    pub added: Vec<CmdName>,
+    pub removed: Vec<CmdName>,
+    /// Changed cmds whose running process is stopped, to be spawned with the new settings on
+    /// the next run.
+    pub respawned: Vec<CmdName>,
+    /// Changed cmds whose running process keeps running with the new settings.
+    pub updated: Vec<CmdName>,
+}
+
+/// Whether the change affects how the process is spawned, so the running process can't be
+/// reused. The other fields only change how the input and output are handled.
+fn requires_respawn(old: &Cmd, new: &Cmd) -> bool {
+    old.cmd != new.cmd
+        || old.args != new.args
+        || old.env != new.env
+        || old.use_shell != new.use_shell
+        || old.shell != new.shell
+        || old.init_input != new.init_input
+        || old.capture_stderr != new.capture_stderr
+        || old.redirect_stderr_to_stdout != new.redirect_stderr_to_stdout
+        || old.use_pty != new.use_pty
+        || old.persistent != new.persistent
+}
+
+/// Replaces the cmd table, keeping the warm processes of the cmds that don't require a respawn.
+pub async fn reload_cmd_table(cmd_table: CmdTable) -> Result<ReloadSummary> {
+    let new_cmd_table = into_shared_cmd_table(cmd_table);
+
+    let mut proceses = process_table().lock().await;
+    let old_cmd_table = {
+        let mut current = CMD_TABLE
+            .get()
+            .ok_or(ProcessManagerError::CmdTableNotInitialize)?
+            .write()
+            .unwrap();
+        std::mem::replace(&mut *current, new_cmd_table.clone())
+    };
+
+    let summary = migrate_processes(&mut proceses, &old_cmd_table, &new_cmd_table).await;
+    drop(proceses);
+
+    let mut caches = cache_table().lock().await;
+    for name in summary
+        .removed
+        .iter()
+        .chain(summary.respawned.iter())
+        .chain(summary.updated.iter())
+    {
+        caches.remove(name);
+    }
+
+    Ok(summary)
+}
+
+async fn migrate_processes(
+    proceses: &mut ProcessTable,
+    old_cmd_table: &SharedCmdTable,
+    new_cmd_table: &SharedCmdTable,
+) -> ReloadSummary {
+    let mut summary = ReloadSummary::default();
+
+    for name in old_cmd_table.keys() {
+        if !new_cmd_table.contains_key(name) {
+            if let Some(mut running_process) = proceses.remove(name) {
+                stop_process(name, &mut running_process).await;
+            }
+            summary.removed.push(name.clone());
+        }
+    }
+
+    for (name, new_cmd) in new_cmd_table.iter() {
+        match old_cmd_table.get(name) {
+            None => summary.added.push(name.clone()),
+            Some(old_cmd) if old_cmd == new_cmd => {}
+            Some(old_cmd) if requires_respawn(old_cmd, new_cmd) => {
+                if let Some(mut running_process) = proceses.remove(name) {
+                    tracing::info!("stopping process to respawn with the new config: {}", name);
+                    stop_process(name, &mut running_process).await;
+                }
+                summary.respawned.push(name.clone());
+            }
+            Some(_) => {
+                if let Some(running_process) = proceses.get_mut(name) {
+                    running_process.running_cmd = new_cmd.clone();
+                }
+                summary.updated.push(name.clone());
+            }
+        }
+    }
+
+    summary.added.sort();
+    summary.removed.sort();
+    summary.respawned.sort();
+    summary.updated.sort();
+    summary
+}
+
+/// Stops every running process and empties the process table, then the cmds are spawned again
+/// on the next run. Returns the names of the stopped cmds.
+pub async fn reset_all() -> Vec<CmdName> {
+    let mut proceses = process_table().lock().await;
+    let mut names: Vec<CmdName> = proceses.keys().cloned().collect();
+    names.sort();
+    terminate_all(&mut proceses).await;
+    names
+}
+
+/// Stops every running process so that no REPL is orphaned when the server stops.
+pub async fn shutdown_all() {
+    let mut proceses = process_table().lock().await;
+    terminate_all(&mut proceses).await;
+}
+
+async fn terminate_all(proceses: &mut ProcessTable) {
+    futures::future::join_all(proceses.iter_mut().map(|(name, running_process)| {
+        tracing::info!("stopping process: {}", name);
+        stop_process(name, running_process)
+    }))
+    .await;
+    proceses.clear();
+}
+
+fn send_signal(child: &Child, signal: Signal) -> Result<()> {
+    let pid = child.id().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "process has already exited")
+    })?;
+    send_signal_to_pid(pid, signal)
+}
+
+fn send_signal_to_pid(pid: u32, signal: Signal) -> Result<()> {
+    // SAFETY: kill(2) has no memory safety requirements, the pid belongs to our own child.
+    if unsafe { libc::kill(pid as libc::pid_t, signal.as_raw()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+fn arrange_input(
+    mut input: String,
+    auto_trailing_newline: bool,
+    join_new_lines_with: Option<&String>,
+    truncate_line_regex: Option<&String>,
+    remove_empty_line: bool,
+    pipeline: &[InputStep],
+    newline: &str,
+) -> Result<String> {
+    // inputs from editors on windows end lines with "\r\n"
+    if input.contains("\r\n") {
+        input = input.replace("\r\n", "\n");
+    }
+    let truncate_line_regex = truncate_line_regex
+        .map(|truncate_line_regex| Regex::new(truncate_line_regex))
+        .transpose()?;
+    let empty_line_regex = remove_empty_line.then(|| Regex::new(r"^[\s\t]+$").unwrap());
+
+    for step in pipeline {
+        match step {
+            InputStep::TruncateLine => {
+                if let Some(re) = truncate_line_regex.as_ref() {
+                    let mut ss = Vec::<String>::new();
+                    for each in input.split("\n") {
+                        ss.push(re.replace_all(each, "").to_string());
+                    }
+                    input = ss.join("\n")
+                }
+            }
+            InputStep::RemoveEmptyLine => {
+                if let Some(empty_line_regex) = empty_line_regex.as_ref() {
+                    let mut ss = Vec::<String>::new();
+                    for each in input.split("\n") {
+                        if !each.is_empty() && !empty_line_regex.is_match(each) {
+                            ss.push(each.to_string());
+                        }
+                    }
+                    input = ss.join("\n")
+                }
+            }
+            InputStep::JoinInputNewline => {
+                //TODO(tacogips) retain the trailing new line
+                if let Some(rep) = join_new_lines_with {
+                    input = input.replace('\n', rep);
+                }
+            }
+            InputStep::AutoTrailingNewline => {
+                if auto_trailing_newline {
+                    input = format!("{}\n", input);
+                }
+            }
+        }
+    }
+    if newline != "\n" {
+        input = input.replace('\n', newline);
+    }
+
+    Ok(input)
+}
+
+fn arrange_cmd_input(cmd: &Cmd, input: String) -> Result<String> {
+    arrange_input(
+        input,
+        cmd.auto_trailing_newline,
+        cmd.join_input_newline_with.as_ref(),
+        cmd.truncate_line_regex.as_ref(),
+        cmd.remove_empty_line,
+        cmd.input_pipeline
+            .as_deref()
+            .unwrap_or(DEFAULT_INPUT_PIPELINE),
+        cmd.input_newline.as_deref().unwrap_or("\n"),
+    )
+}
+
+/// The input as passed to stdin of the cmd, after the arrangements such as
+/// `join_input_newline_with` and `auto_trailing_newline`.
+pub fn arranged_input(cmd_name: &CmdName, input: &str) -> Result<String> {
+    arrange_cmd_input(&*get_cmd_from_table(cmd_name)?, input.to_string())
+}
+
+/// Splits the output on the prompts and drops the empty segments, such as the one after the
+/// trailing prompt. List elements don't include the trailing newline of each result.
+fn split_output_on_prompt(output: &[u8], prompt_regex: &str) -> Result<Vec<Output>> {
+    let re = BytesRegex::new(prompt_regex)?;
+    let segments = re
+        .split(output)
+        .filter(|segment| !segment.iter().all(u8::is_ascii_whitespace))
+        .map(|segment| segment.to_vec())
+        .collect();
+    Ok(segments)
+}
+
+/// Drops the first `lines` lines, e.g. the input echoed back by the REPL.
+fn skip_leading_lines(output: &[u8], lines: usize) -> Output {
+    let mut rest = output;
+    for _ in 0..lines {
+        match rest.iter().position(|b| *b == b'\n') {
+            Some(pos) => rest = &rest[pos + 1..],
+            None => return Output::new(),
+        }
+    }
+    rest.to_vec()
+}
+
+/// Keeps the first `max_lines` lines, followed by the number of the cut lines.
+fn truncate_lines(output: Output, max_lines: usize) -> Output {
+    let lines = output.split_inclusive(|b| *b == b'\n').count();
+    if lines <= max_lines {
+        return output;
+    }
+    let mut truncated: Output = output
+        .split_inclusive(|b| *b == b'\n')
+        .take(max_lines)
+        .flatten()
+        .copied()
+        .collect();
+    if !matches!(truncated.last(), None | Some(b'\n')) {
+        truncated.push(b'\n');
+    }
+    truncated.extend(format!("... {} more lines\n", lines - max_lines).into_bytes());
+    truncated
+}
+
+/// Removes CSI sequences like `ESC [ 31 m`, OSC sequences like the window title, and the other
+/// two byte escape sequences.
+fn strip_ansi_escapes(output: &[u8]) -> Output {
+    const ESC: u8 = 0x1b;
+    const BEL: u8 = 0x07;
+
+    let mut stripped = Vec::with_capacity(output.len());
+    let mut i = 0;
+    while i < output.len() {
+        if output[i] != ESC {
+            stripped.push(output[i]);
+            i += 1;
+            continue;
+        }
+        match output.get(i + 1) {
+            Some(b'[') => {
+                // parameter and intermediate bytes until the final byte in 0x40..=0x7e
+                i += 2;
+                while i < output.len() && !(0x40..=0x7e).contains(&output[i]) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            Some(b']') => {
+                // terminated by BEL or `ESC \`
+                i += 2;
+                while i < output.len() {
+                    if output[i] == BEL {
+                        i += 1;
+                        break;
+                    }
+                    if output[i] == ESC && output.get(i + 1) == Some(&b'\\') {
+                        i += 2;
+                        break;
+                    }
+                    i += 1;
+                }
+            }
+            Some(_) => i += 2,
+            None => i += 1,
+        }
+    }
+    stripped
+}
+
+/// Applies `max_output_lines` last, so the prompts removed before don't count as lines.
+fn limit_output_lines(output: Output, cmd: &Cmd) -> Output {
+    match cmd.max_output_lines {
+        Some(max_lines) => truncate_lines(output, max_lines),
+        None => output,
+    }
+}
+
+fn arrange_output(mut output: Output, cmd: &Cmd) -> Result<CmdOutput> {
+    // matched before the arrangements, which may drop the error, e.g. by max_output_lines
+    let is_error = match cmd.error_regex.as_ref() {
+        Some(error_regex) => BytesRegex::new(error_regex)?.is_match(&output),
+        None => false,
+    };
+    if let Some(lines) = cmd.skip_leading_output_lines {
+        output = skip_leading_lines(&output, lines);
+    }
+    if cmd.strip_ansi.unwrap_or(false) {
+        output = strip_ansi_escapes(&output);
+    }
+    if let Some(output_filter_regex) = cmd.output_filter_regex.as_ref() {
+        let replace = cmd.output_replace.as_deref().unwrap_or("");
+        output = BytesRegex::new(output_filter_regex)?
+            .replace_all(&output, replace.as_bytes())
+            .into_owned();
+    }
+
+    let prompt_regex = match (cmd.prompt_regex.as_ref(), cmd.prompt_output) {
+        (Some(prompt_regex), Some(_)) => prompt_regex,
+        _ => {
+            return Ok(CmdOutput {
+                output: limit_output_lines(output, cmd),
+                segments: None,
+                encoding: cmd.output_encoding.unwrap_or_default(),
+                exit_code: None,
+                output_bytes: 0,
+                elapsed: Duration::ZERO,
+                is_error,
+            })
+        }
+    };
+
+    let segments = split_output_on_prompt(&output, prompt_regex)?;
+    let output = segments.concat();
+    let segments = match cmd.prompt_output {
+        Some(PromptOutput::List) => Some(
+            segments
+                .into_iter()
+                .map(|mut segment| {
+                    while matches!(segment.last(), Some(b'\n' | b'\r')) {
+                        segment.pop();
+                    }
+                    segment
+                })
+                .collect(),
+        ),
+        _ => None,
+    };
+
+    Ok(CmdOutput {
+        output: limit_output_lines(output, cmd),
+        segments,
+        encoding: cmd.output_encoding.unwrap_or_default(),
+        exit_code: None,
+        output_bytes: 0,
+        elapsed: Duration::ZERO,
+        is_error,
+    })
+}
+
+async fn pass_input_to_process(
+    name: &CmdName,
+    io: ProcessIo<'_>,
+    input: CmdInput,
+    max_output_size: usize,
+    wait_output_timeout_milli_sec: Option<u64>,
+    output_chunks: Option<&OutputChunks>,
+    cmd: &Cmd,
+) -> Result<Output> {
+    let input = match input {
+        CmdInput::Text(input) => {
+            let input = arrange_cmd_input(cmd, input)?;
+            if cmd.no_empty_input
+                && (input.is_empty() || Regex::new(r"^[\s\n]+$")?.is_match(&input))
+            {
+                return Err(ProcessManagerError::EmptyInputNotAllowed);
+            }
+
+            tracing::info!(cmd_name = %name, "input: {}", input);
+            if let Some(history_file) = cmd.history_file.as_ref() {
+                history::record(history_file, &input);
+            }
+            CmdInput::Text(input)
+        }
+        CmdInput::Raw(input) => {
+            tracing::info!(cmd_name = %name, "raw input");
+            CmdInput::Raw(input)
+        }
+    };
+    let ProcessIo {
+        stdin,
+        stdout: child_stdout,
+        stderr: child_stderr,
+    } = io;
+    let child_stdin = stdin
+        .as_mut()
+        .ok_or_else(|| ProcessManagerError::FailedToGetChildProcessStdin(name.clone()))?;
+
+    tracing::debug!("passing to stdin of process: {}", name);
+
+    let echoed_input = match &input {
+        CmdInput::Text(input) if cmd.strip_input_echo.unwrap_or(false) => Some(input.clone()),
+        _ => None,
+    };
+    let mut ends_with_newline = false;
+    match input {
+        CmdInput::Text(input) => {
+            let bytes = cmd.input_encoding.unwrap_or_default().encode(&input)?;
+            write_stdin(name, child_stdin, &bytes).await?;
+            ends_with_newline = input.ends_with(cmd.input_newline.as_deref().unwrap_or("\n"));
+        }
+        CmdInput::Raw(mut input) => {
+            let max_input_size = max_input_size();
+            let mut size = 0;
+            while let Some(chunk) = input.next().await {
+                let chunk = chunk?;
+                size += chunk.len();
+                check_input_len(size, max_input_size)?;
+                write_stdin(name, child_stdin, &chunk).await?;
+                if let Some(last) = chunk.last() {
+                    ends_with_newline = *last == b'\n';
+                }
+            }
+        }
+    }
+    if !cmd.persistent.unwrap_or(true) {
+        // filters print the output at the end of stdin
+        tracing::debug!("closing stdin of one-shot process: {}", name);
+        if cmd.use_pty.unwrap_or(false) {
+            // the terminal stays open while the master is, so send EOF instead. EOF in the middle
+            // of a line only flushes the line.
+            let eof: &[u8] = if ends_with_newline {
+                &[pty::EOF]
+            } else {
+                &[pty::EOF, pty::EOF]
+            };
+            write_stdin(name, child_stdin, eof).await?;
+        }
+        *stdin = None;
+    }
+    tracing::debug!("reading from stdout of process: {}", name);
+
+    let read_buffer_size = cmd.read_buffer_size.unwrap_or(DEFAULT_READ_BUFFER_SIZE);
+    let mut std_out_read_buf = BytesMut::with_capacity(read_buffer_size);
+    let mut std_out_reader = BufReader::with_capacity(read_buffer_size, child_stdout);
+
+    let mut std_err_read_buf = BytesMut::with_capacity(read_buffer_size);
+    let mut std_err_reader =
+        child_stderr.map(|child_stderr| BufReader::with_capacity(read_buffer_size, child_stderr));
+
+    let mut latest_read_at: Option<Instant> = None;
+    let mut result = Output::new();
+
+    let wait_duration_sequential_output = Duration::from_millis(
+        wait_output_timeout_milli_sec
+            .or(cmd.wait_output_timeout_milli_sec)
+            .unwrap_or(DEFAULT_WAIT_OUTPUT_FINISH_SEC),
+    );
+
+    // the outputs are closed when the process exited, then there's no more output to wait for
+    let read_until_closed = cmd.wait_for_exit.unwrap_or(false);
+    let mut std_out_closed = false;
+    let mut std_err_closed = std_err_reader.is_none();
+    let sentinel = cmd
+        .output_sentinel
+        .as_deref()
+        .filter(|sentinel| !sentinel.is_empty());
+    let mut sentinel_searched_to = 0;
+    let mut sentinel_found = false;
+
+    // a char split between the reads is completed by the next read of the same stream, so
+    // that a write to the other stream doesn't come between its bytes
+    let text = cmd.output_encoding != Some(OutputEncoding::Base64);
+    let mut std_out_pending = Output::new();
+    let mut std_err_pending = Output::new();
+    let mut output_full = false;
+    let mut sent_to = 0;
+
+    // the output is finished when nothing is read for `wait_duration_sequential_output` after
+    // the latest read. Waits for the first output without a deadline.
+    loop {
+        if std_out_closed && std_err_closed {
+            tracing::debug!("outputs of process are closed: {}", name);
+            break;
+        }
+        select! {
+            chunk = read_output_chunk(Some(&mut std_out_reader), &mut std_out_read_buf, "stdout"), if !std_out_closed => {
+                match chunk? {
+                    Some(chunk) => {
+                        let chunk = hold_incomplete_char(&mut std_out_pending, chunk, text);
+                        if !output_full {
+                            output_full = append_output(&mut result, &chunk, max_output_size, text);
+                        }
+                        latest_read_at = Some(Instant::now());
+                    }
+                    None => std_out_closed = true,
+                }
+            }
+
+            chunk = read_output_chunk(std_err_reader.as_mut(), &mut std_err_read_buf, "stderr"), if !std_err_closed => {
+                match chunk? {
+                    Some(chunk) => {
+                        let chunk = hold_incomplete_char(&mut std_err_pending, chunk, text);
+                        if !output_full {
+                            output_full = append_output(&mut result, &chunk, max_output_size, text);
+                        }
+                        latest_read_at = Some(Instant::now());
+                    }
+                    None => std_err_closed = true,
+                }
+            }
+
+            _ = sleep_until_or_pending(
+                latest_read_at
+                    .filter(|_| !read_until_closed)
+                    .map(|read_at| read_at + wait_duration_sequential_output)
+            ) => break,
+        }
+
+        if let Some(sentinel) = sentinel {
+            if let Some(pos) = find_sentinel(&result, sentinel.as_bytes(), sentinel_searched_to) {
+                tracing::debug!("output sentinel found: {}", name);
+                result.truncate(pos);
+                sentinel_found = true;
+                break;
+            }
+            // a sentinel split between the reads starts in the last bytes
+            sentinel_searched_to = result.len().saturating_sub(sentinel.len() - 1);
+        }
+        // the bytes which may start a sentinel are sent with the next chunk
+        let sendable = match sentinel {
+            Some(_) => sentinel_searched_to,
+            None => result.len(),
+        };
+        send_output_chunk(output_chunks, &result, &mut sent_to, sendable);
+    }
+    if !sentinel_found {
+        // the bytes of a char never completed
+        for pending in [std_out_pending, std_err_pending] {
+            if !output_full {
+                output_full = append_output(&mut result, &pending, max_output_size, text);
+            }
+        }
+    }
+    send_output_chunk(output_chunks, &result, &mut sent_to, result.len());
+    if let Some(echoed_input) = echoed_input {
+        result = strip_echo(result, echoed_input.as_bytes());
+    }
+    Ok(result)
+}
+
+/// Sends the output between `sent_to` and `end` to `output_chunks`, if any.
+fn send_output_chunk(
+    output_chunks: Option<&OutputChunks>,
+    output: &[u8],
+    sent_to: &mut usize,
+    end: usize,
+) {
+    if let Some(OutputChunks(output_chunks)) = output_chunks {
+        if end > *sent_to {
+            // the receiver may have gone with its connection
+            let _ = output_chunks.send(output[*sent_to..end].to_vec());
+            *sent_to = end;
+        }
+    }
+}
+
+/// The position of the first sentinel in the output, searched from `from`. A sentinel over
+/// `output_size` isn't found, then the output ends when it pauses instead.
+fn find_sentinel(output: &[u8], sentinel: &[u8], from: usize) -> Option<usize> {
+    output
+        .get(from..)?
+        .windows(sentinel.len())
+        .position(|window| window == sentinel)
+        .map(|pos| from + pos)
+}
+
+/// Appends the chunk up to `max_output_size` bytes of the whole output, and returns whether
+/// the output is full. The rest is read and discarded so that it doesn't leak into the output
+/// of the next input. With `text`, the output is cut before the UTF-8 char at the limit
+/// instead of in the middle of it.
+fn append_output(result: &mut Output, chunk: &[u8], max_output_size: usize, text: bool) -> bool {
+    let room = max_output_size.saturating_sub(result.len());
+    if chunk.len() <= room {
+        result.extend_from_slice(chunk);
+        return result.len() >= max_output_size;
+    }
+
+    let mut cut = room;
+    while text && cut > 0 && is_utf8_continuation(chunk[cut]) {
+        cut -= 1;
+    }
+    tracing::debug!(
+        "discarding {} bytes over output_size {}",
+        chunk.len() - cut,
+        max_output_size
+    );
+    result.extend_from_slice(&chunk[..cut]);
+    true
+}
+
+fn is_utf8_continuation(byte: u8) -> bool {
+    byte & 0xc0 == 0x80
+}
+
+/// The number of the bytes of the UTF-8 char started at the end but not completed.
+fn incomplete_char_len(bytes: &[u8]) -> usize {
+    let start = match bytes
+        .iter()
+        .rev()
+        .take(4)
+        .position(|byte| !is_utf8_continuation(*byte))
+    {
+        Some(pos) => bytes.len() - 1 - pos,
+        None => return 0,
+    };
+    let char_len = match bytes[start] {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => 1,
+    };
+    let tail_len = bytes.len() - start;
+    if tail_len < char_len {
+        tail_len
+    } else {
+        0
+    }
+}
+
+/// Prefixes the chunk with the incomplete char held from the previous chunk of the stream,
+/// and holds the one at the end of this chunk in turn.
+fn hold_incomplete_char(pending: &mut Output, chunk: Output, text: bool) -> Output {
+    if !text {
+        return chunk;
+    }
+    let mut bytes = std::mem::take(pending);
+    bytes.extend_from_slice(&chunk);
+    let incomplete = incomplete_char_len(&bytes);
+    *pending = bytes.split_off(bytes.len() - incomplete);
+    bytes
+}
+
+/// Removes the leading copy of the input from the output. The `\r` that a terminal adds
+/// before `\n` in the echo is ignored. The output is returned as is unless it starts with
+/// the whole input.
+fn strip_echo(output: Output, input: &[u8]) -> Output {
+    let mut pos = 0;
+    for &expected in input {
+        if expected != b'\r' {
+            while output.get(pos) == Some(&b'\r') {
+                pos += 1;
+            }
+        }
+        if output.get(pos) != Some(&expected) {
+            return output;
+        }
+        pos += 1;
+    }
+    output[pos..].to_vec()
+}
+
+async fn write_stdin(
+    name: &CmdName,
+    child_stdin: &mut (dyn AsyncWrite + Unpin + Send),
+    bytes: &[u8],
+) -> Result<()> {
+    // the pipe buffer fills up when the process isn't reading stdin
+    timeout(
+        Duration::from_secs(STDIN_WRITE_TIMEOUT_SEC),
+        child_stdin.write_all(bytes),
+    )
+    .await
+    .map_err(|_| ProcessManagerError::StdinWriteTimeout(name.clone()))??;
+    Ok(())
+}
+
+/// Sleeps until the deadline, or never completes if there is no deadline.
+async fn sleep_until_or_pending(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Reads the next chunk of stdout or stderr, labeled by `label` in the logs. Returns `None` at
+/// the end of the output, or never completes if there is no reader.
+async fn read_output_chunk<R: AsyncRead + Unpin>(
+    reader: Option<&mut R>,
+    buf: &mut BytesMut,
+    label: &str,
+) -> Result<Option<Output>> {
+    let reader = match reader {
+        Some(reader) => reader,
+        None => return std::future::pending().await,
+    };
+
+    buf.clear();
+    match reader.read_buf(buf).await {
+        Err(e) => {
+            tracing::debug!(" read {} error :{}", label, e);
+            Err(ProcessManagerError::IOError(e))
+        }
+        Ok(0) => Ok(None),
+        Ok(read_size) => {
+            tracing::debug!(
+                " finished to read from {} of process :{:?}",
+                label,
+                String::from_utf8_lossy(&buf[..read_size])
+            );
+            Ok(Some(buf[..read_size].to_vec()))
+        }
+    }
+}
+
+/// Splits the cmd into the program and the args, or wraps the whole cmd with the shell if
+/// `use_shell` is set.
+fn program_and_args(cmd: &Cmd) -> (String, Vec<String>) {
+    if cmd.use_shell {
+        let shell = cmd.shell.as_deref().unwrap_or(DEFAULT_SHELL);
+        let mut args = vec!["-c".to_string(), cmd.cmd.clone()];
+        if !cmd.args.is_empty() {
+            // the first arg after the cmd is `$0`
+            args.push(shell.to_string());
+            args.extend(cmd.args.iter().cloned());
+        }
+        return (shell.to_string(), args);
+    }
+
+    let mut words = cmd.cmd.split_whitespace().map(|word| word.to_string());
+    let program = words.next().unwrap_or_default();
+    (program, words.chain(cmd.args.iter().cloned()).collect())
+}
+
+/// Replaces stderr of the child with the stdout pipe. To be called in `pre_exec`.
+fn redirect_stderr_to_stdout() -> std::io::Result<()> {
+    // SAFETY: both fds are open in the child at this point.
+    if unsafe { libc::dup2(1, 2) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+async fn spawn_process(
+    cmd: Arc<Cmd>,
+    env: Option<&HashMap<String, String>>,
+) -> Result<RunningProcess> {
+    let (program, args) = program_and_args(&cmd);
+    let stderr = if cmd.capture_stderr.unwrap_or(true) {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    };
+    let mut command = Command::new(&program);
+    if let Some(login_shell_env) = LOGIN_SHELL_ENV.read().unwrap().as_ref() {
+        command.envs(login_shell_env);
+    }
+    command
+        .args(args)
+        .envs(&cmd.env)
+        .envs(env.into_iter().flatten());
+
+    let mut pty_master = None;
+    if cmd.use_pty.unwrap_or(false) {
+        let (master, slave) = pty::open()?;
+        let stderr = if cmd.capture_stderr.unwrap_or(true) {
+            Stdio::from(slave.try_clone()?)
+        } else {
+            stderr
+        };
+        command
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave))
+            .stderr(stderr);
+        // SAFETY: set_controlling_terminal only calls async signal safe functions.
+        unsafe {
+            command.pre_exec(pty::set_controlling_terminal);
+        }
+        pty_master = Some(master);
+    } else if cmd.redirect_stderr_to_stdout.unwrap_or(false) {
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        // SAFETY: dup2 is async signal safe.
+        unsafe {
+            command.pre_exec(redirect_stderr_to_stdout);
+        }
+    } else {
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(stderr);
+    }
+
+    let retries = cmd.spawn_retries.unwrap_or(0);
+    let mut backoff = Duration::from_millis(
+        cmd.spawn_retry_base_milli_sec
+            .unwrap_or(DEFAULT_SPAWN_RETRY_BASE_MILLI_SEC),
+    );
+    let mut attempts = 0;
+    let mut child = loop {
+        attempts += 1;
+        match command.spawn() {
+            Ok(child) => break child,
+            Err(e) if attempts <= retries => {
+                tracing::warn!(
+                    cmd_name = %cmd.name,
+                    "failed to spawn {}, retrying in {:?}: {}",
+                    program,
+                    backoff,
+                    e
+                );
+                time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(ProcessManagerError::ProgramNotFound(program))
+            }
+            Err(source) => {
+                return Err(ProcessManagerError::SpawnFailed {
+                    program,
+                    attempts,
+                    source,
+                })
+            }
+        }
+    };
+    metrics().inc_spawn();
+    tracing::info!(cmd_name = %cmd.name, pid = child.id(), "process spawned");
+
+    let stdin: Option<ProcessStdin> = match pty_master.as_ref() {
+        Some(pty_master) => Some(Box::new(pty_master.try_clone()?)),
+        None => child
+            .stdin
+            .take()
+            .map(|stdin| Box::new(stdin) as ProcessStdin),
+    };
+    let recent_output = RecentOutput::new(
+        cmd.recent_output_lines
+            .unwrap_or(DEFAULT_RECENT_OUTPUT_LINES),
+    );
+    let mut running_process = RunningProcess {
+        running_cmd: cmd,
+        pid_entry: None,
+        child,
+        spawned_at: Instant::now(),
+        last_used: Instant::now(),
+        env: env.cloned(),
+        stdin,
+        pty: pty_master,
+        started: false,
+        dirty: false,
+        recent_output,
+    };
+
+    if running_process.running_cmd.persistent.unwrap_or(true) {
+        check_exited_early(&mut running_process).await?;
+    }
+
+    let result = match wait_until_ready(&mut running_process).await {
+        Ok(()) => pass_init_input(&mut running_process).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        terminate_process(
+            &running_process.running_cmd.name,
+            &mut running_process.child,
+            Signal::Kill,
+        )
+        .await;
+        return Err(e);
+    }
+
+    Ok(running_process)
+}
+
+/// Waits briefly for the REPL to fail right after the spawn, e.g. by a missing package, to
+/// report what it printed instead of waiting for the output that never comes.
+async fn check_exited_early(running_process: &mut RunningProcess) -> Result<()> {
+    let wait = Duration::from_millis(EARLY_EXIT_WAIT_MILLI_SEC);
+    let status = match timeout(wait, running_process.child.wait()).await {
+        Ok(Ok(status)) if !status.success() => status,
+        _ => return Ok(()),
+    };
+
+    let name = running_process.running_cmd.name.clone();
+    let mut output = Vec::new();
+    if let Ok(io) = running_process.io(&name) {
+        let reader = io.stderr.unwrap_or(io.stdout);
+        if let Ok(Err(e)) = timeout(wait, reader.read_to_end(&mut output)).await {
+            tracing::warn!("failed to read output of exited process {}: {}", name, e);
+        }
+    }
+    Err(ProcessManagerError::ProcessExitedEarly {
+        name,
+        exit_code: status.code(),
+        output: String::from_utf8_lossy(&output).into_owned(),
+    })
+}
+
+/// Discards the output until it matches `ready_regex`. Fails if it doesn't within the timeout of
+/// the first interaction or the process exits before.
+async fn wait_until_ready(running_process: &mut RunningProcess) -> Result<()> {
+    let cmd = running_process.running_cmd.clone();
+    let ready_regex = match cmd.ready_regex.as_ref() {
+        Some(ready_regex) => BytesRegex::new(ready_regex)?,
+        None => return Ok(()),
+    };
+    let timeout_sec = running_process.next_timeout_sec();
+    let ProcessIo { stdout, stderr, .. } = running_process.io(&cmd.name)?;
+
+    let read_buffer_size = cmd.read_buffer_size.unwrap_or(DEFAULT_READ_BUFFER_SIZE);
+    let mut std_out_read_buf = BytesMut::with_capacity(read_buffer_size);
+    let mut std_out_reader = BufReader::with_capacity(read_buffer_size, stdout);
+    let mut std_err_read_buf = BytesMut::with_capacity(read_buffer_size);
+    let mut std_err_reader =
+        stderr.map(|stderr| BufReader::with_capacity(read_buffer_size, stderr));
+
+    let mut output = Output::new();
+    let mut std_out_closed = false;
+    let mut std_err_closed = std_err_reader.is_none();
+    let probe = async {
+        loop {
+            if std_out_closed && std_err_closed {
+                return Err(ProcessManagerError::ProcessNotRunning(cmd.name.clone()));
+            }
+            select! {
+                chunk = read_output_chunk(Some(&mut std_out_reader), &mut std_out_read_buf, "stdout"), if !std_out_closed => {
+                    match chunk? {
+                        Some(chunk) => output.extend_from_slice(&chunk),
+                        None => std_out_closed = true,
+                    }
+                }
+                chunk = read_output_chunk(std_err_reader.as_mut(), &mut std_err_read_buf, "stderr"), if !std_err_closed => {
+                    match chunk? {
+                        Some(chunk) => output.extend_from_slice(&chunk),
+                        None => std_err_closed = true,
+                    }
+                }
+            }
+            if ready_regex.is_match(&output) {
+                return Ok(());
+            }
+        }
+    };
+
+    match timeout(Duration::from_secs(timeout_sec), probe).await {
+        Ok(Ok(())) => {
+            tracing::debug!("process is ready: {}", cmd.name);
+            Ok(())
+        }
+        Ok(Err(e)) => Err(e),
+        Err(e) => {
+            tracing::warn!(
+                "output didn't match ready_regex in {} sec: {}",
+                timeout_sec,
+                cmd.name
+            );
+            Err(e.into())
+        }
+    }
+}
+
+/// Passes `init_input` and discards its output so that it won't leak into the output of the
+/// first input. The init input is expected to print something (e.g. the prompt), otherwise
+/// this waits until `spawn_timeout_sec` elapsed.
+async fn pass_init_input(running_process: &mut RunningProcess) -> Result<()> {
+    match running_process.running_cmd.init_input.clone() {
+        Some(init_input) => pass_input_discarding_output(running_process, init_input, "init").await,
+        None => Ok(()),
+    }
+}
+
+/// Passes the input and drains its output. The process is kept even if it prints nothing
+/// before the timeout, which is only logged.
+async fn pass_input_discarding_output(
+    running_process: &mut RunningProcess,
+    input: Input,
+    purpose: &str,
+) -> Result<()> {
+    let cmd = running_process.running_cmd.clone();
+    let timeout_sec = running_process.next_timeout_sec();
+
+    let result = timeout(
+        Duration::from_secs(timeout_sec),
+        pass_input_to_process(
+            &cmd.name,
+            running_process.io(&cmd.name)?,
+            CmdInput::Text(input),
+            cmd.output_size,
+            None,
+            None,
+            &cmd,
+        ),
+    )
+    .await;
+
+    match result {
+        Ok(Ok(output)) => {
+            tracing::debug!(
+                "discarded {} bytes of {} output: {}",
+                output.len(),
+                purpose,
+                cmd.name
+            );
+            Ok(())
+        }
+        Ok(Err(e)) => Err(e),
+        Err(_) => {
+            tracing::warn!(
+                "no output for {} input in {} sec: {}",
+                purpose,
+                timeout_sec,
+                cmd.name
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use sysinfo::ProcessStatus;
+
+    #[test]
+    fn test_arrange_input() {
+        {
+            let input = arrange_input(
+                "aaa\nbbb".to_string(),
+                false,
+                Some(&";".to_string()),
+                None,
+                false,
+                DEFAULT_INPUT_PIPELINE,
+                "\n",
+            );
+            assert_eq!("aaa;bbb".to_string(), input.unwrap());
+        }
+
+        {
+            let input = arrange_input(
+                "
+                # sss
+                aaa # ddd
+ \n\nbbb"
+                    .to_string(),
+                true,
+                Some(&";".to_string()),
+                Some(&"#.*".to_string()),
+                false,
+                DEFAULT_INPUT_PIPELINE,
+                "\n",
+            );
+            assert_eq!(
+                ";                ;                aaa ; ;;bbb\n".to_string(),
+                input.unwrap()
+            );
+        }
+
+        {
+            let input = arrange_input(
+                "
+                # sss
+                aaa # ddd
+ \n\nbbb"
+                    .to_string(),
+                true,
+                Some(&";".to_string()),
+                Some(&"#.*".to_string()),
+                true,
+                DEFAULT_INPUT_PIPELINE,
+                "\n",
+            );
+            assert_eq!("                aaa ;bbb\n".to_string(), input.unwrap());
+        }
+
+        {
+            let input = arrange_input(
+                "aaa\r\n \r\n\r\nbbb\r\n".to_string(),
+                true,
+                Some(&";".to_string()),
+                None,
+                true,
+                DEFAULT_INPUT_PIPELINE,
+                "\n",
+            );
+            assert_eq!("aaa;bbb\n".to_string(), input.unwrap());
+        }
+
+        {
+            let input = arrange_input(
+                "aaa # ddd\r\nbbb".to_string(),
+                false,
+                None,
+                Some(&"#.*".to_string()),
+                false,
+                DEFAULT_INPUT_PIPELINE,
+                "\n",
+            );
+            assert_eq!("aaa \nbbb".to_string(), input.unwrap());
+        }
+
+        {
+            // joined before truncated, then the comment eats the rest
+            let pipeline = [InputStep::JoinInputNewline, InputStep::TruncateLine];
+            let input = "aaa # ddd\nbbb".to_string();
+            let arrange = |pipeline: &[InputStep]| {
+                arrange_input(
+                    input.clone(),
+                    false,
+                    Some(&";".to_string()),
+                    Some(&"#.*".to_string()),
+                    false,
+                    pipeline,
+                    "\n",
+                )
+                .unwrap()
+            };
+            assert_eq!("aaa ;bbb", arrange(DEFAULT_INPUT_PIPELINE));
+            assert_eq!("aaa ", arrange(&pipeline));
+            assert_eq!("aaa # ddd\nbbb", arrange(&[]));
+        }
+    }
+
+    #[test]
+    fn test_arrange_cmd_input() {
+        let cmd = Cmd {
+            auto_trailing_newline: true,
+            join_input_newline_with: Some("; ".to_string()),
+            truncate_line_regex: Some("#.*".to_string()),
+            remove_empty_line: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            "x = 1 ; y = 2\n",
+            arrange_cmd_input(&cmd, "x = 1 # one\n\ny = 2".to_string()).unwrap()
+        );
+
+        let cmd = Cmd {
+            input_newline: Some("\r\n".to_string()),
+            ..cmd
+        };
+        assert_eq!(
+            "x = 1 ; y = 2\r\n",
+            arrange_cmd_input(&cmd, "x = 1 # one\n\ny = 2".to_string()).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_input_newline() {
+        let cmd = Arc::new(Cmd {
+            name: "od".to_string(),
+            cmd: "od -An -c".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(5000),
+            persistent: Some(false),
+            input_newline: Some("\r\n".to_string()),
+            input_encoding: Some(InputEncoding::Latin1),
+            ..Default::default()
+        });
+
+        let (output, _) = run_one_shot(
+            &TokioProcessBackend,
+            &cmd.name,
+            &cmd,
+            "a\né".to_string().into(),
+            &RunOptions::default(),
+        )
+        .await
+        .unwrap();
+        let chars: Vec<String> = String::from_utf8(output)
+            .unwrap()
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+        assert_eq!(vec!["a", "\\r", "\\n", "351", "\\r", "\\n"], chars);
+
+        assert!(matches!(
+            run_one_shot(
+                &TokioProcessBackend,
+                &cmd.name,
+                &cmd,
+                "α".to_string().into(),
+                &RunOptions::default()
+            )
+            .await,
+            Err(ProcessManagerError::InputNotEncodable { ch: 'α', .. })
+        ));
+    }
+
+    #[test]
+    fn test_strip_ansi_escapes() {
+        assert_eq!(
+            b"ERROR: x\n".to_vec(),
+            strip_ansi_escapes(b"\x1b[31mERROR\x1b[0m: x\n")
+        );
+        assert_eq!(
+            b"julia> 2".to_vec(),
+            strip_ansi_escapes(b"\x1b]0;julia\x07\x1b[?2004hjulia> \x1b[1;32m2\x1b[K")
+        );
+        assert_eq!(b"a".to_vec(), strip_ansi_escapes(b"\x1b]0;x\x1b\\a\x1b[31"));
+        assert_eq!(b"a\xff".to_vec(), strip_ansi_escapes(b"a\xff\x1b"));
+
+        let cmd = Cmd {
+            strip_ansi: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            b"julia> 2\n".to_vec(),
+            arrange_output(b"\x1b[32mjulia> \x1b[0m2\n".to_vec(), &cmd)
+                .unwrap()
+                .output
+        );
+    }
+
+    #[test]
+    fn test_filter_output() {
+        let output = b"\x1b[32mjulia> \x1b[0m2\n\x1b[1;31mERROR\x1b[0m: x\n".to_vec();
+        let cmd = Cmd {
+            output_filter_regex: Some(r"\x1b\[[0-9;]*m".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            b"julia> 2\nERROR: x\n".to_vec(),
+            arrange_output(output.clone(), &cmd).unwrap().output
+        );
+
+        let cmd = Cmd {
+            output_filter_regex: Some(r"(?m)^\x1b\[32mjulia> \x1b\[0m".to_string()),
+            output_replace: Some("> ".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            b"> 2\n\x1b[1;31mERROR\x1b[0m: x\n".to_vec(),
+            arrange_output(output, &cmd).unwrap().output
+        );
+    }
+
+    #[test]
+    fn test_max_output_lines() {
+        let cmd = Cmd {
+            max_output_lines: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            b"1\n2\n... 2 more lines\n".to_vec(),
+            arrange_output(b"1\n2\n3\n4".to_vec(), &cmd).unwrap().output
+        );
+        assert_eq!(
+            b"1\n2\n".to_vec(),
+            arrange_output(b"1\n2\n".to_vec(), &cmd).unwrap().output
+        );
+        assert_eq!(
+            b"... 1 more lines\n".to_vec(),
+            truncate_lines(b"1".to_vec(), 0)
+        );
+    }
+
+    #[test]
+    fn test_arrange_output() {
+        let output = b"julia> 2\njulia> \njulia> 4\njulia> ".to_vec();
+
+        let cmd = Cmd {
+            prompt_regex: Some("julia> ".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(output, arrange_output(output.clone(), &cmd).unwrap().output);
+
+        let cmd = Cmd {
+            prompt_regex: Some("julia> ".to_string()),
+            prompt_output: Some(PromptOutput::Joined),
+            ..Default::default()
+        };
+        assert_eq!(
+            CmdOutput {
+                output: b"2\n4\n".to_vec(),
+                segments: None,
+                encoding: OutputEncoding::Utf8Lossy,
+                exit_code: None,
+                output_bytes: 0,
+                elapsed: Duration::ZERO,
+                is_error: false,
+            },
+            arrange_output(output.clone(), &cmd).unwrap()
+        );
+
+        let cmd = Cmd {
+            prompt_regex: Some("julia> ".to_string()),
+            prompt_output: Some(PromptOutput::List),
+            ..Default::default()
+        };
+        assert_eq!(
+            CmdOutput {
+                output: b"2\n4\n".to_vec(),
+                segments: Some(vec![b"2".to_vec(), b"4".to_vec()]),
+                encoding: OutputEncoding::Utf8Lossy,
+                exit_code: None,
+                output_bytes: 0,
+                elapsed: Duration::ZERO,
+                is_error: false,
+            },
+            arrange_output(output, &cmd).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_error_regex() {
+        let cmd = Cmd {
+            error_regex: Some("(?m)^(ERROR:|Traceback)".to_string()),
+            max_output_lines: Some(1),
+            ..Default::default()
+        };
+        let output = b"1\nERROR: UndefVarError: x not defined\n".to_vec();
+        let cmd_output = arrange_output(output, &cmd).unwrap();
+        assert!(cmd_output.is_error);
+        // matched before max_output_lines drops the ERROR line
+        assert_eq!(b"1\n... 1 more lines\n".to_vec(), cmd_output.output);
+
+        assert!(!arrange_output(b"2\n".to_vec(), &cmd).unwrap().is_error);
+        assert!(
+            !arrange_output(b"ERROR:".to_vec(), &Cmd::default())
+                .unwrap()
+                .is_error
+        );
+    }
+
+    #[test]
+    fn test_skip_leading_lines() {
+        assert_eq!(b"".to_vec(), skip_leading_lines(b"1+1\n", 2));
+        assert_eq!(b"".to_vec(), skip_leading_lines(b"1+1\n2", 2));
+        assert_eq!(b"".to_vec(), skip_leading_lines(b"1+1\n2\n", 2));
+        assert_eq!(b"3\n".to_vec(), skip_leading_lines(b"1+1\n2\n3\n", 2));
+        assert_eq!(b"1+1\n".to_vec(), skip_leading_lines(b"1+1\n", 0));
+
+        let cmd = Cmd {
+            skip_leading_output_lines: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            b"2\n".to_vec(),
+            arrange_output(b"1+1\n2\n".to_vec(), &cmd).unwrap().output
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timeout_kills_child() {
+        let cmd = Arc::new(Cmd {
+            name: "sh".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            timeout_sec: Some(1),
+            timeout_signal: Some(Signal::Term),
+            ..Default::default()
+        });
+        let name = cmd.name.clone();
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+        let pid = proceses[&name].child.id().unwrap();
+
+        let result = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            "sleep 30".to_string().into(),
+            &RunOptions::default(),
+        )
+        .await;
+        assert!(matches!(result, Err(ProcessManagerError::Timeout(_))));
+
+        assert!(!proceses.contains_key(&name));
+        // the child has been reaped, so the pid no longer exists
+        assert_ne!(0, unsafe { libc::kill(pid as libc::pid_t, 0) });
+    }
+
+    #[tokio::test]
+    async fn test_use_pty() {
+        for (use_pty, expected) in [(None, "pipe\n"), (Some(true), "tty\n")] {
+            let cmd = Arc::new(Cmd {
+                name: "isatty".to_string(),
+                cmd: "while read line; do if [ -t 1 ]; then echo tty; else echo pipe; fi; done"
+                    .to_string(),
+                use_shell: true,
+                output_size: 1024,
+                auto_trailing_newline: true,
+                wait_output_timeout_milli_sec: Some(200),
+                use_pty,
+                ..Default::default()
+            });
+
+            let mut proceses = ProcessTable::new();
+            add_to_process_table(
+                &mut proceses,
+                spawn_process(cmd.clone(), None).await.unwrap(),
+            )
+            .unwrap();
+
+            for _ in 0..2 {
+                let output = run_cmd_in_process_table(
+                    &TokioProcessBackend,
+                    &mut proceses,
+                    &cmd,
+                    "".to_string().into(),
+                    &RunOptions::default(),
+                )
+                .await;
+                assert_eq!(expected.as_bytes().to_vec(), output.unwrap());
+            }
+
+            terminate_all(&mut proceses).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spawn_timeout() {
+        let cmd = Arc::new(Cmd {
+            name: "slow_start".to_string(),
+            cmd: "sleep 1.5; cat".to_string(),
+            use_shell: true,
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(200),
+            timeout_sec: Some(1),
+            spawn_timeout_sec: Some(5),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+
+        for input in ["first", "second"] {
+            let output = run_cmd_in_process_table(
+                &TokioProcessBackend,
+                &mut proceses,
+                &cmd,
+                input.to_string().into(),
+                &RunOptions::default(),
+            )
+            .await;
+            assert_eq!(format!("{}\n", input).into_bytes(), output.unwrap());
+        }
+
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_exited_without_output() {
+        let cmd = Arc::new(Cmd {
+            name: "head".to_string(),
+            cmd: "head -n 1 > /dev/null".to_string(),
+            use_shell: true,
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(5000),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+
+        let started_at = Instant::now();
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            "1 + 1".to_string().into(),
+            &RunOptions::default(),
+        )
+        .await;
+        assert_eq!(Output::new(), output.unwrap());
+        assert!(started_at.elapsed() < Duration::from_secs(2));
+
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_output_finished_after_wait_duration() {
+        // with the time paused, it advances only when every task waits for it
+        time::pause();
+        let backend = MockBackend::default();
+        let cmd = Arc::new(Cmd {
+            name: "mock".to_string(),
+            cmd: "mock".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(250),
+            ..Default::default()
+        });
+
+        let started_at = Instant::now();
+        assert_eq!("1001:hello\n", run_on_mock(&backend, &cmd, "hello").await);
+        // returns right after the idle window instead of on the next periodic check
+        let elapsed = started_at.elapsed();
+        assert!(elapsed >= Duration::from_millis(250), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(300), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_find_sentinel() {
+        assert_eq!(Some(2), find_sentinel(b"a\n__END__\n", b"__END__", 0));
+        assert_eq!(Some(2), find_sentinel(b"a\n__END__\n", b"__END__", 2));
+        assert_eq!(None, find_sentinel(b"a\n__END__\n", b"__END__", 3));
+        assert_eq!(None, find_sentinel(b"a\n__EN", b"__END__", 0));
+        assert_eq!(None, find_sentinel(b"a", b"__END__", 5));
+    }
+
+    #[tokio::test]
+    async fn test_output_sentinel() {
+        let cmd = Arc::new(Cmd {
+            name: "sentinel".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(3000),
+            output_sentinel: Some("__END__".to_string()),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+
+        // the sentinel split between the writes is found too
+        let started_at = Instant::now();
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            "echo a; printf __EN; sleep 0.1; echo D__"
+                .to_string()
+                .into(),
+            &RunOptions::default(),
+        )
+        .await;
+        assert_eq!(b"a\n".to_vec(), output.unwrap());
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+
+        // falls back to the pause without the sentinel
+        let started_at = Instant::now();
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            "echo b".to_string().into(),
+            &RunOptions {
+                wait_output_timeout_milli_sec: Some(200),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(b"b\n".to_vec(), output.unwrap());
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_output_chunks() {
+        let cmd = Arc::new(Cmd {
+            name: "chunks".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(3000),
+            output_sentinel: Some("__END__".to_string()),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+
+        // each chunk is sent as it's read, without the sentinel split between the writes
+        let (chunk_sender, mut chunks) = mpsc::unbounded_channel();
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            "echo a; sleep 0.2; printf 'b\\n__EN'; sleep 0.2; echo D__"
+                .to_string()
+                .into(),
+            &RunOptions {
+                output_chunks: Some(OutputChunks(chunk_sender)),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(b"a\nb\n".to_vec(), output.unwrap());
+        let mut received = vec![];
+        while let Some(chunk) = chunks.recv().await {
+            received.push(chunk);
+        }
+        assert_eq!(vec![b"a\n".to_vec(), b"b\n".to_vec()], received);
+
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_one_shot() {
+        let cmd = Arc::new(Cmd {
+            name: "rev".to_string(),
+            cmd: "rev".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(5000),
+            persistent: Some(false),
+            ..Default::default()
+        });
+
+        for (input, expected) in [("dairi", "iriad\n"), ("julia\nrepl", "ailuj\nlper\n")] {
+            let started_at = Instant::now();
+            let output = run_one_shot(
+                &TokioProcessBackend,
+                &cmd.name,
+                &cmd,
+                input.to_string().into(),
+                &RunOptions::default(),
+            )
+            .await;
+            assert_eq!((expected.as_bytes().to_vec(), None), output.unwrap());
+            assert!(started_at.elapsed() < Duration::from_secs(2));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_one_shot_closes_stdin() {
+        for use_pty in [None, Some(true)] {
+            for auto_trailing_newline in [true, false] {
+                let cmd = Arc::new(Cmd {
+                    name: "wc".to_string(),
+                    cmd: "wc -l".to_string(),
+                    output_size: 1024,
+                    auto_trailing_newline,
+                    wait_output_timeout_milli_sec: Some(5000),
+                    persistent: Some(false),
+                    use_pty,
+                    ..Default::default()
+                });
+
+                let started_at = Instant::now();
+                let (output, _) = run_one_shot(
+                    &TokioProcessBackend,
+                    &cmd.name,
+                    &cmd,
+                    "a\nb\nc".to_string().into(),
+                    &RunOptions::default(),
+                )
+                .await
+                .unwrap();
+                let expected = if auto_trailing_newline { "3" } else { "2" };
+                assert_eq!(expected, String::from_utf8(output).unwrap().trim());
+                assert!(started_at.elapsed() < Duration::from_secs(2));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_exit() {
+        for (wait_for_exit, expected) in [(None, ("a\n", None)), (Some(true), ("a\nb\n", Some(3)))]
+        {
+            let cmd = Arc::new(Cmd {
+                name: "sh".to_string(),
+                cmd: "cat > /dev/null; echo a; sleep 0.5; echo b; exit 3".to_string(),
+                use_shell: true,
+                output_size: 1024,
+                auto_trailing_newline: true,
+                wait_output_timeout_milli_sec: Some(100),
+                persistent: Some(false),
+                wait_for_exit,
+                ..Default::default()
+            });
+
+            let output = run_one_shot(
+                &TokioProcessBackend,
+                &cmd.name,
+                &cmd,
+                "input".to_string().into(),
+                &RunOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!((expected.0.as_bytes().to_vec(), expected.1), output);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lock_cmd() {
+        let name = "test_lock_cmd".to_string();
+
+        let guard = lock_cmd(&name, ConcurrentRequestPolicy::Reject)
+            .await
+            .unwrap();
+        assert!(matches!(
+            lock_cmd(&name, ConcurrentRequestPolicy::Reject).await,
+            Err(ProcessManagerError::CmdBusy(_))
+        ));
+        // the other cmds aren't blocked
+        lock_cmd(&"other".to_string(), ConcurrentRequestPolicy::Reject)
+            .await
+            .unwrap();
+
+        let mut queued = tokio::spawn({
+            let name = name.clone();
+            async move {
+                let _guard = lock_cmd(&name, ConcurrentRequestPolicy::Queue)
+                    .await
+                    .unwrap();
+            }
+        });
+        assert!(timeout(Duration::from_millis(100), &mut queued)
+            .await
+            .is_err());
+
+        drop(guard);
+        timeout(Duration::from_secs(1), queued)
+            .await
+            .unwrap()
+            .unwrap();
+        lock_cmd(&name, ConcurrentRequestPolicy::Reject)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_strip_echo() {
+        assert_eq!(
+            b"2\n".to_vec(),
+            strip_echo(b"1 + 1\n2\n".to_vec(), b"1 + 1\n")
+        );
+        assert_eq!(
+            b"2\r\n".to_vec(),
+            strip_echo(b"1 + 1\r\n2\r\n".to_vec(), b"1 + 1\n")
+        );
+        assert_eq!(b"2\n".to_vec(), strip_echo(b"2\n".to_vec(), b"1 + 1\n"));
+        assert_eq!(b"1 +".to_vec(), strip_echo(b"1 +".to_vec(), b"1 + 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_strip_input_echo() {
+        let cmd = Arc::new(Cmd {
+            name: "echo_repl".to_string(),
+            cmd: r#"while read line; do echo "$line"; echo "=> ${#line}"; done"#.to_string(),
+            use_shell: true,
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(200),
+            strip_input_echo: Some(true),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            "hello".to_string().into(),
+            &RunOptions::default(),
+        )
+        .await;
+        assert_eq!(b"=> 5\n".to_vec(), output.unwrap());
+
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_init_input() {
+        let cmd = Arc::new(Cmd {
+            name: "sh".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(200),
+            init_input: Some("x=42; echo initialized".to_string()),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            "echo $x".to_string().into(),
+            &RunOptions::default(),
+        )
+        .await;
+        assert_eq!(b"42\n".to_vec(), output.unwrap());
+
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_ready_regex() {
+        let repl_cmd = |cmd: &str, spawn_timeout_sec: u64| {
+            Arc::new(Cmd {
+                name: "repl".to_string(),
+                cmd: cmd.to_string(),
+                use_shell: true,
+                output_size: 1024,
+                auto_trailing_newline: true,
+                wait_output_timeout_milli_sec: Some(100),
+                ready_regex: Some("ready> $".to_string()),
+                spawn_timeout_sec: Some(spawn_timeout_sec),
+                ..Default::default()
+            })
+        };
+
+        // a slow REPL which prints a banner and then the prompt
+        let cmd = repl_cmd("echo banner; sleep 0.5; printf 'ready> '; cat", 5);
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            "hello".to_string().into(),
+            &RunOptions::default(),
+        )
+        .await;
+        assert_eq!(b"hello\n".to_vec(), output.unwrap());
+        terminate_all(&mut proceses).await;
+
+        assert!(matches!(
+            spawn_process(repl_cmd("echo banner; cat", 1), None).await,
+            Err(ProcessManagerError::Timeout(_))
+        ));
+
+        assert!(matches!(
+            spawn_process(repl_cmd("echo banner", 5), None).await,
+            Err(ProcessManagerError::ProcessNotRunning(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_restart_after_cancel() {
+        let cmd = Arc::new(Cmd {
+            name: "sh".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(100),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+        let pid = proceses[&cmd.name].child.id();
+
+        // dropped while waiting for the output
+        let cancelled = timeout(
+            Duration::from_millis(100),
+            run_cmd_in_process_table(
+                &TokioProcessBackend,
+                &mut proceses,
+                &cmd,
+                "sleep 0.5; echo cancelled".to_string().into(),
+                &RunOptions::default(),
+            ),
+        )
+        .await;
+        assert!(cancelled.is_err());
+        assert!(proceses[&cmd.name].dirty);
+        time::sleep(Duration::from_millis(500)).await;
+
+        // the output of the cancelled input doesn't leak
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            "echo hello".to_string().into(),
+            &RunOptions::default(),
+        )
+        .await;
+        assert_eq!(b"hello\n".to_vec(), output.unwrap());
+        assert_ne!(pid, proceses[&cmd.name].child.id());
+        assert!(!proceses[&cmd.name].dirty);
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_max_lifetime() {
+        let cmd = Arc::new(Cmd {
+            name: "sh".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(100),
+            max_lifetime_sec: Some(1),
+            ..Default::default()
+        });
+        let mut env = HashMap::new();
+        env.insert("DAIRI_TEST".to_string(), "kept".to_string());
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), Some(&env)).await.unwrap(),
+        )
+        .unwrap();
+        let pid = proceses[&cmd.name].child.id();
+
+        assert_eq!(
+            b"kept\n".to_vec(),
+            run_cmd_in_process_table(
+                &TokioProcessBackend,
+                &mut proceses,
+                &cmd,
+                "echo $DAIRI_TEST".to_string().into(),
+                &RunOptions::default(),
+            )
+            .await
+            .unwrap()
+        );
+        assert_eq!(pid, proceses[&cmd.name].child.id());
+
+        time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(
+            b"kept\n".to_vec(),
+            run_cmd_in_process_table(
+                &TokioProcessBackend,
+                &mut proceses,
+                &cmd,
+                "echo $DAIRI_TEST".to_string().into(),
+                &RunOptions::default(),
+            )
+            .await
+            .unwrap()
+        );
+        assert_ne!(pid, proceses[&cmd.name].child.id());
+        terminate_all(&mut proceses).await;
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2, now);
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+
+        // refilled by one token in 500ms
+        let now = now + Duration::from_millis(500);
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+
+        // up to the burst size
+        let now = now + Duration::from_secs(10);
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+    }
+
+    #[test]
+    fn test_check_rate_limit() {
+        let cmd = Cmd {
+            name: "rate_limited".to_string(),
+            rate_limit_per_sec: Some(3),
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            assert!(check_rate_limit(&cmd).is_ok());
+        }
+        assert!(matches!(
+            check_rate_limit(&cmd),
+            Err(ProcessManagerError::RateLimited {
+                rate_limit_per_sec: 3,
+                ..
+            })
+        ));
+
+        let unlimited = Cmd {
+            name: "unlimited".to_string(),
+            ..Default::default()
+        };
+        for _ in 0..10 {
+            assert!(check_rate_limit(&unlimited).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_debounce() {
+        let cmd = Arc::new(Cmd {
+            name: "debounced".to_string(),
+            debounce_milli_sec: Some(200),
+            ..Default::default()
+        });
+
+        let mut runs = Vec::new();
+        for _ in 0..3 {
+            let cmd = cmd.clone();
+            runs.push(tokio::spawn(async move { debounce(&cmd).await }));
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut results = Vec::new();
+        for run in runs {
+            results.push(run.await.unwrap());
+        }
+        // only the latest one runs
+        for result in &results[..2] {
+            assert!(matches!(result, Err(ProcessManagerError::Superseded(_))));
+        }
+        assert!(results[2].is_ok());
+
+        // a request after the window isn't affected by the older ones
+        debounce(&cmd).await.unwrap();
+        debounce(&Cmd::default()).await.unwrap();
+    }
+
+    #[test]
+    fn test_parse_env() {
+        let env = parse_env(b"PATH=/usr/bin:/opt/bin\nEMPTY=\nEQ=a=b\nmulti\n=x\n");
+        assert_eq!(3, env.len());
+        assert_eq!("/usr/bin:/opt/bin", env["PATH"]);
+        assert_eq!("", env["EMPTY"]);
+        assert_eq!("a=b", env["EQ"]);
+    }
+
+    #[tokio::test]
+    async fn test_login_shell_env() {
+        set_login_shell_env(Some(HashMap::from([
+            ("DAIRI_LOGIN_ONLY".to_string(), "login".to_string()),
+            ("DAIRI_LOGIN_AND_CMD".to_string(), "login".to_string()),
+        ])));
+        let cmd = Arc::new(Cmd {
+            name: "login_shell_env".to_string(),
+            cmd: "echo $DAIRI_LOGIN_ONLY $DAIRI_LOGIN_AND_CMD".to_string(),
+            use_shell: true,
+            output_size: 1024,
+            wait_output_timeout_milli_sec: Some(5000),
+            persistent: Some(false),
+            env: HashMap::from([("DAIRI_LOGIN_AND_CMD".to_string(), "cmd".to_string())]),
+            ..Default::default()
+        });
+
+        // the env of the cmd overrides the login shell
+        let (output, _) = run_one_shot(
+            &TokioProcessBackend,
+            &cmd.name,
+            &cmd,
+            "".to_string().into(),
+            &RunOptions::default(),
+        )
+        .await
+        .unwrap();
+        set_login_shell_env(None);
+        assert_eq!(b"login cmd\n".to_vec(), output);
+    }
+
+    #[test]
+    fn test_recent_output() {
+        let mut recent_output = RecentOutput::new(3);
+        assert!(recent_output.last(10).is_empty());
+        recent_output.push(b"a\nb\n");
+        recent_output.push(b"c\nd");
+        assert_eq!(vec!["b", "c", "d"], recent_output.last(10));
+        assert_eq!(vec!["d"], recent_output.last(1));
+
+        let mut disabled = RecentOutput::new(0);
+        disabled.push(b"a\n");
+        assert!(disabled.last(10).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reset_process() {
+        let cmd = Arc::new(Cmd {
+            name: "sh".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(200),
+            reset_input: Some("unset x; echo reset".to_string()),
+            ..Default::default()
+        });
+        let name = cmd.name.clone();
+
+        let mut proceses = ProcessTable::new();
+        assert!(matches!(
+            reset_process_in_table(&mut proceses, &name, "echo reset".to_string()).await,
+            Err(ProcessManagerError::ProcessNotRunning(_))
+        ));
+
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+        let pid = proceses[&name].child.id();
+
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            "x=42; echo ${x:-unset}".to_string().into(),
+            &RunOptions::default(),
+        )
+        .await;
+        assert_eq!(b"42\n".to_vec(), output.unwrap());
+
+        reset_process_in_table(&mut proceses, &name, cmd.reset_input.clone().unwrap())
+            .await
+            .unwrap();
+
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            "echo ${x:-unset}".to_string().into(),
+            &RunOptions::default(),
+        )
+        .await;
+        assert_eq!(b"unset\n".to_vec(), output.unwrap());
+        assert_eq!(pid, proceses[&name].child.id());
+
+        terminate_all(&mut proceses).await;
+    }
+
+    #[test]
+    fn test_program_and_args() {
+        let cmd = Cmd {
+            cmd: "julia --quiet  --color=no".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            (
+                "julia".to_string(),
+                vec!["--quiet".to_string(), "--color=no".to_string()]
+            ),
+            program_and_args(&cmd)
+        );
+
+        let cmd = Cmd {
+            cmd: "cat | tr a-z A-Z".to_string(),
+            use_shell: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            (
+                "/bin/sh".to_string(),
+                vec!["-c".to_string(), "cat | tr a-z A-Z".to_string()]
+            ),
+            program_and_args(&cmd)
+        );
+
+        let cmd = Cmd {
+            cmd: "julia --quiet".to_string(),
+            args: vec!["--banner=no".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            (
+                "julia".to_string(),
+                vec!["--quiet".to_string(), "--banner=no".to_string()]
+            ),
+            program_and_args(&cmd)
+        );
+
+        let cmd = Cmd {
+            cmd: "echo \"$1\"".to_string(),
+            use_shell: true,
+            args: vec!["hello".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            (
+                "/bin/sh".to_string(),
+                vec![
+                    "-c".to_string(),
+                    "echo \"$1\"".to_string(),
+                    "/bin/sh".to_string(),
+                    "hello".to_string()
+                ]
+            ),
+            program_and_args(&cmd)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_env() {
+        let cmd = Arc::new(Cmd {
+            name: "sh".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(200),
+            ..Default::default()
+        });
+        let request_env = |greeting: &str, respawn: bool| RequestEnv {
+            vars: HashMap::from([("GREETING".to_string(), greeting.to_string())]),
+            respawn,
+        };
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), Some(&request_env("hello", false).vars))
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+
+        for (env, expected) in [
+            (request_env("hello", false), "hello\n"),
+            (request_env("bye", false), "hello\n"),
+            (request_env("bye", true), "bye\n"),
+        ] {
+            let input = "echo $GREETING".to_string();
+            let output = run_cmd_in_process_table(
+                &TokioProcessBackend,
+                &mut proceses,
+                &cmd,
+                input.into(),
+                &RunOptions {
+                    env: Some(env),
+                    ..Default::default()
+                },
+            )
+            .await;
+            assert_eq!(expected.as_bytes().to_vec(), output.unwrap());
+        }
+
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_wait_output_timeout_override() {
+        let cmd = Arc::new(Cmd {
+            name: "sh".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(200),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+
+        let input = "echo a; sleep 0.5; echo b".to_string();
+        let options = RunOptions {
+            wait_output_timeout_milli_sec: Some(1000),
+            ..Default::default()
+        };
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            input.clone().into(),
+            &options,
+        )
+        .await;
+        assert_eq!(b"a\nb\n".to_vec(), output.unwrap());
+
+        let options = RunOptions::default();
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            input.into(),
+            &options,
+        )
+        .await;
+        assert_eq!(b"a\n".to_vec(), output.unwrap());
+
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_raw_input() {
+        let cmd = Arc::new(Cmd {
+            name: "cat".to_string(),
+            cmd: "cat".to_string(),
+            output_size: 1024,
+            // not applied to the raw input
+            join_input_newline_with: Some(";".to_string()),
+            wait_output_timeout_milli_sec: Some(200),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+
+        let chunks = vec![
+            Ok(Bytes::from_static(b"1+1\n")),
+            Ok(Bytes::from_static(b"2+2\n")),
+        ];
+        let input = CmdInput::Raw(Box::pin(futures::stream::iter(chunks)));
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            input,
+            &RunOptions::default(),
+        )
+        .await;
+        assert_eq!(b"1+1\n2+2\n".to_vec(), output.unwrap());
+
+        terminate_all(&mut proceses).await;
+    }
+
+    #[test]
+    fn test_effective_output_size() {
+        let name = "julia".to_string();
+        let cmd = Cmd {
+            output_size: 1024,
+            ..Default::default()
+        };
+        let requested = RunOptions {
+            output_size: Some(1 << 30),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            1024,
+            effective_output_size(&name, &RunOptions::default(), &cmd, Some(4096))
+        );
+        assert_eq!(
+            4096,
+            effective_output_size(&name, &requested, &cmd, Some(4096))
+        );
+        assert_eq!(
+            1 << 30,
+            effective_output_size(&name, &requested, &cmd, None)
+        );
+        assert_eq!(
+            512,
+            effective_output_size(&name, &RunOptions::default(), &cmd, Some(512))
+        );
+    }
+
+    #[test]
+    fn test_append_output() {
+        let mut result = Output::new();
+        assert!(!append_output(&mut result, b"hello", 8, true));
+        assert_eq!(b"hello".to_vec(), result);
+        assert!(append_output(&mut result, b" world", 8, true));
+        assert_eq!(b"hello wo".to_vec(), result);
+        assert!(append_output(&mut result, b"!", 8, true));
+        assert_eq!(b"hello wo".to_vec(), result);
+
+        // "é" is 2 bytes and "あ" is 3 bytes, cut before the char over the limit
+        let mut result = Output::new();
+        assert!(append_output(&mut result, "éあ".as_bytes(), 4, true));
+        assert_eq!("é".as_bytes().to_vec(), result);
+        let mut result = Output::new();
+        assert!(append_output(&mut result, "éあ".as_bytes(), 4, false));
+        assert_eq!(b"\xc3\xa9\xe3\x81".to_vec(), result);
+    }
+
+    #[test]
+    fn test_hold_incomplete_char() {
+        assert_eq!(0, incomplete_char_len("aé".as_bytes()));
+        assert_eq!(1, incomplete_char_len(b"a\xc3"));
+        assert_eq!(2, incomplete_char_len(b"\xe3\x81"));
+        assert_eq!(0, incomplete_char_len(b"\x81\x81\x81\x81\x81"));
+
+        let mut pending = Output::new();
+        let bytes = "あ".as_bytes();
+        assert_eq!(
+            b"a".to_vec(),
+            hold_incomplete_char(&mut pending, [b"a", &bytes[..1]].concat(), true)
+        );
+        assert_eq!(
+            Output::new(),
+            hold_incomplete_char(&mut pending, bytes[1..2].to_vec(), true)
+        );
+        assert_eq!(
+            "あb".as_bytes().to_vec(),
+            hold_incomplete_char(&mut pending, [&bytes[2..], b"b"].concat(), true)
+        );
+        assert!(pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_multibyte_output() {
+        let cmd = Arc::new(Cmd {
+            name: "sh".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 8,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(300),
+            output_encoding: Some(OutputEncoding::Utf8Strict),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+
+        // cut at 8 bytes in the middle of the third "あ"
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            "printf 'あああ'".to_string().into(),
+            &RunOptions::default(),
+        )
+        .await;
+        assert_eq!("ああ", String::from_utf8(output.unwrap()).unwrap());
+
+        // the first half of a char on stdout isn't followed by stderr
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            r"printf '\343'; sleep 0.1; printf '!' >&2; sleep 0.1; printf '\201\202'"
+                .to_string()
+                .into(),
+            &RunOptions {
+                output_size: Some(1024),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!("!あ", String::from_utf8(output.unwrap()).unwrap());
+
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_read_buffer_size() {
+        let cmd = Arc::new(Cmd {
+            name: "sh".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(200),
+            read_buffer_size: Some(16),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+
+        // larger than the buffer, read over several fills
+        let input = "printf '%0100d' 0".to_string();
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            input.into(),
+            &RunOptions::default(),
+        )
+        .await;
+        assert_eq!(vec![b'0'; 100], output.unwrap());
+
+        let options = RunOptions {
+            output_size: Some(40),
+            ..Default::default()
+        };
+        let input = "printf '%0100d' 0".to_string();
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            input.into(),
+            &options,
+        )
+        .await;
+        assert_eq!(vec![b'0'; 40], output.unwrap());
+
+        // the discarded output doesn't leak into the next one
+        let input = "echo next".to_string();
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            input.into(),
+            &RunOptions::default(),
+        )
+        .await;
+        assert_eq!(b"next\n".to_vec(), output.unwrap());
+
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_capture_stderr() {
+        let cmd = Arc::new(Cmd {
+            name: "sh".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(200),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+
+        let input = "echo err 1>&2".to_string();
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            input.into(),
+            &RunOptions::default(),
+        )
+        .await;
+        assert_eq!(b"err\n".to_vec(), output.unwrap());
+
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_process_exited_early() {
+        let cmd = Arc::new(Cmd {
+            name: "broken".to_string(),
+            cmd: "echo 'no such package' >&2; exit 3".to_string(),
+            use_shell: true,
+            output_size: 1024,
+            ..Default::default()
+        });
+
+        match spawn_process(cmd, None).await {
+            Err(ProcessManagerError::ProcessExitedEarly {
+                name,
+                exit_code,
+                output,
+            }) => {
+                assert_eq!("broken", name);
+                assert_eq!(Some(3), exit_code);
+                assert_eq!("no such package\n", output);
+            }
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("spawned the exited process"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stdout_closed() {
+        // prints the input to stderr only
+        let cmd = Arc::new(Cmd {
+            name: "stderr_only".to_string(),
+            cmd: "exec >&-; echo ready >&2; cat >&2".to_string(),
+            use_shell: true,
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(100),
+            ready_regex: Some("ready\n".to_string()),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+        for input in ["a", "b"] {
+            let output = run_cmd_in_process_table(
+                &TokioProcessBackend,
+                &mut proceses,
+                &cmd,
+                input.to_string().into(),
+                &RunOptions::default(),
+            )
+            .await;
+            assert_eq!(format!("{}\n", input).into_bytes(), output.unwrap());
+        }
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_redirect_stderr_to_stdout() {
+        let cmd = Arc::new(Cmd {
+            name: "sh".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(200),
+            redirect_stderr_to_stdout: Some(true),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+        for _ in 0..5 {
+            let output = run_cmd_in_process_table(
+                &TokioProcessBackend,
+                &mut proceses,
+                &cmd,
+                "echo 1; echo 2 >&2; echo 3; echo 4 >&2; echo 5"
+                    .to_string()
+                    .into(),
+                &RunOptions::default(),
+            )
+            .await;
+            assert_eq!(b"1\n2\n3\n4\n5\n".to_vec(), output.unwrap());
+        }
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_ignore_stderr() {
+        let cmd = Arc::new(Cmd {
+            name: "sh".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(200),
+            capture_stderr: Some(false),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+
+        let input = "echo out; echo err 1>&2".to_string();
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            input.into(),
+            &RunOptions::default(),
+        )
+        .await;
+        assert_eq!(b"out\n".to_vec(), output.unwrap());
+
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_stdin_write_timeout() {
+        let cmd = Arc::new(Cmd {
+            name: "sleep".to_string(),
+            cmd: "sleep 30".to_string(),
+            output_size: 1024,
+            ..Default::default()
+        });
+        let name = cmd.name.clone();
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+
+        let input = "a".repeat(1024 * 1024);
+        let result = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            input.into(),
+            &RunOptions::default(),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(ProcessManagerError::StdinWriteTimeout(_))
+        ));
+        assert!(!proceses.contains_key(&name));
+    }
+
+    #[tokio::test]
+    async fn test_make_room_for_spawn() {
+        let mut proceses = ProcessTable::new();
+        for name in ["a", "b"] {
+            let cmd = Arc::new(Cmd {
+                name: name.to_string(),
+                cmd: "cat".to_string(),
+                ..Default::default()
+            });
+            add_to_process_table(&mut proceses, spawn_process(cmd, None).await.unwrap()).unwrap();
+        }
+        proceses.get_mut("b").unwrap().last_used = Instant::now();
+
+        let reject = ProcessLimit {
+            max_processes: 2,
+            policy: ProcessLimitPolicy::Reject,
+        };
+        assert!(matches!(
+            make_room_for_spawn(&mut proceses, Some(reject)).await,
+            Err(ProcessManagerError::TooManyProcesses(2))
+        ));
+        assert_eq!(2, proceses.len());
+
+        let evict_lru = ProcessLimit {
+            max_processes: 2,
+            policy: ProcessLimitPolicy::EvictLru,
+        };
+        make_room_for_spawn(&mut proceses, Some(evict_lru))
+            .await
+            .unwrap();
+        assert_eq!(vec![&"b".to_string()], proceses.keys().collect::<Vec<_>>());
+
+        make_room_for_spawn(&mut proceses, None).await.unwrap();
+        assert_eq!(1, proceses.len());
+
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_spawn_retries() {
+        let dir = std::env::temp_dir().join(format!("dairi-spawn-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // exists but isn't executable
+        let program = dir.join("not_executable");
+        std::fs::write(&program, "").unwrap();
+
+        let cmd = Arc::new(Cmd {
+            name: "not_executable".to_string(),
+            cmd: program.display().to_string(),
+            spawn_retries: Some(2),
+            spawn_retry_base_milli_sec: Some(10),
+            ..Default::default()
+        });
+        let result = spawn_process(cmd, None).await;
+        assert!(matches!(
+            result,
+            Err(ProcessManagerError::SpawnFailed { attempts: 3, .. })
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_program_not_found() {
+        let cmd = Arc::new(Cmd {
+            name: "bogus".to_string(),
+            cmd: "no_such_dairi_program --quiet".to_string(),
+            ..Default::default()
+        });
+
+        let result = spawn_process(cmd, None).await;
+        assert!(matches!(
+            result,
+            Err(ProcessManagerError::ProgramNotFound(program)) if program == "no_such_dairi_program"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_terminate_process_within() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let grace = Duration::from_millis(300);
+        for (cmd, exited_by) in [
+            ("echo ready; cat", libc::SIGTERM),
+            ("trap '' TERM; echo ready; cat", libc::SIGKILL),
+        ] {
+            let cmd = Arc::new(Cmd {
+                name: "sh".to_string(),
+                cmd: cmd.to_string(),
+                use_shell: true,
+                ready_regex: Some("ready\n".to_string()),
+                ..Default::default()
+            });
+            let mut running_process = spawn_process(cmd.clone(), None).await.unwrap();
+
+            let started_at = Instant::now();
+            terminate_process_within(&cmd.name, &mut running_process.child, Signal::Term, grace)
+                .await;
+            let status = running_process.child.wait().await.unwrap();
+            assert_eq!(Some(exited_by), status.signal());
+            assert_eq!(exited_by == libc::SIGKILL, started_at.elapsed() >= grace);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remove_and_reap() {
+        // exits after the check of the early exit
+        let cmd = Arc::new(Cmd {
+            name: "sleep".to_string(),
+            cmd: "sleep 0.2".to_string(),
+            ..Default::default()
+        });
+        let name = cmd.name.clone();
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(&mut proceses, spawn_process(cmd, None).await.unwrap()).unwrap();
+        let pid = proceses[&name].child.id().unwrap();
+
+        // the exited child stays as a zombie until it's waited
+        time::sleep(Duration::from_millis(400)).await;
+        let mut sys = System::new();
+        sys.refresh_process_specifics(Pid::from_u32(pid), ProcessRefreshKind::new());
+        let status = sys.process(Pid::from_u32(pid)).map(|p| p.status());
+        assert_eq!(Some(ProcessStatus::Zombie), status);
+
+        remove_and_reap(&mut proceses, &name).await;
+        assert!(!proceses.contains_key(&name));
+        assert_ne!(0, unsafe { libc::kill(pid as libc::pid_t, 0) });
+    }
+
+    #[tokio::test]
+    async fn test_respawn_exited_process() {
+        let cmd = Arc::new(Cmd {
+            name: "sh".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(100),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+        let pid = proceses[&cmd.name].child.id();
+        send_signal(&proceses[&cmd.name].child, Signal::Kill).unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+
+        let output = run_cmd_in_process_table(
+            &TokioProcessBackend,
+            &mut proceses,
+            &cmd,
+            "echo hello".to_string().into(),
+            &RunOptions::default(),
+        )
+        .await;
+        assert_eq!(b"hello\n".to_vec(), output.unwrap());
+        assert_ne!(pid, proceses[&cmd.name].child.id());
+        // the exited one has been reaped
+        assert_ne!(0, unsafe { libc::kill(pid.unwrap() as libc::pid_t, 0) });
+        terminate_all(&mut proceses).await;
+    }
+
+    #[tokio::test]
+    async fn test_check_unhealthy_process() {
+        let name = "unhealthy".to_string();
+        let mut child = Command::new("cat").stdin(Stdio::piped()).spawn().unwrap();
+        let pid = child.id();
+        let error = || std::io::Error::other("no child process");
+
+        // killed by the caller
+        assert!(check_unhealthy_process(&name, pid, error(), true).is_ok());
+
+        match check_unhealthy_process(&name, pid, error(), false) {
+            Err(ProcessManagerError::ProcessUnhealthy { status, error, .. }) => {
+                // e.g. still in exec right after the spawn, but alive
+                assert!(
+                    status.is_some_and(|status| {
+                        status != ProcessStatus::Zombie && status != ProcessStatus::Dead
+                    }),
+                    "{:?}",
+                    status
+                );
+                assert_eq!("no child process", error);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        // left running
+        assert!(child.try_wait().unwrap().is_none());
+
+        terminate_process(&name, &mut child, Signal::Kill).await;
+        assert!(matches!(
+            check_unhealthy_process(&name, pid, error(), false),
+            Err(ProcessManagerError::ProcessUnhealthy { status: None, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_terminate_all() {
+        let cmd = Arc::new(Cmd {
+            name: "cat".to_string(),
+            cmd: "cat".to_string(),
+            ..Default::default()
+        });
+
+        let mut proceses = ProcessTable::new();
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+        let pid = proceses[&cmd.name].child.id().unwrap();
+
+        terminate_all(&mut proceses).await;
+        assert!(proceses.is_empty());
+        assert_ne!(0, unsafe { libc::kill(pid as libc::pid_t, 0) });
+    }
+
+    #[tokio::test]
+    async fn test_stop_process_with_quit_cmd() {
+        let cmd = Arc::new(Cmd {
+            name: "sh".to_string(),
+            cmd: "sh".to_string(),
+            quit_cmd: Some("exit 3\n".to_string()),
+            ..Default::default()
+        });
+
+        let mut running_process = spawn_process(cmd.clone(), None).await.unwrap();
+        stop_process(&cmd.name, &mut running_process).await;
+
+        let status = running_process.child.try_wait().unwrap().unwrap();
+        assert_eq!(Some(3), status.code());
+    }
+
+    #[tokio::test]
+    async fn test_signal_process() {
+        assert_eq!(Some(Signal::Int), Signal::from_name("INT"));
+        assert_eq!(Some(Signal::Hup), Signal::from_name("sighup"));
+        assert_eq!(None, Signal::from_name("USR1"));
+
+        // the pids are registered by name across the process tables
+        let cmd = Arc::new(Cmd {
+            name: "signaled".to_string(),
+            cmd: "cat".to_string(),
+            ..Default::default()
+        });
+        let mut proceses = ProcessTable::new();
+        assert!(matches!(
+            signal_cmd(&cmd.name, Signal::Term).await,
+            Err(ProcessManagerError::ProcessNotRunning(_))
+        ));
+
+        add_to_process_table(
+            &mut proceses,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+        signal_cmd(&cmd.name, Signal::Term).await.unwrap();
+
+        let running_process = proceses.get_mut(&cmd.name).unwrap();
+        let status = timeout(Duration::from_secs(5), running_process.child.wait())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(None, status.code());
+
+        // unregistered with the removal
+        proceses.clear();
+        assert!(matches!(
+            signal_cmd(&cmd.name, Signal::Term).await,
+            Err(ProcessManagerError::ProcessNotRunning(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_signal_during_run() {
+        let cmd = Arc::new(Cmd {
+            name: "interrupted".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(100),
+            ..Default::default()
+        });
+        let proceses = Arc::new(Mutex::new(ProcessTable::new()));
+        add_to_process_table(
+            &mut *proceses.lock().await,
+            spawn_process(cmd.clone(), None).await.unwrap(),
+        )
+        .unwrap();
+
+        let started_at = Instant::now();
+        let run = tokio::spawn({
+            let proceses = proceses.clone();
+            let cmd = cmd.clone();
+            async move {
+                let mut proceses = proceses.lock().await;
+                run_cmd_in_process_table(
+                    &TokioProcessBackend,
+                    &mut proceses,
+                    &cmd,
+                    "sleep 30 & trap 'kill $!; echo interrupted' INT; wait"
+                        .to_string()
+                        .into(),
+                    &RunOptions::default(),
+                )
+                .await
+            }
+        });
+        time::sleep(Duration::from_millis(300)).await;
+
+        // sent while the run holds the process table lock
+        signal_cmd(&cmd.name, Signal::Int).await.unwrap();
+        let output = timeout(Duration::from_secs(5), run).await.unwrap().unwrap();
+        assert_eq!(b"interrupted\n".to_vec(), output.unwrap());
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+
+        terminate_all(&mut *proceses.lock().await).await;
+    }
+
+    #[tokio::test]
+    async fn test_sample_process_stats() {
+        let cmd = Arc::new(Cmd {
+            name: "cat".to_string(),
+            cmd: "cat".to_string(),
+            ..Default::default()
+        });
+        let running_process = spawn_process(cmd.clone(), None).await.unwrap();
+        let pid = running_process.child.id().unwrap();
+
+        let stats =
+            sample_process_stats(vec![(cmd.name.clone(), pid, Duration::from_secs(3))]).await;
+        assert_eq!(1, stats.len());
+        assert_eq!(pid, stats[0].pid);
+        assert_eq!(3, stats[0].idle_sec);
+        assert!(stats[0].memory_bytes > 0);
+        assert!(stats[0].cpu_usage >= 0.0);
+
+        assert!(
+            sample_process_stats(vec![("gone".to_string(), u32::MAX, Duration::ZERO)])
+                .await
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_check_input_size() {
+        let input = CmdInput::from("1 + 1".to_string());
+        assert!(check_input_size(&input, None).is_ok());
+        assert!(check_input_size(&input, Some(5)).is_ok());
+        assert!(matches!(
+            check_input_size(&input, Some(4)),
+            Err(ProcessManagerError::InputTooLarge {
+                size: 5,
+                max_input_size: 4
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_migrate_processes() {
+        let cat = |name: &str, timeout_sec: u64| Cmd {
+            name: name.to_string(),
+            cmd: "cat".to_string(),
+            timeout_sec: Some(timeout_sec),
+            ..Default::default()
+        };
+        let old_cmd_table = into_shared_cmd_table(CmdTable::from([
+            ("tuned".to_string(), cat("tuned", 1)),
+            ("replaced".to_string(), cat("replaced", 1)),
+            ("redirected".to_string(), cat("redirected", 1)),
+            ("made_one_shot".to_string(), cat("made_one_shot", 1)),
+            ("removed".to_string(), cat("removed", 1)),
+        ]));
+
+        let mut proceses = ProcessTable::new();
+        for cmd in old_cmd_table.values() {
+            add_to_process_table(
+                &mut proceses,
+                spawn_process(cmd.clone(), None).await.unwrap(),
+            )
+            .unwrap();
+        }
+        let tuned_pid = proceses["tuned"].child.id();
+
+        let new_cmd_table = into_shared_cmd_table(CmdTable::from([
+            ("tuned".to_string(), cat("tuned", 2)),
+            (
+                "replaced".to_string(),
+                Cmd {
+                    cmd: "sh".to_string(),
+                    ..cat("replaced", 1)
+                },
+            ),
+            (
+                "redirected".to_string(),
+                Cmd {
+                    redirect_stderr_to_stdout: Some(true),
+                    ..cat("redirected", 1)
+                },
+            ),
+            (
+                "made_one_shot".to_string(),
+                Cmd {
+                    persistent: Some(false),
+                    ..cat("made_one_shot", 1)
+                },
+            ),
+            ("added".to_string(), cat("added", 1)),
+        ]));
+
+        let summary = migrate_processes(&mut proceses, &old_cmd_table, &new_cmd_table).await;
+        assert_eq!(
+            ReloadSummary {
+                added: vec!["added".to_string()],
+                removed: vec!["removed".to_string()],
+                respawned: vec![
+                    "made_one_shot".to_string(),
+                    "redirected".to_string(),
+                    "replaced".to_string()
+                ],
+                updated: vec!["tuned".to_string()],
+            },
+            summary
+        );
+
+        // the warm process is kept with the new settings
+        assert_eq!(tuned_pid, proceses["tuned"].child.id());
+        assert_eq!(Some(2), proceses["tuned"].running_cmd.timeout_sec);
+        assert!(!proceses.contains_key("replaced"));
+        assert!(!proceses.contains_key("redirected"));
+        assert!(!proceses.contains_key("made_one_shot"));
+        assert!(!proceses.contains_key("removed"));
+
+        terminate_all(&mut proceses).await;
+    }
+
+    /// A process echoing the input lines prefixed with its pid, over in-memory pipes. It exits
+    /// when its stdin is closed, on any signal, or when the test sets the exit status.
+    struct MockChild {
+        pid: u32,
+        stdout: tokio::io::DuplexStream,
+        exit_status: Arc<std::sync::Mutex<Option<ExitStatus>>>,
+    }
+
+    fn exit_mock(exit_status: &std::sync::Mutex<Option<ExitStatus>>, code: i32) {
+        use std::os::unix::process::ExitStatusExt;
+        exit_status
+            .lock()
+            .unwrap()
+            .get_or_insert(ExitStatus::from_raw(code << 8));
+    }
+
+    impl MockChild {
+        fn exit(&self, code: i32) {
+            exit_mock(&self.exit_status, code);
+        }
+    }
+
+    impl ProcessChild for MockChild {
+        fn id(&self) -> Option<u32> {
+            Some(self.pid)
+        }
+
+        /// The fake pid may be a real process of the host, which `signal_cmd` must not reach.
+        fn register_pid(&self, _name: &CmdName) -> Option<PidEntry> {
+            None
+        }
+
+        fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+            Ok(*self.exit_status.lock().unwrap())
+        }
+
+        async fn wait(&mut self) -> std::io::Result<ExitStatus> {
+            loop {
+                let exit_status = *self.exit_status.lock().unwrap();
+                if let Some(exit_status) = exit_status {
+                    return Ok(exit_status);
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        fn signal(&self, _signal: Signal) -> Result<()> {
+            self.exit(143);
+            Ok(())
+        }
+
+        async fn kill(&mut self) -> std::io::Result<()> {
+            self.exit(137);
+            Ok(())
+        }
+
+        fn io<'a>(
+            running_process: &'a mut RunningProcess<Self>,
+            _name: &CmdName,
+        ) -> Result<ProcessIo<'a>> {
+            Ok(ProcessIo {
+                stdin: &mut running_process.stdin,
+                stdout: &mut running_process.child.stdout,
+                stderr: None,
+            })
+        }
+    }
+
+    /// Spawns the mock processes with the pids from 1001.
+    #[derive(Default)]
+    struct MockBackend {
+        spawned: std::sync::atomic::AtomicU32,
+        proceses: Mutex<ProcessTable<MockChild>>,
+    }
+
+    impl MockBackend {
+        fn spawned(&self) -> u32 {
+            self.spawned.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    impl ProcessBackend for MockBackend {
+        type Child = MockChild;
+
+        async fn spawn(
+            &self,
+            cmd: Arc<Cmd>,
+            env: Option<&HashMap<String, String>>,
+        ) -> Result<RunningProcess<MockChild>> {
+            use tokio::io::AsyncBufReadExt;
+
+            let pid = 1000
+                + self
+                    .spawned
+                    .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+                + 1;
+            let exit_status = Arc::new(std::sync::Mutex::new(None));
+            let (stdin, process_stdin) = tokio::io::duplex(1024);
+            let (mut process_stdout, stdout) = tokio::io::duplex(1024);
+            tokio::spawn({
+                let exit_status = exit_status.clone();
+                async move {
+                    let mut lines = BufReader::new(process_stdin).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let output = format!("{}:{}\n", pid, line);
+                        if process_stdout.write_all(output.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                    exit_mock(&exit_status, 0);
+                }
+            });
+
+            Ok(RunningProcess {
+                running_cmd: cmd,
+                pid_entry: None,
+                child: MockChild {
+                    pid,
+                    stdout,
+                    exit_status,
+                },
+                spawned_at: Instant::now(),
+                last_used: Instant::now(),
+                env: env.cloned(),
+                stdin: Some(Box::new(stdin)),
+                pty: None,
+                started: false,
+                dirty: false,
+                recent_output: RecentOutput::new(0),
+            })
+        }
+
+        fn process_table(&self) -> &Mutex<ProcessTable<MockChild>> {
+            &self.proceses
+        }
+    }
+
+    fn mock_cmd() -> Arc<Cmd> {
+        Arc::new(Cmd {
+            name: "mock".to_string(),
+            cmd: "mock".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(50),
+            ..Default::default()
+        })
+    }
+
+    async fn run_on_mock(backend: &MockBackend, cmd: &Arc<Cmd>, input: &str) -> String {
+        run_on_mock_with(backend, cmd, input, &RunOptions::default()).await
+    }
+
+    async fn run_on_mock_with(
+        backend: &MockBackend,
+        cmd: &Arc<Cmd>,
+        input: &str,
+        options: &RunOptions,
+    ) -> String {
+        let mut proceses = backend.process_table().lock().await;
+        let output = run_cmd_in_process_table(
+            backend,
+            &mut proceses,
+            cmd,
+            input.to_string().into(),
+            options,
+        )
+        .await
+        .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_mock_backend_reuse() {
+        let backend = MockBackend::default();
+        let cmd = mock_cmd();
+
+        assert_eq!("1001:a\n", run_on_mock(&backend, &cmd, "a").await);
+        assert_eq!("1001:b\n", run_on_mock(&backend, &cmd, "b").await);
+        assert_eq!(1, backend.spawned());
+        // the fake pid isn't signaled by `signal_cmd`
+        assert!(!pid_table().lock().unwrap().contains_key(&cmd.name));
+
+        // the env of the request is ignored without respawn
+        let options = RunOptions {
+            env: Some(RequestEnv {
+                vars: HashMap::from([("A".to_string(), "1".to_string())]),
+                respawn: false,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            "1001:c\n",
+            run_on_mock_with(&backend, &cmd, "c", &options).await
+        );
+        assert_eq!(1, backend.spawned());
+    }
+
+    #[tokio::test]
+    async fn test_mock_backend_respawn() {
+        let backend = MockBackend::default();
+        let cmd = mock_cmd();
+
+        assert_eq!("1001:a\n", run_on_mock(&backend, &cmd, "a").await);
+
+        // exited
+        backend.proceses.lock().await[&cmd.name].child.exit(1);
+        assert_eq!("1002:b\n", run_on_mock(&backend, &cmd, "b").await);
+        assert_eq!("1002:c\n", run_on_mock(&backend, &cmd, "c").await);
+
+        // the last interaction was cancelled
+        backend
+            .proceses
+            .lock()
+            .await
+            .get_mut(&cmd.name)
+            .unwrap()
+            .dirty = true;
+        assert_eq!("1003:d\n", run_on_mock(&backend, &cmd, "d").await);
+        assert!(!backend.proceses.lock().await[&cmd.name].dirty);
+
+        // another env with respawn, which is kept by the next runs
+        let env = HashMap::from([("A".to_string(), "1".to_string())]);
+        let options = RunOptions {
+            env: Some(RequestEnv {
+                vars: env.clone(),
+                respawn: true,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            "1004:e\n",
+            run_on_mock_with(&backend, &cmd, "e", &options).await
+        );
+        assert_eq!(
+            "1004:f\n",
+            run_on_mock_with(&backend, &cmd, "f", &options).await
+        );
+        assert_eq!(
+            Some(&env),
+            backend.proceses.lock().await[&cmd.name].env.as_ref()
+        );
+
+        restart_process(&backend, cmd.clone()).await.unwrap();
+        assert_eq!("1005:g\n", run_on_mock(&backend, &cmd, "g").await);
+        assert_eq!(5, backend.spawned());
+    }
+
+    #[tokio::test]
+    async fn test_mock_backend_batch() {
+        // the tokens of the rate limit aren't refilled while the time is paused
+        time::pause();
+        let backend = MockBackend::default();
+        let cmd = Arc::new(Cmd {
+            name: "mock_batch".to_string(),
+            cmd: "mock".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(50),
+            rate_limit_per_sec: Some(3),
+            no_empty_input: true,
+            ..Default::default()
+        });
+
+        let inputs = ["a", "", "b", "c"].map(String::from).to_vec();
+        let results = run_batch_on(&backend, &cmd, inputs, &RunOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(4, results.len());
+        assert_eq!(b"1001:a\n", &results[0].as_ref().unwrap().output[..]);
+        assert!(matches!(
+            results[1],
+            Err(ProcessManagerError::EmptyInputNotAllowed)
+        ));
+        // to the same process after the failed one
+        assert_eq!(b"1001:b\n", &results[2].as_ref().unwrap().output[..]);
+        assert!(matches!(
+            results[3],
+            Err(ProcessManagerError::RateLimited { .. })
+        ));
+        assert_eq!(1, backend.spawned());
+    }
+
+    #[tokio::test]
+    async fn test_mock_backend_one_shot() {
+        let backend = MockBackend::default();
+        let cmd = Arc::new(Cmd {
+            name: "mock".to_string(),
+            cmd: "mock".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            persistent: Some(false),
+            wait_for_exit: Some(true),
+            ..Default::default()
+        });
+
+        let options = RunOptions::default();
+        let run = |input: &str| {
+            run_cmd_with_cache(
+                &backend,
+                &cmd.name,
+                &cmd,
+                input.to_string().into(),
+                &options,
+            )
+        };
+        assert_eq!((b"1001:a\n".to_vec(), Some(0)), run("a").await.unwrap());
+        assert_eq!((b"1002:b\n".to_vec(), Some(0)), run("b").await.unwrap());
+        // not kept in the process table
+        assert!(backend.proceses.lock().await.is_empty());
+    }
 }
@@ -0,0 +1,162 @@
+//! Pseudo terminals for the cmds with `use_pty`, so that programs which buffer their output or
+//! behave differently when stdout isn't a terminal work as in a terminal.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The default `VEOF` character, `^D`.
+pub const EOF: u8 = 0x04;
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Opens a pty and returns the master and the slave. The echo and the `\n` to `\r\n`
+/// translation of the terminal are turned off so that the output is the same as with pipes.
+pub fn open() -> io::Result<(PtyMaster, File)> {
+    // SAFETY: the fd returned by posix_openpt is owned by the File from here.
+    let master = unsafe {
+        let fd = check(libc::posix_openpt(
+            libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC,
+        ))?;
+        File::from_raw_fd(fd)
+    };
+    let master_fd = master.as_raw_fd();
+
+    // SAFETY: the fd is a valid pty master, the buffer outlives ptsname_r.
+    let slave_path = unsafe {
+        check(libc::grantpt(master_fd))?;
+        check(libc::unlockpt(master_fd))?;
+        let mut buf = [0 as libc::c_char; 128];
+        let ret = libc::ptsname_r(master_fd, buf.as_mut_ptr(), buf.len());
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        std::ffi::CStr::from_ptr(buf.as_ptr())
+            .to_string_lossy()
+            .into_owned()
+    };
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(slave_path)?;
+
+    // SAFETY: termios is plain data filled by tcgetattr before it's read.
+    unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        check(libc::tcgetattr(slave.as_raw_fd(), &mut termios))?;
+        termios.c_lflag &= !(libc::ECHO | libc::ECHONL);
+        termios.c_oflag &= !libc::ONLCR;
+        check(libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios))?;
+    }
+
+    Ok((PtyMaster::new(master)?, slave))
+}
+
+/// Makes the pty the controlling terminal of the child. To be called in `pre_exec` after the
+/// slave is set to stdin.
+pub fn set_controlling_terminal() -> io::Result<()> {
+    // SAFETY: setsid and ioctl are async signal safe.
+    unsafe {
+        check(libc::setsid())?;
+        check(libc::ioctl(0, libc::TIOCSCTTY as _, 0))?;
+    }
+    Ok(())
+}
+
+/// The master side of a pty. Both the input and the output of the child go through it.
+pub struct PtyMaster {
+    fd: AsyncFd<File>,
+}
+
+impl PtyMaster {
+    fn new(master: File) -> io::Result<Self> {
+        // SAFETY: fcntl on an owned fd.
+        unsafe {
+            let flags = check(libc::fcntl(master.as_raw_fd(), libc::F_GETFL))?;
+            check(libc::fcntl(
+                master.as_raw_fd(),
+                libc::F_SETFL,
+                flags | libc::O_NONBLOCK,
+            ))?;
+        }
+        Ok(Self {
+            fd: AsyncFd::new(master)?,
+        })
+    }
+
+    /// Returns another handle of the same pty, to read and write at the same time.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Self::new(self.fd.get_ref().try_clone()?)
+    }
+}
+
+impl AsyncRead for PtyMaster {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = match self.fd.poll_read_ready(cx) {
+                Poll::Ready(guard) => guard?,
+                Poll::Pending => return Poll::Pending,
+            };
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|fd| {
+                let mut file = fd.get_ref();
+                file.read(unfilled)
+            }) {
+                Ok(Ok(read_size)) => {
+                    buf.advance(read_size);
+                    return Poll::Ready(Ok(()));
+                }
+                // the slave is closed when the child exits
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::EIO) => return Poll::Ready(Ok(())),
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for PtyMaster {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = match self.fd.poll_write_ready(cx) {
+                Poll::Ready(guard) => guard?,
+                Poll::Pending => return Poll::Pending,
+            };
+            match guard.try_io(|fd| {
+                let mut file = fd.get_ref();
+                file.write(buf)
+            }) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
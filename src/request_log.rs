@@ -0,0 +1,140 @@
+use axum::http::{header::CONTENT_LENGTH, HeaderValue, Request, Response};
+use futures::future::BoxFuture;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use tokio::time::Instant;
+use tower::{Layer, Service};
+use tracing::Instrument;
+
+pub const REQUEST_ID_HEADER: &str = "x-dairi-request-id";
+
+static REQUEST_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Returns a short id that is unique among the requests of the running server.
+fn new_request_id(random_state: &RandomState) -> String {
+    let mut hasher = random_state.build_hasher();
+    hasher.write_u64(REQUEST_COUNT.fetch_add(1, Ordering::Relaxed));
+    format!("{:08x}", hasher.finish() as u32)
+}
+
+fn cmd_name_from_path(path: &str) -> Option<&str> {
+    let mut segments = path.trim_start_matches('/').split('/');
+    match (segments.next(), segments.next()) {
+        (Some("cmd"), Some(cmd_name)) if !cmd_name.is_empty() => Some(cmd_name),
+        _ => None,
+    }
+}
+
+/// Assigns an id to each request, records it on the tracing span of the request and returns it
+/// in the `x-dairi-request-id` response header.
+#[derive(Clone, Default)]
+pub struct RequestLogLayer {
+    random_state: RandomState,
+}
+
+impl<S> Layer<S> for RequestLogLayer {
+    type Service = RequestLog<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestLog {
+            inner,
+            random_state: self.random_state.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequestLog<S> {
+    inner: S,
+    random_state: RandomState,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RequestLog<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let request_id = new_request_id(&self.random_state);
+        let span = tracing::info_span!("request", request_id = %request_id);
+
+        let method = request.method().clone();
+        let cmd_name = cmd_name_from_path(request.uri().path())
+            .unwrap_or("")
+            .to_string();
+        let input_length = request
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        let started_at = Instant::now();
+        let response_future = span.in_scope(|| {
+            tracing::info!(%method, %cmd_name, input_length, "request started");
+            self.inner.call(request)
+        });
+
+        Box::pin(
+            async move {
+                let mut response = response_future.await?;
+                tracing::info!(
+                    %method,
+                    %cmd_name,
+                    status = response.status().as_u16(),
+                    elapsed_milli_sec = started_at.elapsed().as_millis() as u64,
+                    "request finished"
+                );
+
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+                }
+                Ok(response)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use axum::body::Body;
+    use std::convert::Infallible;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_request_id_header() {
+        let service =
+            RequestLogLayer::default().layer(tower::service_fn(|_: Request<Body>| async {
+                Ok::<_, Infallible>(Response::new(Body::empty()))
+            }));
+
+        let mut request_ids = Vec::new();
+        for _ in 0..2 {
+            let request = Request::builder()
+                .uri("/cmd/julia")
+                .body(Body::empty())
+                .unwrap();
+            let response = service.clone().oneshot(request).await.unwrap();
+            let request_id = response.headers()[REQUEST_ID_HEADER].clone();
+            assert_eq!(8, request_id.len());
+            request_ids.push(request_id);
+        }
+        assert_ne!(request_ids[0], request_ids[1]);
+
+        assert_eq!(Some("julia"), cmd_name_from_path("/cmd/julia/restart"));
+        assert_eq!(None, cmd_name_from_path("/version"));
+    }
+}
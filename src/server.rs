@@ -1,79 +1,397 @@
-use crate::process_manager;
-use serde::{Deserialize, Serialize};
+use crate::cache::CacheStats;
+use crate::config::{Config, ConfigError};
+use crate::line_protocol;
+use crate::metrics::metrics;
+use crate::process_manager::{
+    self, CmdInput, CmdOutput, CmdSession, OutputChunks, OutputEncoding, ProcessManagerError,
+    ProcessStats, ReloadSummary, RequestEnv, RunOptions, Signal,
+};
+use crate::request_log::RequestLogLayer;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use axum::{
+    async_trait,
+    body::Body,
     error_handling::HandleErrorLayer,
-    extract::connect_info,
-    extract::Path,
-    http::StatusCode,
-    response::{IntoResponse, Response},
-    routing::post,
+    extract::connect_info::{self, ConnectInfo},
+    extract::ws::{CloseCode, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    extract::{BodyStream, Extension, FromRequest, Path, Query, RequestParts},
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE},
+        Request, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{Headers, IntoResponse, Response},
+    routing::{get, post},
     Json, Router,
 };
-use futures::ready;
+use bytes::{Bytes, BytesMut};
+use futures::{ready, TryStreamExt};
+use hyper::body::HttpBody;
+use std::collections::HashMap;
 use std::string::FromUtf8Error;
 use std::time::Duration;
 use thiserror::Error;
 use tokio::net::{unix::UCred, UnixListener, UnixStream};
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
 use tower::ServiceBuilder;
+use tower_http::compression::predicate::{DefaultPredicate, Predicate};
+use tower_http::compression::CompressionLayer;
 
 use hyper::server::accept::Accept;
 use once_cell::sync::OnceCell;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{FromRawFd, RawFd};
 use std::{
-    path::PathBuf,
+    path::{Path as StdPath, PathBuf},
     pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
     sync::Arc,
     task::{Context, Poll},
 };
 use tower::BoxError;
 
 pub static DEFAULT_SOCKET_PATH: OnceCell<PathBuf> = OnceCell::new();
+
+/// `$XDG_RUNTIME_DIR/dairi/serve.sock`, which is private to the user, or
+/// `/tmp/dairi/serve.sock` if `XDG_RUNTIME_DIR` isn't set. Shared by the server and the lua
+/// client.
 pub fn default_socket_path() -> &'static PathBuf {
-    DEFAULT_SOCKET_PATH.get_or_init(|| PathBuf::from("/tmp/dairi/serve.sock"))
+    DEFAULT_SOCKET_PATH
+        .get_or_init(|| resolve_default_socket_path(std::env::var_os("XDG_RUNTIME_DIR")))
+}
+
+fn resolve_default_socket_path(xdg_runtime_dir: Option<std::ffi::OsString>) -> PathBuf {
+    xdg_runtime_dir
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/tmp"))
+        .join("dairi")
+        .join("serve.sock")
 }
 
 #[derive(Debug, Error)]
 pub enum ServerError {
     #[error("{0}")]
     IoError(#[from] std::io::Error),
+
+    #[error(
+        "no permission to remove the stale socket {}{}, remove it manually or use another socket path",
+        .path.display(),
+        owner_description(.owner_uid)
+    )]
+    StaleSocketPermissionDenied {
+        path: PathBuf,
+        owner_uid: Option<u32>,
+    },
+
+    #[error(
+        "another server is listening at {}, stop it or use another socket path",
+        .0.display()
+    )]
+    AddressInUse(PathBuf),
 }
-const REQUEST_TIMEOUT_SEC: u64 = 180;
-pub async fn serve() -> Result<(), ServerError> {
-    if std::env::var_os("RUST_LOG").is_none() {
-        std::env::set_var("RUST_LOG", "debug")
-    }
-
-    let socket_path = default_socket_path();
-    let _ = tokio::fs::remove_file(&socket_path).await;
-    tokio::fs::create_dir_all(socket_path.parent().unwrap()).await?;
-    let uds = UnixListener::bind(socket_path.clone()).unwrap();
-
-    let app = Router::new().route("/cmd/:cmd_name", post(run_cmd)).layer(
-        ServiceBuilder::new()
-            .layer(HandleErrorLayer::new(|error: BoxError| async move {
-                if error.is::<tower::timeout::error::Elapsed>() {
-                    Ok(StatusCode::REQUEST_TIMEOUT)
-                } else {
-                    Err((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Unhandled internal error: {}", error),
-                    ))
-                }
-            }))
-            .timeout(Duration::from_secs(REQUEST_TIMEOUT_SEC))
-            .into_inner(),
-    );
 
-    tracing::info!("dairi server is listening at {}", socket_path.display());
+fn owner_description(owner_uid: &Option<u32>) -> String {
+    match owner_uid {
+        Some(uid) => format!(" (owned by uid {})", uid),
+        None => "".to_string(),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ServerConfig {
+    /// The socket to serve on. `default_socket_path()` if `None`.
+    pub socket_path: Option<PathBuf>,
+
+    /// The config file to reload the cmds from on SIGHUP and `POST /admin/reload`.
+    pub config_path: PathBuf,
+
+    /// Uids of the peers allowed to use the server, of which only `/version` is open to every
+    /// peer. Every peer is allowed if `None`.
+    pub allowed_uids: Option<Vec<u32>>,
+
+    /// Socket to serve the line protocol on in addition to HTTP. see `line_protocol`
+    pub line_protocol_socket: Option<PathBuf>,
 
-    axum::Server::builder(ServerAccept { uds })
+    /// The timeout of a whole HTTP request. `DEFAULT_REQUEST_TIMEOUT_SEC` if `None`.
+    pub request_timeout_sec: Option<u64>,
+
+    /// The permissions of the sockets created by the server. `DEFAULT_SOCKET_MODE` if `None`.
+    pub socket_mode: Option<u32>,
+
+    /// Whether an HTTP/1 connection is kept open for the next request. Defaults to true.
+    pub http1_keep_alive: Option<bool>,
+
+    /// The max requests in flight on an HTTP/2 connection. hyper's default if `None`.
+    pub http2_max_concurrent_streams: Option<u32>,
+
+    /// Whether the responses are compressed by `Accept-Encoding`. Defaults to false.
+    pub compress_responses: Option<bool>,
+}
+
+/// The version of the running server, also printed by `--version`.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub const DEFAULT_REQUEST_TIMEOUT_SEC: u64 = 180;
+
+/// Only the owner can connect, since a peer can run any of the cmds.
+pub const DEFAULT_SOCKET_MODE: u32 = 0o600;
+
+pub async fn serve(server_config: ServerConfig) -> Result<(), ServerError> {
+    let server_config = Arc::new(server_config);
+    let socket_path = server_config
+        .socket_path
+        .clone()
+        .unwrap_or_else(|| default_socket_path().clone());
+    let (uds, owns_socket_file) = match listener_from_systemd()? {
+        Some(uds) => {
+            tracing::info!("using the socket passed by systemd");
+            (uds, false)
+        }
+        None => {
+            remove_stale_socket(&socket_path).await?;
+            tokio::fs::create_dir_all(socket_path.parent().unwrap()).await?;
+            (bind_socket(&socket_path, server_config.socket_mode)?, true)
+        }
+    };
+
+    let app = Router::new()
+        .route("/version", get(version))
+        .route("/metrics", get(render_metrics))
+        .route("/status", get(process_status))
+        .route("/admin/reset", post(reset_all))
+        .route("/admin/reload", post(reload))
+        .route("/cmd/:cmd_name", post(run_cmd))
+        .route("/cmd/:cmd_name/raw", post(run_cmd_raw))
+        .route("/cmd/:cmd_name/batch", post(run_batch))
+        .route("/cmd/:cmd_name/cache", get(cache_stats).delete(flush_cache))
+        .route("/cmd/:cmd_name/kill", post(kill_cmd))
+        .route("/cmd/:cmd_name/eof", post(eof_cmd))
+        .route("/cmd/:cmd_name/restart", post(restart_cmd))
+        .route("/cmd/:cmd_name/reset", post(reset_cmd))
+        .route("/cmd/:cmd_name/recent", get(recent_output))
+        .route("/cmd/:cmd_name/config", get(cmd_config))
+        .route("/cmd/:cmd_name/signal", post(signal_cmd))
+        .route("/cmd/:cmd_name/ws", get(ws_cmd))
+        .layer(
+            ServiceBuilder::new()
+                .layer(RequestLogLayer::default())
+                .layer(HandleErrorLayer::new(|error: BoxError| async move {
+                    if error.is::<tower::timeout::error::Elapsed>() {
+                        Ok(StatusCode::REQUEST_TIMEOUT)
+                    } else {
+                        Err((
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            format!("Unhandled internal error: {}", error),
+                        ))
+                    }
+                }))
+                .timeout(Duration::from_secs(
+                    server_config
+                        .request_timeout_sec
+                        .unwrap_or(DEFAULT_REQUEST_TIMEOUT_SEC),
+                ))
+                .layer(Extension(server_config.clone()))
+                .layer(compression_layer(&server_config))
+                .layer(middleware::from_fn(reject_too_large_body))
+                .into_inner(),
+        );
+
+    if owns_socket_file {
+        tracing::info!("dairi server is listening at {}", socket_path.display());
+    }
+
+    tokio::spawn(reload_on_sighup(server_config.config_path.clone()));
+
+    if let Some(line_protocol_socket) = server_config.line_protocol_socket.clone() {
+        let server_config = server_config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = line_protocol::serve(&line_protocol_socket, server_config).await {
+                tracing::error!("line protocol server error: {}", e);
+            }
+        });
+    }
+
+    let mut builder = axum::Server::builder(ServerAccept { uds })
+        .http1_keepalive(server_config.http1_keep_alive.unwrap_or(true));
+    if let Some(max_concurrent_streams) = server_config.http2_max_concurrent_streams {
+        builder = builder.http2_max_concurrent_streams(max_concurrent_streams);
+    }
+    builder
         .serve(app.into_make_service_with_connect_info::<UdsConnectInfo, _>())
+        .with_graceful_shutdown(shutdown_signal())
         .await
         .unwrap();
 
+    process_manager::shutdown_all().await;
+    if owns_socket_file {
+        tokio::fs::remove_file(&socket_path).await?;
+    }
+    if let Some(line_protocol_socket) = server_config.line_protocol_socket.as_ref() {
+        let _ = tokio::fs::remove_file(line_protocol_socket).await;
+    }
+    tracing::info!("dairi server stopped");
+
     Ok(())
 }
 
+/// The first file descriptor passed by systemd socket activation. see sd_listen_fds(3)
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Returns the listener passed by systemd when the server is started by socket activation.
+fn listener_from_systemd() -> Result<Option<UnixListener>, ServerError> {
+    let listen_pid = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok());
+    let listen_fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<u32>().ok());
+
+    match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds))
+            if listen_pid == std::process::id() && listen_fds > 0 =>
+        {
+            // not to be inherited by the cmd processes
+            std::env::remove_var("LISTEN_PID");
+            std::env::remove_var("LISTEN_FDS");
+            std::env::remove_var("LISTEN_FDNAMES");
+
+            if listen_fds > 1 {
+                tracing::warn!(
+                    "{} sockets are passed by systemd, only the first one is used",
+                    listen_fds
+                );
+            }
+
+            // SAFETY: systemd passes the listening socket at SD_LISTEN_FDS_START and nothing else owns it
+            let listener =
+                unsafe { std::os::unix::net::UnixListener::from_raw_fd(SD_LISTEN_FDS_START) };
+            listener.set_nonblocking(true)?;
+            Ok(Some(UnixListener::from_std(listener)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Tells the private directories of the sockets being bound apart.
+static BIND_DIR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Binds the socket and sets its permissions instead of leaving them to the umask. It's bound
+/// in a private directory and linked into place once the permissions are set, so that no peer
+/// can connect to it in between.
+pub(crate) fn bind_socket(socket_path: &StdPath, mode: Option<u32>) -> io::Result<UnixListener> {
+    let parent = socket_path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| StdPath::new("."));
+    let bind_dir = parent.join(format!(
+        ".dairi-bind-{}-{}",
+        std::process::id(),
+        BIND_DIR_COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::DirBuilder::new().mode(0o700).create(&bind_dir)?;
+    let bind_path = bind_dir.join("s");
+
+    let result = (|| {
+        let listener = UnixListener::bind(&bind_path)?;
+        let mode = mode.unwrap_or(DEFAULT_SOCKET_MODE);
+        std::fs::set_permissions(&bind_path, std::fs::Permissions::from_mode(mode))?;
+        // unlike a rename, the link fails on an existing socket as the bind does
+        std::fs::hard_link(&bind_path, socket_path).map_err(|e| match e.kind() {
+            ErrorKind::AlreadyExists => io::Error::from(ErrorKind::AddrInUse),
+            _ => e,
+        })?;
+        Ok(listener)
+    })();
+    let _ = std::fs::remove_file(&bind_path);
+    let _ = std::fs::remove_dir(&bind_dir);
+    result
+}
+
+/// Removes the socket left by a dairi that is no longer running. Fails if a server is still
+/// accepting connections on it.
+pub(crate) async fn remove_stale_socket(socket_path: &StdPath) -> Result<(), ServerError> {
+    if UnixStream::connect(socket_path).await.is_ok() {
+        return Err(ServerError::AddressInUse(socket_path.to_path_buf()));
+    }
+
+    match tokio::fs::remove_file(socket_path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            let owner_uid = tokio::fs::metadata(socket_path)
+                .await
+                .ok()
+                .map(|metadata| metadata.uid());
+            Err(ServerError::StaleSocketPermissionDenied {
+                path: socket_path.to_path_buf(),
+                owner_uid,
+            })
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reloads the cmds from the config file on each SIGHUP.
+async fn reload_on_sighup(config_path: PathBuf) {
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            tracing::error!("failed to listen SIGHUP: {}", e);
+            return;
+        }
+    };
+
+    while sighup.recv().await.is_some() {
+        match reload_config(&config_path).await {
+            Ok(summary) => tracing::info!("config reloaded: {:?}", summary),
+            Err(e) => tracing::error!("failed to reload config, keeping the current cmds: {}", e),
+        }
+    }
+}
+
+/// Re-reads the config file and replaces the cmd table. Server-wide settings such as
+/// `allowed_uids` and `request_timeout_sec` are kept until the server restarts.
+async fn reload_config(config_path: &StdPath) -> Result<ReloadSummary, RunCmdError> {
+    tracing::info!("reloading config from {}", config_path.display());
+    let config = Config::load_from_path(config_path)?;
+    let cmd_table = config.as_cmd_table()?;
+
+    apply_server_settings(&config);
+    Ok(process_manager::reload_cmd_table(cmd_table).await?)
+}
+
+/// Sets the global settings of the process manager from the config, on the start and on each
+/// reload.
+pub fn apply_server_settings(config: &Config) {
+    process_manager::set_process_limit(config.as_process_limit());
+    process_manager::set_max_input_size(config.max_input_size);
+    process_manager::set_max_output_size_limit(config.max_output_size_limit);
+    process_manager::set_coalesce_requests(config.coalesce_requests);
+    process_manager::set_kill_grace_milli_sec(config.kill_grace_milli_sec);
+    process_manager::set_auto_kill_unhealthy(config.auto_kill_unhealthy);
+}
+
+async fn shutdown_signal() {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            tracing::error!("failed to listen SIGTERM: {}", e);
+            return;
+        }
+    };
+
+    select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = sigterm.recv() => {},
+    }
+    tracing::info!("shutting down dairi server");
+}
+
 struct ServerAccept {
     uds: UnixListener,
 }
@@ -112,27 +430,744 @@ impl Accept for ServerAccept {
 
 #[derive(Serialize, Deserialize)]
 pub struct RunCmdRequest {
+    #[serde(default)]
     pub input: String,
+
+    /// Base64 encoded bytes passed to stdin as is instead of `input`, for the input that isn't
+    /// valid UTF-8. The cmd settings such as `auto_trailing_newline` aren't applied to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_base64: Option<String>,
+
     pub output_size: Option<usize>,
+
+    /// Env vars for the process. They only take effect when the process is spawned.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<HashMap<String, String>>,
+
+    /// Respawns the running process if it was spawned with other `env`, otherwise `env` is
+    /// ignored for a running process.
+    #[serde(default)]
+    pub respawn_for_env: bool,
+
+    /// Overrides the cmd's `wait_output_timeout_milli_sec` for this request. The whole run is
+    /// still bounded by the cmd's `timeout_sec`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wait_output_timeout_milli_sec: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct RunCmdResponse {
     pub output: String,
+
+    /// The results between the prompts, set when the cmd's `prompt_output` is `list`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outputs: Option<Vec<String>>,
+
+    /// `base64` when the output is base64 encoded, otherwise the output is plain text.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+
+    /// The exit code of the process, set for the cmds with `wait_for_exit`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+
+    /// How long the run took, set with `?verbose=true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elapsed_milli_sec: Option<u64>,
+
+    /// The bytes read from the process before the output is arranged, set with `?verbose=true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_bytes: Option<usize>,
+
+    /// The input as passed to stdin after the arrangements of the cmd, set with
+    /// `?echo_input=true`. Not set for `input_base64`, which is passed as it is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arranged_input: Option<String>,
+
+    /// Whether the output matches `error_regex` of the cmd, e.g. a traceback printed by the
+    /// REPL, which keeps running.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_error: bool,
+}
+
+/// Query parameters of `POST /cmd/:cmd_name`.
+#[derive(Deserialize)]
+struct RunCmdParams {
+    #[serde(default)]
+    verbose: bool,
+    #[serde(default)]
+    echo_input: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct VersionResponse {
+    pub version: String,
+}
+
+async fn version() -> Json<VersionResponse> {
+    Json(VersionResponse {
+        version: VERSION.to_string(),
+    })
+}
+
+async fn render_metrics(
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+) -> Result<impl IntoResponse, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
+    Ok((
+        Headers([(CONTENT_TYPE, "text/plain; version=0.0.4")]),
+        metrics().render(),
+    ))
+}
+
+#[derive(Serialize, Debug)]
+pub struct StatusResponse {
+    pub processes: Vec<ProcessStats>,
+}
+
+async fn process_status(
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+) -> Result<Json<StatusResponse>, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
+    Ok(Json(StatusResponse {
+        processes: process_manager::process_stats().await,
+    }))
+}
+
+/// Rejects the request by its `Content-Length` before the body is buffered. The JSON body of a
+/// request without it is limited by `LimitedJson`, and the raw input is counted as it streams
+/// to stdin.
+async fn reject_too_large_body<B>(request: Request<B>, next: Next<B>) -> Response {
+    let size = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    match (size, process_manager::max_input_size()) {
+        (Some(size), Some(max_input_size)) if size > max_input_size => {
+            RunCmdError::from(ProcessManagerError::InputTooLarge {
+                size,
+                max_input_size,
+            })
+            .into_response()
+        }
+        _ => next.run(request).await,
+    }
+}
+
+/// Compresses the response by `Accept-Encoding` when `compress_responses` is set.
+fn compression_layer(server_config: &ServerConfig) -> CompressionLayer<NotStreamed> {
+    let compress_responses = server_config.compress_responses.unwrap_or(false);
+    CompressionLayer::new()
+        .gzip(compress_responses)
+        .deflate(compress_responses)
+        .compress_when(NotStreamed(DefaultPredicate::new()))
+}
+
+/// A streamed response is passed as is, so that its chunks still arrive as the REPL prints
+/// them instead of being held by the encoder.
+#[derive(Clone)]
+struct NotStreamed(DefaultPredicate);
+
+impl Predicate for NotStreamed {
+    fn should_compress<B: HttpBody>(&self, response: &Response<B>) -> bool {
+        response.body().size_hint().exact().is_some() && self.0.should_compress(response)
+    }
+}
+
+/// `Json` buffering the body only up to `max_input_size`, for a chunked body without
+/// `Content-Length`.
+struct LimitedJson<T>(T);
+
+#[async_trait]
+impl<T: DeserializeOwned> FromRequest<Body> for LimitedJson<T> {
+    type Rejection = Response;
+
+    async fn from_request(request: &mut RequestParts<Body>) -> Result<Self, Self::Rejection> {
+        if let Some(max_input_size) = process_manager::max_input_size() {
+            if let Some(body) = request.body_mut() {
+                let mut buffered = BytesMut::new();
+                while let Some(chunk) = body.data().await {
+                    let chunk = chunk.map_err(|e| {
+                        RunCmdError::from(ProcessManagerError::IOError(io::Error::other(e)))
+                            .into_response()
+                    })?;
+                    let size = buffered.len() + chunk.len();
+                    if size > max_input_size {
+                        return Err(RunCmdError::from(ProcessManagerError::InputTooLarge {
+                            size,
+                            max_input_size,
+                        })
+                        .into_response());
+                    }
+                    buffered.extend_from_slice(&chunk);
+                }
+                *body = Body::from(buffered.freeze());
+            }
+        }
+        let Json(value) = Json::<T>::from_request(request)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        Ok(Self(value))
+    }
+}
+
+fn authorize_peer(server_config: &ServerConfig, peer_cred: &UCred) -> Result<(), RunCmdError> {
+    match server_config.allowed_uids.as_ref() {
+        Some(allowed_uids) if !allowed_uids.contains(&peer_cred.uid()) => {
+            tracing::warn!("rejected the request from uid {}", peer_cred.uid());
+            Err(RunCmdError::PeerNotAllowed(peer_cred.uid()))
+        }
+        _ => Ok(()),
+    }
 }
 
 async fn run_cmd(
     Path(cmd_name): Path<process_manager::CmdName>,
-    Json(payload): Json<RunCmdRequest>,
-) -> Result<Json<RunCmdResponse>, RunCmdError> {
+    Query(params): Query<RunCmdParams>,
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+    LimitedJson(payload): LimitedJson<RunCmdRequest>,
+) -> Result<Response, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
     tracing::debug!("run cmd start {}", cmd_name);
-    let output = process_manager::run_cmd(&cmd_name, payload.input, payload.output_size).await?;
+    let options = RunOptions {
+        output_size: payload.output_size,
+        env: payload.env.map(|vars| RequestEnv {
+            vars,
+            respawn: payload.respawn_for_env,
+        }),
+        wait_output_timeout_milli_sec: payload.wait_output_timeout_milli_sec,
+        ..Default::default()
+    };
+    let input = match payload.input_base64 {
+        Some(_) if !payload.input.is_empty() => {
+            return Err(RunCmdError::InvalidInput(
+                "input and input_base64 can't be set together".to_string(),
+            ))
+        }
+        Some(input_base64) => {
+            let bytes = decode_base64(&input_base64)
+                .ok_or_else(|| RunCmdError::InvalidInput("invalid input_base64".to_string()))?;
+            let chunk = futures::stream::once(async move { Ok(Bytes::from(bytes)) });
+            CmdInput::Raw(Box::pin(chunk))
+        }
+        None => payload.input.into(),
+    };
+    let arranged_input = match &input {
+        CmdInput::Text(input) if params.echo_input => {
+            Some(process_manager::arranged_input(&cmd_name, input)?)
+        }
+        _ => None,
+    };
+    let cmd_output = process_manager::run_cmd(&cmd_name, input, options).await?;
+
+    let mut response = into_run_cmd_response(cmd_output, params.verbose)?;
+    response.arranged_input = arranged_input;
+    tracing::info!("cmd:{}, output:  {}", cmd_name, response.output);
+    Ok(into_http_response(response))
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct BatchRunCmdRequest {
+    pub inputs: Vec<String>,
+    pub output_size: Option<usize>,
+    pub wait_output_timeout_milli_sec: Option<u64>,
+}
+
+/// The body of a failed request, and of a failed input of a batch.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ErrorResponse {
+    pub error: String,
+    /// A stable identifier of the error, e.g. `cmd_not_found`.
+    pub kind: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum BatchRunCmdResult {
+    Ok(RunCmdResponse),
+    Err(ErrorResponse),
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct BatchRunCmdResponse {
+    /// The result of each input in the order of `inputs`.
+    pub results: Vec<BatchRunCmdResult>,
+}
+
+async fn run_batch(
+    Path(cmd_name): Path<process_manager::CmdName>,
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+    LimitedJson(payload): LimitedJson<BatchRunCmdRequest>,
+) -> Result<Json<BatchRunCmdResponse>, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
+    tracing::debug!(
+        "run batch start {}, {} inputs",
+        cmd_name,
+        payload.inputs.len()
+    );
+    let options = RunOptions {
+        output_size: payload.output_size,
+        wait_output_timeout_milli_sec: payload.wait_output_timeout_milli_sec,
+        ..Default::default()
+    };
+    let results = process_manager::run_batch(&cmd_name, payload.inputs, options).await?;
+
+    let results = results
+        .into_iter()
+        .map(|result| {
+            match result
+                .map_err(RunCmdError::from)
+                .and_then(|cmd_output| into_run_cmd_response(cmd_output, false))
+            {
+                Ok(response) => BatchRunCmdResult::Ok(response),
+                Err(e) => BatchRunCmdResult::Err(e.as_error_response()),
+            }
+        })
+        .collect();
+    Ok(Json(BatchRunCmdResponse { results }))
+}
+
+/// Query parameters of `POST /cmd/:cmd_name/raw`, of which the body is the input.
+#[derive(Deserialize)]
+struct RawRunCmdParams {
+    #[serde(default)]
+    verbose: bool,
+    output_size: Option<usize>,
+    wait_output_timeout_milli_sec: Option<u64>,
+}
+
+/// Passes the request body to stdin as it is received, without the JSON wrapping and the input
+/// arrangement of the cmd.
+async fn run_cmd_raw(
+    Path(cmd_name): Path<process_manager::CmdName>,
+    Query(params): Query<RawRunCmdParams>,
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+    body: BodyStream,
+) -> Result<Response, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
+    tracing::debug!("run cmd with raw input start {}", cmd_name);
+    let options = RunOptions {
+        output_size: params.output_size,
+        wait_output_timeout_milli_sec: params.wait_output_timeout_milli_sec,
+        ..Default::default()
+    };
+    let input = body.map_err(io::Error::other);
+    let cmd_output =
+        process_manager::run_cmd(&cmd_name, CmdInput::Raw(Box::pin(input)), options).await?;
+
+    let response = into_run_cmd_response(cmd_output, params.verbose)?;
+    tracing::info!("cmd:{}, output:  {}", cmd_name, response.output);
+    Ok(into_http_response(response))
+}
+
+/// Responds with 204 No Content when the cmd printed nothing, so that it can be told apart
+/// from a call that is still waiting.
+fn into_http_response(response: RunCmdResponse) -> Response {
+    let no_output = response.output.is_empty()
+        && response.exit_code.is_none()
+        && response.elapsed_milli_sec.is_none()
+        && response.arranged_input.is_none()
+        && !response.is_error
+        && response
+            .outputs
+            .as_ref()
+            .map(|outputs| outputs.is_empty())
+            .unwrap_or(true);
+    if no_output {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        Json(response).into_response()
+    }
+}
+
+/// Adds the diagnostics of the run with `verbose`.
+fn into_run_cmd_response(
+    cmd_output: CmdOutput,
+    verbose: bool,
+) -> Result<RunCmdResponse, RunCmdError> {
+    let encoding = cmd_output.encoding;
+    let output = encode_output(cmd_output.output, encoding)?;
+    let outputs = cmd_output
+        .segments
+        .map(|segments| {
+            segments
+                .into_iter()
+                .map(|segment| encode_output(segment, encoding))
+                .collect::<Result<Vec<String>, FromUtf8Error>>()
+        })
+        .transpose()?;
+
+    Ok(RunCmdResponse {
+        output,
+        outputs,
+        encoding: (encoding == OutputEncoding::Base64).then(|| "base64".to_string()),
+        exit_code: cmd_output.exit_code,
+        elapsed_milli_sec: verbose.then_some(cmd_output.elapsed.as_millis() as u64),
+        output_bytes: verbose.then_some(cmd_output.output_bytes),
+        arranged_input: None,
+        is_error: cmd_output.is_error,
+    })
+}
+
+fn encode_output(
+    output: process_manager::Output,
+    encoding: OutputEncoding,
+) -> Result<String, FromUtf8Error> {
+    match encoding {
+        OutputEncoding::Utf8Lossy => Ok(String::from_utf8_lossy(&output).into_owned()),
+        OutputEncoding::Utf8Strict => String::from_utf8(output),
+        OutputEncoding::Base64 => Ok(encode_base64(&output)),
+    }
+}
 
-    tracing::debug!("cmd finished [{}]", String::from_utf8(output.clone())?);
-    let output = String::from_utf8(output)?;
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
 
-    tracing::info!("cmd:{}, output:  {}", cmd_name, output);
-    Ok(Json(RunCmdResponse { output }))
+/// Encodes with the standard alphabet and padding of RFC 4648.
+pub(crate) fn encode_base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decodes the padded standard base64 of `encode_base64`, `None` if it's malformed.
+pub(crate) fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+    for (i, chunk) in encoded.chunks(4).enumerate() {
+        let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && i != encoded.len() / 4 - 1) {
+            return None;
+        }
+        let mut n = 0u32;
+        for c in &chunk[..4 - padding] {
+            let value = BASE64_ALPHABET.iter().position(|a| a == c)? as u32;
+            n = n << 6 | value;
+        }
+        n <<= 6 * padding;
+        decoded.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(decoded)
+}
+
+async fn cache_stats(
+    Path(cmd_name): Path<process_manager::CmdName>,
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+) -> Result<Json<CacheStats>, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
+    Ok(Json(process_manager::cache_stats(&cmd_name).await?))
+}
+
+async fn flush_cache(
+    Path(cmd_name): Path<process_manager::CmdName>,
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+) -> Result<StatusCode, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
+    process_manager::flush_cache(&cmd_name).await?;
+    tracing::info!("cache flushed: {}", cmd_name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn kill_cmd(
+    Path(cmd_name): Path<process_manager::CmdName>,
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+) -> Result<StatusCode, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
+    process_manager::kill_cmd(&cmd_name).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn eof_cmd(
+    Path(cmd_name): Path<process_manager::CmdName>,
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+) -> Result<StatusCode, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
+    process_manager::eof_cmd(&cmd_name).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn restart_cmd(
+    Path(cmd_name): Path<process_manager::CmdName>,
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+) -> Result<StatusCode, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
+    process_manager::restart_cmd(&cmd_name).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn reset_cmd(
+    Path(cmd_name): Path<process_manager::CmdName>,
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+) -> Result<StatusCode, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
+    process_manager::reset_cmd(&cmd_name).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Returns the settings of the cmd as dairi loaded them, e.g. to check the expanded args.
+async fn cmd_config(
+    Path(cmd_name): Path<process_manager::CmdName>,
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+) -> Result<Json<serde_json::Value>, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
+    let cmd = process_manager::cmd_config(&cmd_name)?;
+    Ok(Json(redacted_cmd_config(&cmd)))
+}
+
+/// Lists only the names of the env vars, since their values may be tokens.
+fn redacted_cmd_config(cmd: &process_manager::Cmd) -> serde_json::Value {
+    let mut env_names: Vec<&String> = cmd.env.keys().collect();
+    env_names.sort();
+    let mut cmd_config = serde_json::to_value(cmd).unwrap_or_default();
+    cmd_config["env"] = serde_json::json!(env_names);
+    cmd_config
+}
+
+const DEFAULT_RECENT_OUTPUT_LINES: usize = 10;
+
+#[derive(Deserialize)]
+struct RecentOutputParams {
+    n: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct RecentOutputResponse {
+    /// Oldest first.
+    pub lines: Vec<String>,
+}
+
+/// Returns the last `n` output lines of the running process, 10 by default.
+async fn recent_output(
+    Path(cmd_name): Path<process_manager::CmdName>,
+    Query(params): Query<RecentOutputParams>,
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+) -> Result<Json<RecentOutputResponse>, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
+    let n = params.n.unwrap_or(DEFAULT_RECENT_OUTPUT_LINES);
+    let lines = process_manager::recent_output(&cmd_name, n).await?;
+    Ok(Json(RecentOutputResponse { lines }))
+}
+
+/// Stops all the running processes, e.g. after editing many cmds.
+async fn reset_all(
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+) -> Result<StatusCode, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
+    let names = process_manager::reset_all().await;
+    tracing::info!("reset all processes: {:?}", names);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Same as SIGHUP, for the environments where sending a signal is awkward. Responds with the
+/// cmds added, removed and changed by the reload.
+async fn reload(
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+) -> Result<Json<ReloadSummary>, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
+    let summary = reload_config(&server_config.config_path).await?;
+    tracing::info!("config reloaded: {:?}", summary);
+    Ok(Json(summary))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignalRequest {
+    /// One of `INT`, `TERM`, `KILL` and `HUP`.
+    pub signal: String,
+}
+
+async fn signal_cmd(
+    Path(cmd_name): Path<process_manager::CmdName>,
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+    Json(request): Json<SignalRequest>,
+) -> Result<StatusCode, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
+    let signal = Signal::from_name(&request.signal)
+        .ok_or_else(|| RunCmdError::InvalidSignal(request.signal.clone()))?;
+    process_manager::signal_cmd(&cmd_name, signal).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// How long the close frame of the client is waited for after the server sent its own.
+const WEBSOCKET_CLOSE_TIMEOUT_MILLI_SEC: u64 = 1000;
+
+/// The max size of a WebSocket message or frame unless `max_input_size` is set, so that a
+/// forged frame length can't make the server allocate it.
+const DEFAULT_WEBSOCKET_MAX_MESSAGE_SIZE: usize = 16 << 20;
+
+const CLOSE_NORMAL: CloseCode = 1000;
+const CLOSE_PROTOCOL_ERROR: CloseCode = 1002;
+const CLOSE_UNSUPPORTED_DATA: CloseCode = 1003;
+const CLOSE_INVALID_PAYLOAD: CloseCode = 1007;
+const CLOSE_MESSAGE_TOO_BIG: CloseCode = 1009;
+const CLOSE_INTERNAL_ERROR: CloseCode = 1011;
+
+/// Upgrades to a WebSocket, of which each text message is passed to the cmd as an input and
+/// the output is sent back chunk by chunk as it's read. The cmd is locked for the connection,
+/// so the other requests to it wait until the socket is closed.
+async fn ws_cmd(
+    Path(cmd_name): Path<process_manager::CmdName>,
+    ConnectInfo(connect_info): ConnectInfo<UdsConnectInfo>,
+    Extension(server_config): Extension<Arc<ServerConfig>>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, RunCmdError> {
+    authorize_peer(&server_config, &connect_info.peer_cred)?;
+    let max_size = process_manager::max_input_size().unwrap_or(DEFAULT_WEBSOCKET_MAX_MESSAGE_SIZE);
+    // a missing or busy cmd fails the handshake
+    let session = process_manager::open_session(&cmd_name).await?;
+    Ok(ws
+        .max_message_size(max_size)
+        .max_frame_size(max_size)
+        .on_upgrade(move |socket| serve_websocket(socket, session)))
+}
+
+async fn serve_websocket(mut socket: WebSocket, mut session: CmdSession) {
+    let close_by_server = loop {
+        let message = select! {
+            message = socket.recv() => message,
+            _ = session.process_exit() => break close_message(CLOSE_NORMAL, "process exited"),
+        };
+        match message {
+            Some(Ok(Message::Text(input))) => {
+                if let Err(close) = pass_websocket_input(&mut session, input, &mut socket).await {
+                    break close;
+                }
+            }
+            Some(Ok(Message::Binary(_))) => {
+                break close_message(CLOSE_UNSUPPORTED_DATA, "only text is passed")
+            }
+            // a ping is answered by tungstenite
+            Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+            Some(Ok(Message::Close(_))) => {
+                // flushes the reply to the close frame
+                let _ = socket.close().await;
+                return;
+            }
+            None => {
+                tracing::debug!("WebSocket disconnected without a close frame");
+                return;
+            }
+            Some(Err(e)) => {
+                tracing::warn!("WebSocket error: {}", e);
+                break close_message(websocket_error_close_code(&e), &e.to_string());
+            }
+        }
+    };
+
+    if socket.send(close_by_server).await.is_err() {
+        return;
+    }
+    let _ = tokio::time::timeout(
+        Duration::from_millis(WEBSOCKET_CLOSE_TIMEOUT_MILLI_SEC),
+        async {
+            while let Some(Ok(message)) = socket.recv().await {
+                if matches!(message, Message::Close(_)) {
+                    break;
+                }
+            }
+        },
+    )
+    .await;
+}
+
+/// The close frame with the reason cut to fit in a control frame.
+fn close_message(code: CloseCode, reason: &str) -> Message {
+    let mut end = reason.len().min(123);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    Message::Close(Some(CloseFrame {
+        code,
+        reason: reason[..end].to_string().into(),
+    }))
+}
+
+fn websocket_error_close_code(error: &axum::Error) -> CloseCode {
+    match std::error::Error::source(error)
+        .and_then(|source| source.downcast_ref::<tungstenite::Error>())
+    {
+        Some(tungstenite::Error::Protocol(_)) => CLOSE_PROTOCOL_ERROR,
+        Some(tungstenite::Error::Capacity(_)) => CLOSE_MESSAGE_TOO_BIG,
+        Some(tungstenite::Error::Utf8) => CLOSE_INVALID_PAYLOAD,
+        _ => CLOSE_INTERNAL_ERROR,
+    }
+}
+
+/// Passes the input and sends each output chunk as a message as soon as it's read. A chunk
+/// that isn't valid UTF-8 is sent as a binary message. Returns the close frame to end the
+/// connection with on an error.
+async fn pass_websocket_input(
+    session: &mut CmdSession,
+    input: String,
+    socket: &mut WebSocket,
+) -> Result<(), Message> {
+    async fn send_chunk(socket: &mut WebSocket, chunk: Vec<u8>) -> Result<(), Message> {
+        let message = match String::from_utf8(chunk) {
+            Ok(text) => Message::Text(text),
+            Err(e) => Message::Binary(e.into_bytes()),
+        };
+        socket.send(message).await.map_err(|_| Message::Close(None))
+    }
+
+    let (chunk_sender, mut chunks) = mpsc::unbounded_channel();
+    let options = RunOptions {
+        output_chunks: Some(OutputChunks(chunk_sender)),
+        ..Default::default()
+    };
+    let result = {
+        let run = session.run(input.into(), options);
+        tokio::pin!(run);
+        loop {
+            select! {
+                result = &mut run => break result,
+                Some(chunk) = chunks.recv() => send_chunk(socket, chunk).await?,
+            }
+        }
+    };
+    // the sender is dropped with the run
+    while let Some(chunk) = chunks.recv().await {
+        send_chunk(socket, chunk).await?;
+    }
+
+    result.map(|_| ()).map_err(|e| {
+        let error = RunCmdError::from(e).as_error_response();
+        close_message(
+            CLOSE_INTERNAL_ERROR,
+            &format!("{} ({})", error.error, error.kind),
+        )
+    })
 }
 
 #[derive(Debug, Error)]
@@ -142,15 +1177,575 @@ pub enum RunCmdError {
 
     #[error("{0}")]
     FromUtf8Error(#[from] FromUtf8Error),
+
+    #[error("uid {0} is not allowed to run cmds")]
+    PeerNotAllowed(u32),
+
+    #[error("unknown signal: {0}")]
+    InvalidSignal(String),
+
+    #[error("{0}")]
+    InvalidInput(String),
+
+    #[error("invalid config: {0}")]
+    InvalidConfig(#[from] ConfigError),
+}
+
+impl RunCmdError {
+    fn status_code(&self) -> StatusCode {
+        use process_manager::ProcessManagerError::*;
+        fn process_manager_status_code(e: &process_manager::ProcessManagerError) -> StatusCode {
+            match e {
+                CmdNotFound(_) | ProcessNotRunning(_) | CacheNotEnabled(_) => StatusCode::NOT_FOUND,
+                Timeout(_) => StatusCode::REQUEST_TIMEOUT,
+                CmdTableNotInitialize | StdinWriteTimeout(_) | TooManyProcesses(_) => {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+                InputTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                CmdBusy(_) | Superseded(_) => StatusCode::CONFLICT,
+                RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+                EmptyInputNotAllowed | InputNotEncodable { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+                ResetNotConfigured(_) => StatusCode::BAD_REQUEST,
+                FailedToGetChildProcessStdin(_)
+                | FailedToGetChildProcessStdout(_)
+                | FailedToGetChildProcessStderr(_)
+                | FailedToAddProcessTable(_)
+                | RegexError(_)
+                | ProgramNotFound(_)
+                | SpawnFailed { .. }
+                | ProcessExitedEarly { .. }
+                | ProcessUnhealthy { .. }
+                | IOError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                Coalesced(e) => process_manager_status_code(e),
+            }
+        }
+
+        match self {
+            RunCmdError::ProcessManagerError(e) => process_manager_status_code(e),
+            RunCmdError::FromUtf8Error(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            RunCmdError::PeerNotAllowed(_) => StatusCode::FORBIDDEN,
+            RunCmdError::InvalidSignal(_) | RunCmdError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            RunCmdError::InvalidConfig(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_kind(&self) -> &'static str {
+        match self {
+            RunCmdError::ProcessManagerError(e) => e.kind(),
+            RunCmdError::FromUtf8Error(_) => "invalid_utf8_output",
+            RunCmdError::PeerNotAllowed(_) => "peer_not_allowed",
+            RunCmdError::InvalidSignal(_) => "invalid_signal",
+            RunCmdError::InvalidInput(_) => "invalid_input",
+            RunCmdError::InvalidConfig(_) => "invalid_config",
+        }
+    }
+
+    fn as_error_response(&self) -> ErrorResponse {
+        ErrorResponse {
+            error: format!("{}", self),
+            kind: self.error_kind().to_string(),
+        }
+    }
 }
 
 impl IntoResponse for RunCmdError {
     fn into_response(self) -> Response {
-        let status_code = StatusCode::BAD_REQUEST;
-        let body = Json(RunCmdResponse {
-            output: format!("{}", self),
-        });
+        let status_code = self.status_code();
+        let body = Json(self.as_error_response());
 
         (status_code, body).into_response()
     }
 }
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_resolve_default_socket_path() {
+        assert_eq!(
+            PathBuf::from("/run/user/1000/dairi/serve.sock"),
+            resolve_default_socket_path(Some("/run/user/1000".into()))
+        );
+        assert_eq!(
+            PathBuf::from("/tmp/dairi/serve.sock"),
+            resolve_default_socket_path(None)
+        );
+        assert_eq!(
+            PathBuf::from("/tmp/dairi/serve.sock"),
+            resolve_default_socket_path(Some("".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_socket() {
+        let dir = std::env::temp_dir().join(format!("dairi-bind-socket-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket_path = dir.join("serve.sock");
+
+        let listener = bind_socket(&socket_path, Some(0o640)).unwrap();
+        let metadata = std::fs::metadata(&socket_path).unwrap();
+        assert_eq!(0o640, metadata.mode() & 0o777);
+        // the private directory to bind in is removed
+        assert_eq!(1, std::fs::read_dir(&dir).unwrap().count());
+        UnixStream::connect(&socket_path).await.unwrap();
+
+        assert_eq!(
+            ErrorKind::AddrInUse,
+            bind_socket(&socket_path, None).unwrap_err().kind()
+        );
+        assert_eq!(1, std::fs::read_dir(&dir).unwrap().count());
+
+        drop(listener);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remove_stale_socket() {
+        let dir = std::env::temp_dir().join(format!("dairi-stale-socket-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket_path = dir.join("serve.sock");
+
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        assert!(matches!(
+            remove_stale_socket(&socket_path).await,
+            Err(ServerError::AddressInUse(_))
+        ));
+        assert!(socket_path.exists());
+
+        drop(listener);
+        remove_stale_socket(&socket_path).await.unwrap();
+        assert!(!socket_path.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reject_too_large_body() {
+        use axum::body::Body;
+        use tower::ServiceExt;
+
+        let app = Router::new()
+            .route("/", post(|| async { "ok" }))
+            .layer(middleware::from_fn(reject_too_large_body));
+        let request = |body: &'static str| {
+            Request::post("/")
+                .header(CONTENT_LENGTH, body.len())
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        process_manager::set_max_input_size(Some(8));
+        let response = app.clone().oneshot(request("1 + 1")).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+
+        let response = app.oneshot(request("using LinearAlgebra")).await.unwrap();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+        process_manager::set_max_input_size(None);
+    }
+
+    #[tokio::test]
+    async fn test_compress_response() {
+        use axum::body::{Body, StreamBody};
+        use axum::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+        use flate2::read::{GzDecoder, ZlibDecoder};
+        use std::io::Read;
+        use tower::ServiceExt;
+
+        let output = "julia> 1 + 1\n2\n".repeat(100);
+        let app = |compress_responses| {
+            let output = output.clone();
+            Router::new()
+                .route("/", get(|| async move { output }))
+                .layer(compression_layer(&ServerConfig {
+                    compress_responses,
+                    ..Default::default()
+                }))
+        };
+        let request = |accept_encoding: Option<&'static str>| {
+            let mut request = Request::get("/");
+            if let Some(accept_encoding) = accept_encoding {
+                request = request.header(ACCEPT_ENCODING, accept_encoding);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        for accept_encoding in ["gzip", "deflate"] {
+            let response = app(Some(true))
+                .oneshot(request(Some(accept_encoding)))
+                .await
+                .unwrap();
+            assert_eq!(accept_encoding, response.headers()[CONTENT_ENCODING]);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert!(body.len() < output.len());
+            let mut decompressed = String::new();
+            if accept_encoding == "gzip" {
+                GzDecoder::new(&body[..])
+                    .read_to_string(&mut decompressed)
+                    .unwrap();
+            } else {
+                ZlibDecoder::new(&body[..])
+                    .read_to_string(&mut decompressed)
+                    .unwrap();
+            }
+            assert_eq!(output, decompressed);
+        }
+
+        // neither without the header nor without the toggle
+        for response in [
+            app(Some(true)).oneshot(request(None)).await.unwrap(),
+            app(None).oneshot(request(Some("gzip"))).await.unwrap(),
+        ] {
+            assert!(!response.headers().contains_key(CONTENT_ENCODING));
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(output.as_bytes(), body);
+        }
+
+        // nor a streamed response
+        let response = Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    StreamBody::new(futures::stream::iter(vec![Ok::<_, io::Error>(
+                        "julia> 1 + 1\n2\n".repeat(100),
+                    )]))
+                }),
+            )
+            .layer(compression_layer(&ServerConfig {
+                compress_responses: Some(true),
+                ..Default::default()
+            }))
+            .oneshot(request(Some("gzip")))
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        use axum::body::Body;
+        use tower::ServiceExt;
+
+        let app = Router::new().route("/version", get(version));
+        let response = app
+            .oneshot(Request::get("/version").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let response: VersionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(env!("CARGO_PKG_VERSION"), response.version);
+    }
+
+    #[tokio::test]
+    async fn test_reject_peer_not_allowed() {
+        use axum::body::Body;
+        use connect_info::Connected;
+        use tower::ServiceExt;
+
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let connect_info = UdsConnectInfo::connect_info(&stream);
+        let uid = connect_info.peer_cred.uid();
+        let app = |allowed_uid| {
+            Router::new()
+                .route("/metrics", get(render_metrics))
+                .route("/status", get(process_status))
+                .layer(Extension(Arc::new(ServerConfig {
+                    allowed_uids: Some(vec![allowed_uid]),
+                    ..Default::default()
+                })))
+        };
+        let request = |path| {
+            let mut request = Request::get(path).body(Body::empty()).unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(connect_info.clone()));
+            request
+        };
+
+        for path in ["/metrics", "/status"] {
+            let response = app(uid + 1).oneshot(request(path)).await.unwrap();
+            assert_eq!(StatusCode::FORBIDDEN, response.status(), "{}", path);
+        }
+        let response = app(uid).oneshot(request("/metrics")).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    #[test]
+    fn test_verbose_response() {
+        let cmd_output = || CmdOutput {
+            output: b"hello\n".to_vec(),
+            segments: None,
+            encoding: OutputEncoding::Utf8Lossy,
+            exit_code: None,
+            output_bytes: 12,
+            elapsed: Duration::from_millis(42),
+            is_error: false,
+        };
+
+        let response = into_run_cmd_response(cmd_output(), false).unwrap();
+        assert_eq!(
+            serde_json::json!({"output": "hello\n"}),
+            serde_json::to_value(&response).unwrap()
+        );
+
+        let response = into_run_cmd_response(cmd_output(), true).unwrap();
+        assert_eq!(
+            serde_json::json!({"output": "hello\n", "elapsed_milli_sec": 42, "output_bytes": 12}),
+            serde_json::to_value(&response).unwrap()
+        );
+
+        let response = into_run_cmd_response(
+            CmdOutput {
+                is_error: true,
+                ..cmd_output()
+            },
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::json!({"output": "hello\n", "is_error": true}),
+            serde_json::to_value(&response).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_redacted_cmd_config() {
+        let cmd = process_manager::Cmd {
+            name: "julia".to_string(),
+            cmd: "julia".to_string(),
+            env: HashMap::from([
+                ("JULIA_TOKEN".to_string(), "secret".to_string()),
+                ("JULIA_NUM_THREADS".to_string(), "4".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let cmd_config = redacted_cmd_config(&cmd);
+        assert_eq!("julia", cmd_config["cmd"]);
+        assert_eq!(
+            serde_json::json!(["JULIA_NUM_THREADS", "JULIA_TOKEN"]),
+            cmd_config["env"]
+        );
+        assert!(!cmd_config.to_string().contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_error_response() {
+        let error = RunCmdError::from(ProcessManagerError::CmdNotFound("julia".to_string()));
+        let response = error.into_response();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            serde_json::json!({"error": "cmd not found. name:julia", "kind": "cmd_not_found"}),
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_status_code_and_kind() {
+        let name = || "julia".to_string();
+        let elapsed = tokio::time::timeout(Duration::ZERO, futures::future::pending::<()>())
+            .await
+            .unwrap_err();
+        let io_error = || std::io::Error::other("broken");
+        let unclosed_group = "(".to_string();
+
+        for (error, status_code, kind) in [
+            (
+                ProcessManagerError::CmdTableNotInitialize,
+                StatusCode::SERVICE_UNAVAILABLE,
+                "cmd_table_not_initialized",
+            ),
+            (
+                ProcessManagerError::CmdNotFound(name()),
+                StatusCode::NOT_FOUND,
+                "cmd_not_found",
+            ),
+            (
+                ProcessManagerError::FailedToGetChildProcessStdin(name()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed_to_get_stdin",
+            ),
+            (
+                ProcessManagerError::RegexError(regex::Regex::new(&unclosed_group).unwrap_err()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "invalid_regex",
+            ),
+            (
+                ProcessManagerError::Timeout(elapsed),
+                StatusCode::REQUEST_TIMEOUT,
+                "timeout",
+            ),
+            (
+                ProcessManagerError::FailedToAddProcessTable(name()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed_to_add_process_table",
+            ),
+            (
+                ProcessManagerError::FailedToGetChildProcessStdout(name()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed_to_get_stdout",
+            ),
+            (
+                ProcessManagerError::FailedToGetChildProcessStderr(name()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed_to_get_stderr",
+            ),
+            (
+                ProcessManagerError::EmptyInputNotAllowed,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "empty_input_not_allowed",
+            ),
+            (
+                ProcessManagerError::ProcessNotRunning(name()),
+                StatusCode::NOT_FOUND,
+                "process_not_running",
+            ),
+            (
+                ProcessManagerError::CacheNotEnabled(name()),
+                StatusCode::NOT_FOUND,
+                "cache_not_enabled",
+            ),
+            (
+                ProcessManagerError::StdinWriteTimeout(name()),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "stdin_write_timeout",
+            ),
+            (
+                ProcessManagerError::TooManyProcesses(8),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "too_many_processes",
+            ),
+            (
+                ProcessManagerError::InputTooLarge {
+                    size: 17,
+                    max_input_size: 16,
+                },
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "input_too_large",
+            ),
+            (
+                ProcessManagerError::CmdBusy(name()),
+                StatusCode::CONFLICT,
+                "cmd_busy",
+            ),
+            (
+                ProcessManagerError::ResetNotConfigured(name()),
+                StatusCode::BAD_REQUEST,
+                "reset_not_configured",
+            ),
+            (
+                ProcessManagerError::RateLimited {
+                    name: name(),
+                    rate_limit_per_sec: 3,
+                },
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+            ),
+            (
+                ProcessManagerError::ProgramNotFound("julia".to_string()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "program_not_found",
+            ),
+            (
+                ProcessManagerError::SpawnFailed {
+                    program: "julia".to_string(),
+                    attempts: 3,
+                    source: io_error(),
+                },
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "spawn_failed",
+            ),
+            (
+                ProcessManagerError::ProcessExitedEarly {
+                    name: name(),
+                    exit_code: Some(1),
+                    output: String::new(),
+                },
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "process_exited_early",
+            ),
+            (
+                ProcessManagerError::ProcessUnhealthy {
+                    name: name(),
+                    status: None,
+                    error: String::new(),
+                },
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "process_unhealthy",
+            ),
+            (
+                ProcessManagerError::Superseded(name()),
+                StatusCode::CONFLICT,
+                "superseded",
+            ),
+            (
+                ProcessManagerError::InputNotEncodable {
+                    encoding: process_manager::InputEncoding::Latin1,
+                    ch: '字',
+                },
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "input_not_encodable",
+            ),
+            (
+                ProcessManagerError::IOError(io_error()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "io_error",
+            ),
+            // the coalesced error is mapped as the error of the in-flight request
+            (
+                ProcessManagerError::Coalesced(Arc::new(ProcessManagerError::CmdBusy(name()))),
+                StatusCode::CONFLICT,
+                "cmd_busy",
+            ),
+        ] {
+            let error = RunCmdError::from(error);
+            assert_eq!(status_code, error.status_code(), "{}", error);
+            assert_eq!(kind, error.error_kind(), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_decode_base64() {
+        for bytes in [
+            &b""[..],
+            b"f",
+            b"fo",
+            b"foo",
+            b"foobar",
+            &[0xff, 0xfe, 0xfd, 0x00],
+        ] {
+            assert_eq!(Some(bytes.to_vec()), decode_base64(&encode_base64(bytes)));
+        }
+        assert_eq!(None, decode_base64("Zg="));
+        assert_eq!(None, decode_base64("Zg=a"));
+        assert_eq!(None, decode_base64("Zg==Zg=="));
+        assert_eq!(None, decode_base64("Z==="));
+        assert_eq!(None, decode_base64("Zm9*"));
+    }
+
+    #[test]
+    fn test_encode_output() {
+        assert_eq!("", encode_base64(b""));
+        assert_eq!("Zg==", encode_base64(b"f"));
+        assert_eq!("Zm8=", encode_base64(b"fo"));
+        assert_eq!("Zm9v", encode_base64(b"foo"));
+        assert_eq!("Zm9vYmFy", encode_base64(b"foobar"));
+        assert_eq!("//79", encode_base64(&[0xff, 0xfe, 0xfd]));
+
+        let output = b"a\xffb".to_vec();
+        assert_eq!(
+            "a\u{fffd}b",
+            encode_output(output.clone(), OutputEncoding::Utf8Lossy).unwrap()
+        );
+        assert!(encode_output(output.clone(), OutputEncoding::Utf8Strict).is_err());
+        assert_eq!(
+            "Yf9i",
+            encode_output(output, OutputEncoding::Base64).unwrap()
+        );
+    }
+}
@@ -0,0 +1,570 @@
+use dairi::process_manager::{self, Cmd, CmdTable, OutputEncoding};
+use dairi::server::{self, RunCmdResponse, ServerConfig};
+use dairi::{build_client_and_request, build_client_and_request_bytes, ClientError};
+
+use once_cell::sync::Lazy;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::time::{sleep, Duration};
+
+fn test_socket_path(test_name: &str) -> &'static Path {
+    let path = std::env::temp_dir()
+        .join(format!("dairi-test-{}", std::process::id()))
+        .join(test_name)
+        .join("serve.sock");
+    Box::leak(path.into_boxed_path())
+}
+
+async fn wait_for_socket(socket_path: &Path) {
+    for _ in 0..50 {
+        if socket_path.exists() {
+            return;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    panic!("server didn't start at {}", socket_path.display());
+}
+
+/// Sends the request on a new connection and returns the whole response with the headers.
+async fn post(socket_path: &Path, path: &str, body: &str) -> String {
+    let mut stream = UnixStream::connect(socket_path).await.unwrap();
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+/// Same as `post`, with the body sent in chunks without `Content-Length`.
+async fn post_chunked(socket_path: &Path, path: &str, chunks: &[&str]) -> String {
+    let mut stream = UnixStream::connect(socket_path).await.unwrap();
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+        path
+    );
+    for chunk in chunks {
+        request.push_str(&format!("{:x}\r\n{}\r\n", chunk.len(), chunk));
+    }
+    request.push_str("0\r\n\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+/// Opens a WebSocket and returns the stream with the response to the handshake.
+async fn ws_connect(socket_path: &Path, path: &str) -> (UnixStream, String) {
+    let mut stream = UnixStream::connect(socket_path).await.unwrap();
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        path
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        response.push(stream.read_u8().await.unwrap());
+    }
+    (stream, String::from_utf8(response).unwrap())
+}
+
+/// Sends a masked frame as a client does.
+async fn ws_send(stream: &mut UnixStream, opcode: u8, payload: &[u8]) {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    stream.write_all(&frame).await.unwrap();
+}
+
+/// Reads a short unmasked frame as the server sends it.
+async fn ws_read(stream: &mut UnixStream) -> (u8, Vec<u8>) {
+    let opcode = stream.read_u8().await.unwrap() & 0x0f;
+    let len = stream.read_u8().await.unwrap();
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload).await.unwrap();
+    (opcode, payload)
+}
+
+fn test_cmd_table() -> CmdTable {
+    let mut cmd_table = CmdTable::new();
+    cmd_table.insert(
+        "cat".to_string(),
+        Cmd {
+            name: "cat".to_string(),
+            cmd: "cat".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(200),
+            ..Default::default()
+        },
+    );
+    cmd_table.insert(
+        "bytes".to_string(),
+        Cmd {
+            name: "bytes".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(200),
+            output_encoding: Some(OutputEncoding::Base64),
+            ..Default::default()
+        },
+    );
+    cmd_table.insert(
+        "sh".to_string(),
+        Cmd {
+            name: "sh".to_string(),
+            cmd: "sh".to_string(),
+            output_size: 1024,
+            auto_trailing_newline: true,
+            wait_output_timeout_milli_sec: Some(200),
+            ..Default::default()
+        },
+    );
+    for (name, fallback) in [
+        ("missing", "cat"),
+        ("cycle_a", "cycle_b"),
+        ("cycle_b", "cycle_a"),
+    ] {
+        cmd_table.insert(
+            name.to_string(),
+            Cmd {
+                name: name.to_string(),
+                cmd: "no_such_dairi_program".to_string(),
+                fallback: Some(fallback.to_string()),
+                ..Default::default()
+            },
+        );
+    }
+    cmd_table.insert(
+        "silent".to_string(),
+        Cmd {
+            name: "silent".to_string(),
+            cmd: "head -n 1 > /dev/null".to_string(),
+            use_shell: true,
+            output_size: 1024,
+            auto_trailing_newline: true,
+            ..Default::default()
+        },
+    );
+    cmd_table
+}
+
+/// Runs the tests one at a time, since they share the cmd table, the processes and the
+/// settings of the process manager.
+static SERIAL: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+struct TestServer {
+    socket_path: &'static Path,
+    config_path: PathBuf,
+    _serial: MutexGuard<'static, ()>,
+}
+
+impl TestServer {
+    /// Resets the cmd table and serves it on a socket of the test.
+    async fn start(test_name: &str) -> Self {
+        let serial = SERIAL.lock().await;
+        if process_manager::init_cmd_table(test_cmd_table()).is_err() {
+            process_manager::reload_cmd_table(test_cmd_table())
+                .await
+                .unwrap();
+        }
+
+        let socket_path = test_socket_path(test_name);
+        let _ = std::fs::remove_dir_all(socket_path.parent().unwrap());
+        let config_path = socket_path.parent().unwrap().join("config.toml");
+        tokio::spawn(server::serve(ServerConfig {
+            socket_path: Some(PathBuf::from(socket_path)),
+            config_path: config_path.clone(),
+            ..Default::default()
+        }));
+        wait_for_socket(socket_path).await;
+        Self {
+            socket_path,
+            config_path,
+            _serial: serial,
+        }
+    }
+
+    /// Stops the processes, which are bound to the runtime of the test.
+    async fn stop(self) {
+        process_manager::shutdown_all().await;
+        let _ = std::fs::remove_dir_all(self.socket_path.parent().unwrap());
+    }
+}
+
+#[tokio::test]
+async fn test_run_cmd_round_trip() {
+    let server = TestServer::start("round_trip").await;
+    let socket_path = server.socket_path;
+    let mode = std::fs::metadata(socket_path).unwrap().permissions().mode();
+    assert_eq!(0o600, mode & 0o777);
+
+    let response = build_client_and_request("cat", socket_path, "hello".to_string())
+        .await
+        .unwrap();
+    assert_eq!(
+        RunCmdResponse {
+            output: "hello\n".to_string(),
+            outputs: None,
+            encoding: None,
+            exit_code: None,
+            elapsed_milli_sec: None,
+            output_bytes: None,
+            arranged_input: None,
+            is_error: false,
+        },
+        response
+    );
+
+    assert_eq!(
+        vec!["hello".to_string()],
+        process_manager::recent_output(&"cat".to_string(), 10)
+            .await
+            .unwrap()
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_echo_input() {
+    let server = TestServer::start("echo_input").await;
+
+    let response = post(
+        server.socket_path,
+        "/cmd/cat?echo_input=true",
+        r#"{"input":"a\r\nb"}"#,
+    )
+    .await;
+    assert!(
+        response.contains(r#""output":"a\nb\n","arranged_input":"a\nb\n""#),
+        "{}",
+        response
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_cmd_config() {
+    let server = TestServer::start("cmd_config").await;
+
+    let cmd_config =
+        serde_json::to_value(&*process_manager::cmd_config(&"cat".to_string()).unwrap()).unwrap();
+    assert_eq!("cat", cmd_config["cmd"]);
+    assert_eq!(200, cmd_config["wait_output_timeout_milli_sec"]);
+    assert!(cmd_config["timeout_sec"].is_null());
+    assert!(matches!(
+        process_manager::cmd_config(&"no_such_cmd".to_string()),
+        Err(process_manager::ProcessManagerError::CmdNotFound(_))
+    ));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_reuse_connection() {
+    let server = TestServer::start("reuse_connection").await;
+
+    // the requests in a row share the connection
+    let stream = UnixStream::connect(server.socket_path).await.unwrap();
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+    let connection = tokio::spawn(connection);
+    for _ in 0..20 {
+        futures::future::poll_fn(|cx| sender.poll_ready(cx))
+            .await
+            .unwrap();
+        let request = hyper::Request::get("http://localhost/version")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(200, response.status());
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+    }
+    drop(sender);
+    connection.await.unwrap().unwrap();
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_base64_output() {
+    let server = TestServer::start("base64_output").await;
+    let socket_path = server.socket_path;
+
+    let input = r"printf '\377\376'".to_string();
+    let response = build_client_and_request("bytes", socket_path, input)
+        .await
+        .unwrap();
+    assert_eq!("//4=", response.output);
+    assert_eq!(Some("base64".to_string()), response.encoding);
+
+    // not valid UTF-8, passed as base64
+    let input = b"echo '\xff\xfe'\n".to_vec();
+    let response = build_client_and_request_bytes("bytes", socket_path, input)
+        .await
+        .unwrap();
+    assert_eq!("//4K", response.output);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_empty_output() {
+    let server = TestServer::start("empty_output").await;
+
+    let response = build_client_and_request("silent", server.socket_path, "hello".to_string())
+        .await
+        .unwrap();
+    assert_eq!("", response.output);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_fallback() {
+    let server = TestServer::start("fallback").await;
+    let socket_path = server.socket_path;
+
+    let response = build_client_and_request("missing", socket_path, "fallback".to_string())
+        .await
+        .unwrap();
+    assert_eq!("fallback\n", response.output);
+    match build_client_and_request("cycle_a", socket_path, "hello".to_string()).await {
+        Err(ClientError::ServerError(error)) => assert_eq!("program_not_found", error.kind),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    match build_client_and_request("no_such_cmd", socket_path, "hello".to_string()).await {
+        Err(ClientError::ServerError(error)) => {
+            assert_eq!("cmd_not_found", error.kind);
+            assert!(error.error.contains("no_such_cmd"), "{}", error.error);
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_coalesce_requests() {
+    let server = TestServer::start("coalesce_requests").await;
+    let socket_path = server.socket_path;
+
+    // the identical requests in flight share one run
+    process_manager::set_coalesce_requests(true);
+    let count = "n=$((n+1)); echo $n".to_string();
+    let (first, second) = tokio::join!(
+        build_client_and_request("sh", socket_path, count.clone()),
+        build_client_and_request("sh", socket_path, count.clone()),
+    );
+    process_manager::set_coalesce_requests(false);
+    assert_eq!("1\n", first.unwrap().output);
+    assert_eq!("1\n", second.unwrap().output);
+    let response = build_client_and_request("sh", socket_path, count)
+        .await
+        .unwrap();
+    assert_eq!("2\n", response.output);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_websocket() {
+    let server = TestServer::start("websocket").await;
+    let socket_path = server.socket_path;
+
+    // each text message is an input, and the socket is closed when the REPL exits
+    let (mut ws, response) = ws_connect(socket_path, "/cmd/sh/ws").await;
+    assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+    assert!(
+        response
+            .to_lowercase()
+            .contains("sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo="),
+        "{}",
+        response
+    );
+    ws_send(&mut ws, 0x1, b"echo hi").await;
+    assert_eq!((0x1, b"hi\n".to_vec()), ws_read(&mut ws).await);
+    // the other requests to the cmd wait for the socket
+    let mut waiting = tokio::spawn(build_client_and_request(
+        "sh",
+        socket_path,
+        "echo waited".to_string(),
+    ));
+    assert!(
+        tokio::time::timeout(Duration::from_millis(300), &mut waiting)
+            .await
+            .is_err()
+    );
+    ws_send(&mut ws, 0x1, b"exit").await;
+    let (opcode, payload) = ws_read(&mut ws).await;
+    assert_eq!(0x8, opcode);
+    assert_eq!([0x03, 0xe8], payload[..2]);
+    ws_send(&mut ws, 0x8, &payload[..2]).await;
+    let mut rest = Vec::new();
+    ws.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+    assert_eq!("waited\n", waiting.await.unwrap().unwrap().output);
+    let (_, response) = ws_connect(socket_path, "/cmd/no_such_cmd/ws").await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_websocket_invalid_frames() {
+    let server = TestServer::start("websocket_invalid_frames").await;
+    let socket_path = server.socket_path;
+
+    // a forged frame length is rejected before the payload is allocated
+    let (mut ws, _) = ws_connect(socket_path, "/cmd/sh/ws").await;
+    let mut frame = vec![0x81, 0x80 | 127];
+    frame.extend_from_slice(&(u64::MAX >> 1).to_be_bytes());
+    frame.extend_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+    ws.write_all(&frame).await.unwrap();
+    let (opcode, payload) = ws_read(&mut ws).await;
+    assert_eq!(0x8, opcode);
+    assert_eq!(1009u16.to_be_bytes(), payload[..2]);
+    drop(ws);
+
+    // a control frame over 125 bytes or fragmented is a protocol error
+    let (mut ws, _) = ws_connect(socket_path, "/cmd/sh/ws").await;
+    let mut frame = vec![0x89, 0x80 | 126, 0x00, 126];
+    frame.extend_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+    frame.extend_from_slice(&[0; 126]);
+    ws.write_all(&frame).await.unwrap();
+    let (opcode, payload) = ws_read(&mut ws).await;
+    assert_eq!(0x8, opcode);
+    assert_eq!(1002u16.to_be_bytes(), payload[..2]);
+    drop(ws);
+    let (mut ws, _) = ws_connect(socket_path, "/cmd/sh/ws").await;
+    ws.write_all(&[0x09, 0x80, 0x12, 0x34, 0x56, 0x78])
+        .await
+        .unwrap();
+    let (opcode, payload) = ws_read(&mut ws).await;
+    assert_eq!(0x8, opcode);
+    assert_eq!(1002u16.to_be_bytes(), payload[..2]);
+    drop(ws);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_reset_all() {
+    let server = TestServer::start("reset_all").await;
+    let socket_path = server.socket_path;
+
+    // the state of the process is lost by the reset
+    let response = build_client_and_request("sh", socket_path, "x=42; echo $x".to_string())
+        .await
+        .unwrap();
+    assert_eq!("42\n", response.output);
+    assert!(process_manager::reset_all()
+        .await
+        .contains(&"sh".to_string()));
+    let response = build_client_and_request("sh", socket_path, "echo ${x:-unset}".to_string())
+        .await
+        .unwrap();
+    assert_eq!("unset\n", response.output);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_eof_cmd() {
+    let server = TestServer::start("eof_cmd").await;
+    let socket_path = server.socket_path;
+
+    // the shell exits at EOF and is respawned on the next input
+    let response = build_client_and_request("sh", socket_path, "y=1; echo $y".to_string())
+        .await
+        .unwrap();
+    assert_eq!("1\n", response.output);
+    process_manager::eof_cmd(&"sh".to_string()).await.unwrap();
+    assert!(matches!(
+        process_manager::eof_cmd(&"sh".to_string()).await,
+        Err(process_manager::ProcessManagerError::ProcessNotRunning(_))
+    ));
+    let response = build_client_and_request("sh", socket_path, "echo ${y:-unset}".to_string())
+        .await
+        .unwrap();
+    assert_eq!("unset\n", response.output);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_chunked_body_limit() {
+    let server = TestServer::start("chunked_body_limit").await;
+    let socket_path = server.socket_path;
+
+    // the chunked bodies without Content-Length are limited too
+    process_manager::set_max_input_size(Some(16));
+    let too_large_raw = post_chunked(socket_path, "/cmd/cat/raw", &["1234567890\n"; 3]).await;
+    let too_large_json = post_chunked(
+        socket_path,
+        "/cmd/cat",
+        &[r#"{"input": "1234"#, r#"567890123456"}"#],
+    )
+    .await;
+    let small = post_chunked(socket_path, "/cmd/cat", &[r#"{"input": "hi"}"#]).await;
+    process_manager::set_max_input_size(None);
+
+    assert!(
+        too_large_raw.starts_with("HTTP/1.1 413"),
+        "{}",
+        too_large_raw
+    );
+    assert!(
+        too_large_raw.contains("input_too_large"),
+        "{}",
+        too_large_raw
+    );
+    assert!(
+        too_large_json.starts_with("HTTP/1.1 413"),
+        "{}",
+        too_large_json
+    );
+    assert!(small.starts_with("HTTP/1.1 200"), "{}", small);
+    // the raw input passed until the limit doesn't leak into the next run
+    assert!(small.contains(r#""output":"hi\n""#), "{}", small);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_reload() {
+    let server = TestServer::start("reload").await;
+
+    // a cmd added to the config file is runnable after the reload
+    std::fs::write(
+        &server.config_path,
+        r#"
+[[cmds]]
+name = "reloaded"
+cmd = "cat"
+auto_trailing_newline = true
+wait_output_timeout_milli_sec = 200
+"#,
+    )
+    .unwrap();
+    let response = post(server.socket_path, "/admin/reload", "").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains(r#""added":["reloaded"]"#), "{}", response);
+    let response = build_client_and_request("reloaded", server.socket_path, "hello".to_string())
+        .await
+        .unwrap();
+    assert_eq!("hello\n", response.output);
+
+    server.stop().await;
+}